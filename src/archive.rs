@@ -0,0 +1,216 @@
+use chrono::NaiveDateTime;
+use rusqlite::Connection;
+use serde::Serialize;
+use std::fs;
+use std::io::{self, Read, Seek};
+use std::path::Path;
+use zip::ZipArchive;
+
+use crate::database::{self, ActivityMetadata, RegionVisit};
+use crate::regions::{self, Regions};
+use crate::tiles::{self, TileOptions};
+use crate::track_file;
+
+/// Date format of activities.csv, e.g. "Jan 5, 2021, 7:12:33 AM" (UTC)
+const CSV_DATE_FORMAT: &str = "%b %d, %Y, %I:%M:%S %p";
+
+/// Result of importing a Strava account export
+#[derive(Debug, Default, Serialize)]
+pub struct ArchiveSummary {
+    /// Activities newly added to the database
+    pub imported: u32,
+    /// Activities that were already imported or were deleted
+    pub skipped: u32,
+    /// Activities without a track file (e.g. manual or indoor activities)
+    pub without_track: u32,
+    /// Tiles added by the imported tracks
+    pub new_tiles: usize,
+    /// IDs of the imported activities with a track
+    pub track_activity_ids: Vec<i64>,
+    /// Regions the imported activities entered first
+    pub new_regions: Vec<RegionVisit>,
+    /// Rows or files that could not be imported
+    pub errors: Vec<String>,
+}
+
+/// Column positions in activities.csv, looked up by header name
+///
+/// Some names appear twice (e.g. "Distance" in km and again in meters); the first one
+/// is the summary value shown on Strava.
+struct Columns {
+    id: usize,
+    date: Option<usize>,
+    name: Option<usize>,
+    activity_type: Option<usize>,
+    moving_time: Option<usize>,
+    distance: Option<usize>,
+    elevation_gain: Option<usize>,
+    gear: Option<usize>,
+    filename: Option<usize>,
+}
+
+impl Columns {
+    fn from_headers(headers: &csv::StringRecord) -> Result<Columns, String> {
+        let find = |name: &str| headers.iter().position(|h| h.trim() == name);
+        Ok(Columns {
+            id: find("Activity ID").ok_or("activities.csv has no 'Activity ID' column")?,
+            date: find("Activity Date"),
+            name: find("Activity Name"),
+            activity_type: find("Activity Type"),
+            moving_time: find("Moving Time"),
+            distance: find("Distance"),
+            elevation_gain: find("Elevation Gain"),
+            gear: find("Activity Gear"),
+            filename: find("Filename"),
+        })
+    }
+}
+
+/// Import a Strava "download your data" archive
+///
+/// Reads activities.csv for the activity metadata, extracts the referenced track files
+/// (.gpx, .tcx, .fit, optionally gzip compressed) into `out_dir` as `activity_<id>.<ext>`
+/// and adds their tiles and region visits. Imported activity IDs are recorded so that
+/// fetching from the API doesn't download them again; deleted activities are skipped.
+pub fn import_archive<R: Read + Seek>(
+    conn: &mut Connection,
+    archive: R,
+    out_dir: &Path,
+    options: &TileOptions,
+    regions: &Regions,
+) -> Result<ArchiveSummary, String> {
+    let mut zip = ZipArchive::new(archive).map_err(|e| format!("Invalid ZIP archive: {}", e))?;
+
+    // The CSV may sit in a top-level folder if the archive was repacked
+    let csv_name = zip
+        .file_names()
+        .filter(|n| *n == "activities.csv" || n.ends_with("/activities.csv"))
+        .min_by_key(|n| n.len())
+        .map(str::to_string)
+        .ok_or("activities.csv not found in archive")?;
+    let prefix = &csv_name[..csv_name.len() - "activities.csv".len()];
+
+    let mut csv_data = Vec::new();
+    zip.by_name(&csv_name)
+        .and_then(|mut f| f.read_to_end(&mut csv_data).map_err(Into::into))
+        .map_err(|e| format!("{}: {}", csv_name, e))?;
+
+    let mut reader = csv::Reader::from_reader(csv_data.as_slice());
+    let headers = reader.headers().map_err(|e| e.to_string())?.clone();
+    let columns = Columns::from_headers(&headers)?;
+
+    fs::create_dir_all(out_dir).map_err(|e| e.to_string())?;
+
+    let mut summary = ArchiveSummary::default();
+    for record in reader.records() {
+        let record = match record {
+            Ok(r) => r,
+            Err(e) => {
+                summary.errors.push(format!("activities.csv: {}", e));
+                continue;
+            }
+        };
+        let field = |index: Option<usize>| {
+            index
+                .and_then(|i| record.get(i))
+                .map(str::trim)
+                .filter(|v| !v.is_empty())
+        };
+
+        let Some(activity_id) = field(Some(columns.id)).and_then(|v| v.parse::<i64>().ok()) else {
+            continue;
+        };
+        let activity = ActivityMetadata {
+            activity_id,
+            activity_name: field(columns.name).map(str::to_string),
+            activity_type: field(columns.activity_type).map(str::to_string),
+            start_date: field(columns.date)
+                .and_then(|d| NaiveDateTime::parse_from_str(d, CSV_DATE_FORMAT).ok())
+                .map(|d| d.and_utc().timestamp()),
+            moving_time_s: field(columns.moving_time)
+                .and_then(|v| v.parse::<f64>().ok())
+                .map(|s| s.round() as i64),
+            gear: field(columns.gear).map(str::to_string),
+            distance_km: field(columns.distance)
+                .and_then(|v| v.replace(',', "").parse::<f64>().ok())
+                .unwrap_or(0.0),
+            elevation_gain_m: field(columns.elevation_gain)
+                .and_then(|v| v.replace(',', "").parse::<f64>().ok())
+                .map(|m| m.round() as i32)
+                .unwrap_or(0),
+        };
+
+        // Deleted on purpose, not to be brought back
+        if database::is_activity_deleted(conn, activity_id).unwrap_or(false) {
+            summary.skipped += 1;
+            continue;
+        }
+        if database::is_activity_imported(conn, activity_id).unwrap_or(false) {
+            // Still fill in metadata the API import doesn't store
+            if let Err(e) = database::save_activity_metadata(conn, &activity) {
+                summary.errors.push(format!("{}: {}", activity_id, e));
+            }
+            summary.skipped += 1;
+            continue;
+        }
+
+        let result = match field(columns.filename) {
+            Some(entry_name) => import_track(
+                &mut zip, prefix, entry_name, out_dir, conn, &activity, options,
+            )
+            .map(|new_tiles| {
+                summary.new_tiles += new_tiles;
+                summary.track_activity_ids.push(activity_id);
+            })
+            .map_err(|e| format!("{}: {}", entry_name, e)),
+            None => {
+                summary.without_track += 1;
+                database::save_activity_metadata(conn, &activity)
+                    .map_err(|e| format!("{}: {}", activity_id, e))
+            }
+        };
+        match result {
+            Ok(()) => summary.imported += 1,
+            Err(e) => summary.errors.push(e),
+        }
+    }
+
+    match regions::record_region_visits(conn, regions, &summary.track_activity_ids) {
+        Ok(new_regions) => summary.new_regions = new_regions,
+        Err(e) => summary.errors.push(format!("Region visits: {}", e)),
+    }
+    Ok(summary)
+}
+
+/// Extract one track file, store the activity and add its tiles
+///
+/// Distance and elevation gain come from activities.csv; the track only fills in what the
+/// CSV leaves empty.
+fn import_track<R: Read + Seek>(
+    zip: &mut ZipArchive<R>,
+    prefix: &str,
+    entry_name: &str,
+    out_dir: &Path,
+    conn: &mut Connection,
+    activity: &ActivityMetadata,
+    options: &TileOptions,
+) -> Result<usize, String> {
+    let stem = track_file::file_stem(entry_name).ok_or("unsupported track format")?;
+    let extension = entry_name[stem.len()..].to_ascii_lowercase();
+    let path = out_dir.join(format!("activity_{}{}", activity.activity_id, extension));
+
+    let mut entry = zip
+        .by_name(&format!("{}{}", prefix, entry_name))
+        .map_err(|e| e.to_string())?;
+    let mut file = fs::File::create(&path).map_err(|e| e.to_string())?;
+    io::copy(&mut entry, &mut file).map_err(|e| e.to_string())?;
+
+    // Metadata first so the tiles get the activity name (FIT files carry none); a track
+    // that fails to import takes it back out, so the next import tries again
+    database::save_activity_metadata(conn, activity).map_err(|e| e.to_string())?;
+    tiles::process_track_file(conn, &path, options).inspect_err(|_| {
+        if let Err(e) = tiles::forget_track_file(conn, &path, activity.activity_id) {
+            eprintln!("Error rolling back {}: {}", path.display(), e);
+        }
+    })
+}
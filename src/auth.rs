@@ -0,0 +1,171 @@
+use rusqlite::Connection;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::database::{self, StoredToken};
+use crate::strava::{self, StravaClient, TokenResponse};
+
+/// Refresh the access token when it expires within this many seconds
+const REFRESH_MARGIN_SECS: i64 = 300;
+
+/// How long a login may take between authorize URL and callback
+const STATE_TTL: Duration = Duration::from_secs(600);
+
+/// Hands out valid Strava access tokens
+///
+/// Tokens are kept in the database, so a restarted server doesn't need a new login.
+/// Access tokens are refreshed shortly before they expire; Strava may rotate the refresh
+/// token on every refresh, so the new one is saved right away.
+pub struct TokenProvider {
+    db: Arc<Mutex<Connection>>,
+    client_id: String,
+    client_secret: String,
+    /// Only one refresh at a time, so concurrent requests don't use up the refresh token
+    refresh_lock: tokio::sync::Mutex<()>,
+}
+
+impl TokenProvider {
+    /// Provider for the app in STRAVA_CLIENT_ID and STRAVA_CLIENT_SECRET
+    pub fn from_env(db: Arc<Mutex<Connection>>) -> Self {
+        TokenProvider {
+            db,
+            client_id: std::env::var("STRAVA_CLIENT_ID").unwrap_or_default(),
+            client_secret: std::env::var("STRAVA_CLIENT_SECRET").unwrap_or_default(),
+            refresh_lock: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Whether tokens from a login are stored
+    pub fn is_authenticated(&self) -> bool {
+        self.stored().is_some()
+    }
+
+    /// Save the tokens of a code exchange
+    pub fn save(&self, token: &TokenResponse) -> Result<(), String> {
+        let previous_refresh_token = self.stored().and_then(|t| t.refresh_token);
+        self.store(token, previous_refresh_token)
+    }
+
+    /// A valid access token, refreshed first if it is about to expire
+    ///
+    /// Without stored tokens, STRAVA_REFRESH_TOKEN and then STRAVA_ACCESS_TOKEN from the
+    /// environment are used.
+    pub async fn access_token(&self, client: &StravaClient) -> Result<String, String> {
+        if let Some(token) = self.stored().filter(|t| !expires_soon(t)) {
+            return Ok(token.access_token);
+        }
+
+        let _guard = self.refresh_lock.lock().await;
+        // Another request may have refreshed while this one waited
+        let stored = self.stored();
+        if let Some(token) = stored.as_ref().filter(|t| !expires_soon(t)) {
+            return Ok(token.access_token.clone());
+        }
+
+        let refresh_token = stored
+            .as_ref()
+            .and_then(|t| t.refresh_token.clone())
+            .or_else(|| env_token("STRAVA_REFRESH_TOKEN"));
+        if let Some(refresh_token) = refresh_token {
+            if self.client_id.is_empty() || self.client_secret.is_empty() {
+                return Err("STRAVA_CLIENT_ID or STRAVA_CLIENT_SECRET not set".to_string());
+            }
+            let token =
+                strava::refresh_token(client, &self.client_id, &self.client_secret, &refresh_token)
+                    .await
+                    .map_err(|e| e.to_string())?;
+            self.store(&token, Some(refresh_token))?;
+            println!("Strava access token refreshed");
+            return Ok(token.access_token);
+        }
+
+        stored
+            .map(|t| t.access_token)
+            .or_else(|| env_token("STRAVA_ACCESS_TOKEN"))
+            .ok_or_else(|| "not authenticated".to_string())
+    }
+
+    fn stored(&self) -> Option<StoredToken> {
+        let conn = self.db.lock().unwrap();
+        database::get_strava_token(&conn).unwrap_or_else(|e| {
+            eprintln!("Error reading Strava tokens: {}", e);
+            None
+        })
+    }
+
+    /// Save a token response; Strava may leave out the refresh token, then the previous
+    /// one stays valid
+    fn store(
+        &self,
+        token: &TokenResponse,
+        previous_refresh_token: Option<String>,
+    ) -> Result<(), String> {
+        let expires_at = token
+            .expires_at
+            .or_else(|| token.expires_in.map(|secs| now() + secs));
+        let conn = self.db.lock().unwrap();
+        database::save_strava_token(
+            &conn,
+            &StoredToken {
+                access_token: token.access_token.clone(),
+                refresh_token: token.refresh_token.clone().or(previous_refresh_token),
+                expires_at,
+            },
+        )
+        .map_err(|e| e.to_string())
+    }
+}
+
+/// OAuth `state` values of logins in progress
+///
+/// The callback is only accepted with a state handed out here, so nobody can log the
+/// server into their own Strava account by sending the user a crafted callback link.
+#[derive(Default)]
+pub struct PendingLogins {
+    states: Mutex<HashMap<String, Instant>>,
+}
+
+impl PendingLogins {
+    /// A new random state for an authorize URL
+    pub fn start(&self) -> Result<String, String> {
+        let state = random_state()?;
+        let mut states = self.states.lock().unwrap();
+        states.retain(|_, started| started.elapsed() < STATE_TTL);
+        states.insert(state.clone(), Instant::now());
+        Ok(state)
+    }
+
+    /// Whether the state belongs to a login in progress; each state is accepted once
+    pub fn finish(&self, state: &str) -> bool {
+        let mut states = self.states.lock().unwrap();
+        states
+            .remove(state)
+            .is_some_and(|started| started.elapsed() < STATE_TTL)
+    }
+}
+
+/// 128 random bits as hex
+pub fn random_state() -> Result<String, String> {
+    let mut bytes = [0u8; 16];
+    getrandom::fill(&mut bytes).map_err(|e| format!("No random numbers: {}", e))?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Tokens without a known expiry are used until Strava rejects them
+fn expires_soon(token: &StoredToken) -> bool {
+    token
+        .expires_at
+        .is_some_and(|expires_at| expires_at - now() < REFRESH_MARGIN_SECS)
+}
+
+fn env_token(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|t| !t.is_empty())
+}
+
+fn now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
@@ -1,11 +1,15 @@
-use rusqlite::{params, Connection, Result};
+use rusqlite::{params, Connection, OptionalExtension, Result};
+use serde::Serialize;
 
 const DB_PATH: &str = "tiles.db";
 
 /// Initialize the database and create tables if they don't exist
 pub fn init_db() -> Result<Connection> {
-    let conn = Connection::open(DB_PATH)?;
+    setup_db(Connection::open(DB_PATH)?)
+}
 
+/// Create missing tables and run the migrations on an open database
+pub fn setup_db(conn: Connection) -> Result<Connection> {
     // Create table for visited tiles with first visit timestamp and activity info
     conn.execute(
         "CREATE TABLE IF NOT EXISTS tiles (
@@ -21,6 +25,54 @@ pub fn init_db() -> Result<Connection> {
         [],
     )?;
 
+    // Migration: Cached metrics per tile, NULL until computed by `update_tile_metrics`:
+    // side of the largest visited square with the tile as bottom-right corner, and the
+    // cluster of surrounded tiles the tile belongs to
+    let _ = conn.execute("ALTER TABLE tiles ADD COLUMN square_size INTEGER", []);
+    let _ = conn.execute("ALTER TABLE tiles ADD COLUMN cluster_id INTEGER", []);
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_tiles_cluster ON tiles (z, cluster_id)",
+        [],
+    )?;
+
+    // Clusters of surrounded tiles and the cached Übersquadrat and Yard per zoom level
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS tile_clusters (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            z INTEGER NOT NULL,
+            size INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS tile_metrics (
+            z INTEGER PRIMARY KEY,
+            max_square INTEGER NOT NULL,
+            square_x INTEGER NOT NULL,
+            square_y INTEGER NOT NULL,
+            max_cluster INTEGER NOT NULL,
+            cluster_id INTEGER
+        )",
+        [],
+    )?;
+
+    // Every visit of a tile: one row per tile and track file, with the time the track
+    // first entered the tile. `tiles` keeps only the first visit for the fast map view.
+    let had_tile_visits = table_exists(&conn, "tile_visits")?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS tile_visits (
+            z INTEGER NOT NULL,
+            x INTEGER NOT NULL,
+            y INTEGER NOT NULL,
+            gpx_filename TEXT NOT NULL,
+            activity_id TEXT,
+            activity_title TEXT,
+            visited_at INTEGER NOT NULL,
+            PRIMARY KEY (z, x, y, gpx_filename)
+        )",
+        [],
+    )?;
+
     // Create table to track processed GPX files
     conn.execute(
         "CREATE TABLE IF NOT EXISTS processed_files (
@@ -30,6 +82,97 @@ pub fn init_db() -> Result<Connection> {
         [],
     )?;
 
+    // Migration: Zoom levels a file was processed at (comma separated, NULL = zoom 14 only)
+    let _ = conn.execute("ALTER TABLE processed_files ADD COLUMN zooms TEXT", []);
+
+    // Migration: Content hash of processed files, to reprocess replaced files
+    let _ = conn.execute(
+        "ALTER TABLE processed_files ADD COLUMN content_hash TEXT",
+        [],
+    );
+
+    // Migration: Size and modification time of processed files, so unchanged files
+    // aren't hashed again
+    let _ = conn.execute(
+        "ALTER TABLE processed_files ADD COLUMN file_size INTEGER",
+        [],
+    );
+    let _ = conn.execute(
+        "ALTER TABLE processed_files ADD COLUMN file_modified INTEGER",
+        [],
+    );
+
+    // Migration: Visits from before tile_visits existed are backfilled with the first
+    // visits kept in `tiles`, so processed files don't need to be processed again
+    if !had_tile_visits {
+        let backfilled = conn.execute(
+            "INSERT OR IGNORE INTO tile_visits
+                (z, x, y, gpx_filename, activity_id, activity_title, visited_at)
+             SELECT z, x, y, gpx_filename, activity_id, activity_title, first_visited_at
+             FROM tiles WHERE gpx_filename IS NOT NULL",
+            [],
+        )?;
+        if backfilled > 0 {
+            println!(
+                "Database upgrade: recorded {} tile visits from the visited tiles",
+                backfilled
+            );
+        }
+    }
+
+    // Activity that first entered each region of the region registry
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS region_visits (
+            layer TEXT NOT NULL,
+            region_index INTEGER NOT NULL,
+            region_name TEXT NOT NULL,
+            admin_level INTEGER NOT NULL,
+            activity_id TEXT,
+            gpx_filename TEXT,
+            first_visited_at INTEGER NOT NULL,
+            PRIMARY KEY (layer, region_index)
+        )",
+        [],
+    )?;
+
+    // Strava OAuth tokens of the logged in athlete (a single row)
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS strava_tokens (
+            id INTEGER PRIMARY KEY CHECK (id = 1),
+            access_token TEXT NOT NULL,
+            refresh_token TEXT,
+            expires_at INTEGER,
+            updated_at INTEGER NOT NULL
+        )",
+        [],
+    )?;
+
+    // Newest start date up to which all Strava activities are synced (a single row)
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS sync_state (
+            id INTEGER PRIMARY KEY CHECK (id = 1),
+            synced_until INTEGER NOT NULL,
+            updated_at INTEGER NOT NULL
+        )",
+        [],
+    )?;
+
+    // Result of every Strava sync, started by hand or by the scheduler
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS sync_runs (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            triggered_by TEXT NOT NULL,
+            started_at INTEGER NOT NULL,
+            finished_at INTEGER NOT NULL,
+            success INTEGER NOT NULL,
+            imported INTEGER NOT NULL,
+            skipped INTEGER NOT NULL,
+            failed INTEGER NOT NULL,
+            message TEXT NOT NULL
+        )",
+        [],
+    )?;
+
     // Create table to track imported Strava activities
     conn.execute(
         "CREATE TABLE IF NOT EXISTS imported_activities (
@@ -54,33 +197,175 @@ pub fn init_db() -> Result<Connection> {
         [],
     );
 
+    // Migration: Activity metadata from Strava account export archives
+    for column in [
+        "activity_type TEXT",
+        "start_date INTEGER",
+        "moving_time_s INTEGER",
+        "gear TEXT",
+    ] {
+        let _ = conn.execute(
+            &format!("ALTER TABLE imported_activities ADD COLUMN {}", column),
+            [],
+        );
+    }
+
+    // Migration: Deleted activities stay as tombstones, so syncs and archive imports
+    // don't bring them back
+    let _ = conn.execute(
+        "ALTER TABLE imported_activities ADD COLUMN deleted INTEGER NOT NULL DEFAULT 0",
+        [],
+    );
+
     Ok(conn)
 }
 
-/// Check if a GPX file has already been processed
-pub fn is_file_processed(conn: &Connection, filename: &str) -> Result<bool> {
+fn table_exists(conn: &Connection, name: &str) -> Result<bool> {
     let count: i32 = conn.query_row(
-        "SELECT COUNT(*) FROM processed_files WHERE filename = ?1",
-        params![filename],
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?1",
+        params![name],
         |row| row.get(0),
     )?;
     Ok(count > 0)
 }
 
-/// Mark a GPX file as processed
-pub fn mark_file_processed(conn: &Connection, filename: &str) -> Result<()> {
+/// Zoom level of files processed before zoom levels were recorded
+const LEGACY_ZOOM: u32 = 14;
+
+/// Get the zoom levels a track file has been processed at (None if never processed)
+pub fn get_processed_zooms(conn: &Connection, filename: &str) -> Result<Option<Vec<u32>>> {
+    let zooms: Option<Option<String>> = conn
+        .query_row(
+            "SELECT zooms FROM processed_files WHERE filename = ?1",
+            params![filename],
+            |row| row.get(0),
+        )
+        .optional()?;
+    Ok(zooms.map(|zooms| match zooms {
+        Some(list) => list.split(',').filter_map(|z| z.parse().ok()).collect(),
+        None => vec![LEGACY_ZOOM],
+    }))
+}
+
+/// Mark a GPX file as processed at the given zoom levels
+pub fn mark_file_processed(conn: &Connection, filename: &str, zooms: &[u32]) -> Result<()> {
     let now = std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
         .unwrap_or_default()
         .as_secs() as i64;
+    let zooms = zooms
+        .iter()
+        .map(|z| z.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+
+    conn.execute(
+        "INSERT INTO processed_files (filename, processed_at, zooms) VALUES (?1, ?2, ?3)
+         ON CONFLICT(filename) DO UPDATE SET zooms = excluded.zooms",
+        params![filename, now, zooms],
+    )?;
+    Ok(())
+}
+
+/// Content hash, size and modification time a file had when it was processed; None for
+/// files processed before they were stored
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct FileVersion {
+    pub content_hash: Option<String>,
+    pub size: Option<i64>,
+    /// Nanoseconds since the Unix epoch
+    pub modified: Option<i64>,
+}
+
+/// Get the version of a file as it was processed (None if never processed)
+pub fn get_file_version(conn: &Connection, filename: &str) -> Result<Option<FileVersion>> {
+    conn.query_row(
+        "SELECT content_hash, file_size, file_modified FROM processed_files WHERE filename = ?1",
+        params![filename],
+        |row| {
+            Ok(FileVersion {
+                content_hash: row.get(0)?,
+                size: row.get(1)?,
+                modified: row.get(2)?,
+            })
+        },
+    )
+    .optional()
+}
 
+/// Store the version of a processed file
+pub fn set_file_version(conn: &Connection, filename: &str, version: &FileVersion) -> Result<()> {
     conn.execute(
-        "INSERT OR IGNORE INTO processed_files (filename, processed_at) VALUES (?1, ?2)",
-        params![filename, now],
+        "UPDATE processed_files SET content_hash = ?2, file_size = ?3, file_modified = ?4
+         WHERE filename = ?1",
+        params![
+            filename,
+            version.content_hash,
+            version.size,
+            version.modified
+        ],
     )?;
     Ok(())
 }
 
+/// Get the names of all processed files
+pub fn get_processed_filenames(conn: &Connection) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare("SELECT filename FROM processed_files")?;
+    let names = stmt.query_map([], |row| row.get(0))?;
+    names.collect()
+}
+
+/// Remove everything a file contributed to the tiles and forget that it was processed
+///
+/// Tiles first visited by this file are handed to the earliest remaining visit, or
+/// removed if no other activity visited them. Returns the number of such tiles.
+pub fn remove_file_tiles(conn: &mut Connection, filename: &str) -> Result<usize> {
+    let tx = conn.transaction()?;
+    let owned = tx.query_row(
+        "SELECT COUNT(*) FROM tiles WHERE gpx_filename = ?1",
+        params![filename],
+        |row| row.get::<_, i64>(0),
+    )? as usize;
+
+    tx.execute(
+        "DELETE FROM tile_visits WHERE gpx_filename = ?1",
+        params![filename],
+    )?;
+    tx.execute(
+        "UPDATE tiles SET (first_visited_at, activity_id, activity_title, gpx_filename) = (
+             SELECT v.visited_at, v.activity_id, v.activity_title, v.gpx_filename FROM tile_visits v
+             WHERE v.z = tiles.z AND v.x = tiles.x AND v.y = tiles.y
+             ORDER BY v.visited_at
+             LIMIT 1
+         )
+         WHERE gpx_filename = ?1 AND EXISTS (
+             SELECT 1 FROM tile_visits v WHERE v.z = tiles.z AND v.x = tiles.x AND v.y = tiles.y
+         )",
+        params![filename],
+    )?;
+
+    // Tiles nobody else visited disappear, which the incremental metrics can't follow
+    let emptied_zooms: Vec<u32> = {
+        let mut stmt = tx.prepare("SELECT DISTINCT z FROM tiles WHERE gpx_filename = ?1")?;
+        let rows = stmt.query_map(params![filename], |row| row.get(0))?;
+        rows.collect::<Result<_>>()?
+    };
+    tx.execute(
+        "DELETE FROM tiles WHERE gpx_filename = ?1",
+        params![filename],
+    )?;
+    for z in emptied_zooms {
+        reset_tile_metrics(&tx, z)?;
+    }
+
+    tx.execute(
+        "DELETE FROM processed_files WHERE filename = ?1",
+        params![filename],
+    )?;
+    tx.commit()?;
+    Ok(owned)
+}
+
 /// Tile data for batch insert
 pub struct TileData {
     pub x: u32,
@@ -93,6 +378,8 @@ pub struct TileData {
 }
 
 /// Insert multiple tiles in a transaction
+///
+/// Records a visit for every tile and keeps the earliest visit in `tiles`.
 pub fn insert_tiles_batch(conn: &mut Connection, tiles: &[TileData]) -> Result<()> {
     let tx = conn.transaction()?;
     {
@@ -105,6 +392,14 @@ pub fn insert_tiles_batch(conn: &mut Connection, tiles: &[TileData]) -> Result<(
                 gpx_filename = CASE WHEN excluded.first_visited_at < first_visited_at THEN excluded.gpx_filename ELSE gpx_filename END"
         )?;
 
+        let mut visit_stmt = tx.prepare(
+            "INSERT INTO tile_visits (z, x, y, gpx_filename, activity_id, activity_title, visited_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+             ON CONFLICT(z, x, y, gpx_filename) DO UPDATE SET
+                visited_at = MIN(visited_at, excluded.visited_at),
+                activity_id = excluded.activity_id,
+                activity_title = excluded.activity_title",
+        )?;
+
         for tile in tiles {
             stmt.execute(params![
                 tile.x,
@@ -115,39 +410,284 @@ pub fn insert_tiles_batch(conn: &mut Connection, tiles: &[TileData]) -> Result<(
                 tile.activity_title,
                 tile.gpx_filename
             ])?;
+            visit_stmt.execute(params![
+                tile.z,
+                tile.x,
+                tile.y,
+                tile.gpx_filename,
+                tile.activity_id,
+                tile.activity_title,
+                tile.visited_at
+            ])?;
         }
     }
     tx.commit()?;
     Ok(())
 }
 
-/// Get all visited tiles from the database
-pub fn get_all_tiles(conn: &Connection) -> Result<Vec<TileRecord>> {
+/// Cached Übersquadrat and Yard of a zoom level
+#[derive(Debug, Default)]
+pub struct TileMetricsRecord {
+    pub max_square: u32,
+    /// Bottom-right tile of the Übersquadrat
+    pub square_x: u32,
+    pub square_y: u32,
+    pub max_cluster: usize,
+    /// Cluster of the Yard in `tile_clusters`
+    pub cluster_id: Option<i64>,
+}
+
+/// Get the cached metrics, as of the last `update_tile_metrics`
+pub fn get_tile_metrics(conn: &Connection, z: u32) -> Result<TileMetricsRecord> {
+    conn.query_row(
+        "SELECT max_square, square_x, square_y, max_cluster, cluster_id FROM tile_metrics WHERE z = ?1",
+        params![z],
+        |row| {
+            Ok(TileMetricsRecord {
+                max_square: row.get(0)?,
+                square_x: row.get(1)?,
+                square_y: row.get(2)?,
+                max_cluster: row.get::<_, i64>(3)? as usize,
+                cluster_id: row.get(4)?,
+            })
+        },
+    )
+    .optional()
+    .map(Option::unwrap_or_default)
+}
+
+/// Tiles of a cluster of surrounded tiles
+pub fn get_cluster_tiles(conn: &Connection, z: u32, cluster_id: i64) -> Result<Vec<(u32, u32)>> {
+    let mut stmt = conn.prepare("SELECT x, y FROM tiles WHERE z = ?1 AND cluster_id = ?2")?;
+    let tiles = stmt.query_map(params![z, cluster_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    tiles.collect()
+}
+
+/// Forget the cached metrics of a zoom level so the next update recomputes them
+fn reset_tile_metrics(conn: &Connection, z: u32) -> Result<()> {
+    conn.execute(
+        "UPDATE tiles SET square_size = NULL, cluster_id = NULL WHERE z = ?1",
+        params![z],
+    )?;
+    conn.execute("DELETE FROM tile_clusters WHERE z = ?1", params![z])?;
+    conn.execute("DELETE FROM tile_metrics WHERE z = ?1", params![z])?;
+    Ok(())
+}
+
+/// Bring the cached Übersquadrat and Yard up to date with tiles added since the last update
+///
+/// Only new tiles and what depends on them are looked at. The square is a DP stored per
+/// tile, propagated right and down from the new tiles; clusters are merged when a new
+/// surrounded tile connects them. Tiles never enter a bounding-box sized grid, so far
+/// away tiles cost nothing extra.
+pub fn update_tile_metrics(conn: &mut Connection, z: u32) -> Result<()> {
+    use std::collections::BTreeSet;
+
+    let tx = conn.transaction()?;
+    let new_tiles: Vec<(u32, u32)> = {
+        let mut stmt = tx.prepare("SELECT x, y FROM tiles WHERE z = ?1 AND square_size IS NULL")?;
+        let rows = stmt.query_map(params![z], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        rows.collect::<Result<_>>()?
+    };
+    if new_tiles.is_empty() {
+        return Ok(());
+    }
+    let mut metrics = get_tile_metrics(&tx, z)?;
+
+    {
+        let mut get_square =
+            tx.prepare("SELECT square_size FROM tiles WHERE z = ?1 AND x = ?2 AND y = ?3")?;
+        let mut set_square =
+            tx.prepare("UPDATE tiles SET square_size = ?4 WHERE z = ?1 AND x = ?2 AND y = ?3")?;
+        // None if the tile isn't visited
+        let mut square_at = |x: Option<u32>, y: Option<u32>| -> Result<Option<Option<u32>>> {
+            match (x, y) {
+                (Some(x), Some(y)) => get_square
+                    .query_row(params![z, x, y], |row| row.get(0))
+                    .optional(),
+                _ => Ok(None),
+            }
+        };
+
+        // Row-major order (y, x) computes left, upper and upper-left neighbours first
+        let mut pending: BTreeSet<(u32, u32)> = new_tiles.iter().map(|&(x, y)| (y, x)).collect();
+        while let Some((y, x)) = pending.pop_first() {
+            let Some(current) = square_at(Some(x), Some(y))? else {
+                continue;
+            };
+            let left = square_at(x.checked_sub(1), Some(y))?.flatten().unwrap_or(0);
+            let up = square_at(Some(x), y.checked_sub(1))?.flatten().unwrap_or(0);
+            let up_left = square_at(x.checked_sub(1), y.checked_sub(1))?
+                .flatten()
+                .unwrap_or(0);
+            let size = left.min(up).min(up_left) + 1;
+            if current == Some(size) {
+                continue;
+            }
+
+            set_square.execute(params![z, x, y, size])?;
+            if size > metrics.max_square {
+                metrics.max_square = size;
+                metrics.square_x = x;
+                metrics.square_y = y;
+            }
+            for (next_x, next_y) in [(x + 1, y), (x, y + 1), (x + 1, y + 1)] {
+                if square_at(Some(next_x), Some(next_y))?.is_some() {
+                    pending.insert((next_y, next_x));
+                }
+            }
+        }
+    }
+
+    {
+        let mut get_cluster =
+            tx.prepare("SELECT cluster_id FROM tiles WHERE z = ?1 AND x = ?2 AND y = ?3")?;
+        // None if the tile isn't visited
+        let mut cluster_at = |(x, y): (u32, u32)| -> Result<Option<Option<i64>>> {
+            get_cluster
+                .query_row(params![z, x, y], |row| row.get(0))
+                .optional()
+        };
+        let neighbours = |(x, y): (u32, u32)| {
+            [
+                x.checked_sub(1).map(|x| (x, y)),
+                Some((x + 1, y)),
+                y.checked_sub(1).map(|y| (x, y)),
+                Some((x, y + 1)),
+            ]
+        };
+
+        // Only new tiles and their neighbours can have become surrounded
+        let mut candidates: Vec<(u32, u32)> = new_tiles
+            .iter()
+            .flat_map(|&t| std::iter::once(Some(t)).chain(neighbours(t)).flatten())
+            .collect();
+        candidates.sort_unstable();
+        candidates.dedup();
+
+        for tile in candidates {
+            if cluster_at(tile)? != Some(None) {
+                continue;
+            }
+            let mut touching: Vec<i64> = Vec::new();
+            let mut surrounded = true;
+            for neighbour in neighbours(tile) {
+                match neighbour.map(&mut cluster_at).transpose()?.flatten() {
+                    Some(Some(id)) => touching.push(id),
+                    Some(None) => {}
+                    None => surrounded = false,
+                }
+            }
+            if !surrounded {
+                continue;
+            }
+            touching.sort_unstable();
+            touching.dedup();
+
+            // Join the largest touching cluster and merge the others into it
+            let mut clusters: Vec<(i64, usize)> = Vec::new();
+            for id in touching {
+                let size: i64 = tx.query_row(
+                    "SELECT size FROM tile_clusters WHERE id = ?1",
+                    params![id],
+                    |row| row.get(0),
+                )?;
+                clusters.push((id, size as usize));
+            }
+            clusters.sort_by_key(|&(_, size)| std::cmp::Reverse(size));
+            let (cluster_id, mut size) = match clusters.first() {
+                Some(&largest) => largest,
+                None => {
+                    tx.execute(
+                        "INSERT INTO tile_clusters (z, size) VALUES (?1, 0)",
+                        params![z],
+                    )?;
+                    (tx.last_insert_rowid(), 0)
+                }
+            };
+            for &(other, other_size) in clusters.iter().skip(1) {
+                tx.execute(
+                    "UPDATE tiles SET cluster_id = ?2 WHERE z = ?1 AND cluster_id = ?3",
+                    params![z, cluster_id, other],
+                )?;
+                tx.execute("DELETE FROM tile_clusters WHERE id = ?1", params![other])?;
+                size += other_size;
+            }
+            size += 1;
+            tx.execute(
+                "UPDATE tiles SET cluster_id = ?4 WHERE z = ?1 AND x = ?2 AND y = ?3",
+                params![z, tile.0, tile.1, cluster_id],
+            )?;
+            tx.execute(
+                "UPDATE tile_clusters SET size = ?2 WHERE id = ?1",
+                params![cluster_id, size as i64],
+            )?;
+            if size > metrics.max_cluster {
+                metrics.max_cluster = size;
+                metrics.cluster_id = Some(cluster_id);
+            }
+        }
+    }
+
+    tx.execute(
+        "INSERT OR REPLACE INTO tile_metrics (z, max_square, square_x, square_y, max_cluster, cluster_id)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            z,
+            metrics.max_square,
+            metrics.square_x,
+            metrics.square_y,
+            metrics.max_cluster as i64,
+            metrics.cluster_id
+        ],
+    )?;
+    tx.commit()
+}
+
+/// Get all visited tiles at a zoom level from the database
+pub fn get_all_tiles(conn: &Connection, z: u32) -> Result<Vec<TileRecord>> {
     let mut stmt = conn.prepare(
-        "SELECT x, y, z, first_visited_at, activity_id, activity_title, gpx_filename FROM tiles",
+        "SELECT x, y, z, first_visited_at, activity_id, activity_title, gpx_filename FROM tiles WHERE z = ?1",
     )?;
-    let tiles = stmt.query_map([], |row| {
-        Ok(TileRecord {
-            x: row.get(0)?,
-            y: row.get(1)?,
-            z: row.get(2)?,
-            first_visited_at: row.get(3)?,
-            activity_id: row.get(4)?,
-            activity_title: row.get(5)?,
-            gpx_filename: row.get(6)?,
-        })
-    })?;
+    let tiles = stmt.query_map(params![z], tile_record)?;
 
     tiles.collect()
 }
 
-/// Get tile count
-pub fn get_tile_count(conn: &Connection) -> Result<usize> {
-    let count: i64 = conn.query_row("SELECT COUNT(*) FROM tiles", [], |row| row.get(0))?;
+/// Tiles at a zoom level that an activity visited first
+pub fn get_activity_tiles(conn: &Connection, z: u32, activity_id: &str) -> Result<Vec<TileRecord>> {
+    let mut stmt = conn.prepare(
+        "SELECT x, y, z, first_visited_at, activity_id, activity_title, gpx_filename FROM tiles
+         WHERE z = ?1 AND activity_id = ?2",
+    )?;
+    let tiles = stmt.query_map(params![z, activity_id], tile_record)?;
+
+    tiles.collect()
+}
+
+fn tile_record(row: &rusqlite::Row) -> Result<TileRecord> {
+    Ok(TileRecord {
+        x: row.get(0)?,
+        y: row.get(1)?,
+        z: row.get(2)?,
+        first_visited_at: row.get(3)?,
+        activity_id: row.get(4)?,
+        activity_title: row.get(5)?,
+        gpx_filename: row.get(6)?,
+    })
+}
+
+/// Get tile count at a zoom level
+pub fn get_tile_count(conn: &Connection, z: u32) -> Result<usize> {
+    let count: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM tiles WHERE z = ?1",
+        params![z],
+        |row| row.get(0),
+    )?;
     Ok(count as usize)
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct TileRecord {
     pub x: u32,
     pub y: u32,
@@ -158,10 +698,84 @@ pub struct TileRecord {
     pub gpx_filename: Option<String>,
 }
 
-/// Check if an activity has already been imported from Strava
+/// Visit statistics of one tile
+#[derive(Debug)]
+pub struct TileVisitSummary {
+    pub x: u32,
+    pub y: u32,
+    pub z: u32,
+    pub visit_count: usize,
+    pub first_visited_at: i64,
+    pub last_visited_at: i64,
+}
+
+/// Get visit counts and first/last visit of all tiles at a zoom level, optionally only
+/// tiles whose last visit is before `last_visited_before`
+pub fn get_tile_visit_summaries(
+    conn: &Connection,
+    z: u32,
+    last_visited_before: Option<i64>,
+) -> Result<Vec<TileVisitSummary>> {
+    let mut stmt = conn.prepare(
+        "SELECT x, y, z, COUNT(*), MIN(visited_at), MAX(visited_at) FROM tile_visits
+         WHERE z = ?1
+         GROUP BY x, y
+         HAVING ?2 IS NULL OR MAX(visited_at) < ?2",
+    )?;
+    let summaries = stmt.query_map(params![z, last_visited_before], |row| {
+        Ok(TileVisitSummary {
+            x: row.get(0)?,
+            y: row.get(1)?,
+            z: row.get(2)?,
+            visit_count: row.get::<_, i64>(3)? as usize,
+            first_visited_at: row.get(4)?,
+            last_visited_at: row.get(5)?,
+        })
+    })?;
+    summaries.collect()
+}
+
+/// A single visit of a tile by an activity
+#[derive(Debug)]
+pub struct TileVisitRecord {
+    pub activity_id: Option<String>,
+    pub activity_title: Option<String>,
+    pub gpx_filename: String,
+    pub visited_at: i64,
+}
+
+/// Get all visits of a tile, oldest first
+pub fn get_tile_visits(conn: &Connection, x: u32, y: u32, z: u32) -> Result<Vec<TileVisitRecord>> {
+    let mut stmt = conn.prepare(
+        "SELECT activity_id, activity_title, gpx_filename, visited_at FROM tile_visits
+         WHERE z = ?1 AND x = ?2 AND y = ?3
+         ORDER BY visited_at",
+    )?;
+    let visits = stmt.query_map(params![z, x, y], |row| {
+        Ok(TileVisitRecord {
+            activity_id: row.get(0)?,
+            activity_title: row.get(1)?,
+            gpx_filename: row.get(2)?,
+            visited_at: row.get(3)?,
+        })
+    })?;
+    visits.collect()
+}
+
+/// Check if an activity has already been imported from Strava (and not deleted since)
 pub fn is_activity_imported(conn: &Connection, activity_id: i64) -> Result<bool> {
     let count: i32 = conn.query_row(
-        "SELECT COUNT(*) FROM imported_activities WHERE activity_id = ?1",
+        "SELECT COUNT(*) FROM imported_activities WHERE activity_id = ?1 AND deleted = 0",
+        params![activity_id],
+        |row| row.get(0),
+    )?;
+    Ok(count > 0)
+}
+
+/// Check if an activity was deleted and must not be imported again
+pub fn is_activity_deleted(conn: &Connection, activity_id: i64) -> Result<bool> {
+    let count: i32 = conn.query_row(
+        "SELECT COUNT(*) FROM imported_activities WHERE activity_id = ?1 AND deleted = 1",
         params![activity_id],
         |row| row.get(0),
     )?;
@@ -169,6 +783,9 @@ pub fn is_activity_imported(conn: &Connection, activity_id: i64) -> Result<bool>
 }
 
 /// Mark an activity as imported from Strava
+///
+/// Distance and elevation gain of an already stored activity are kept (e.g. Strava's own
+/// values from an account export) and only filled in where missing.
 pub fn mark_activity_imported(
     conn: &Connection,
     activity_id: i64,
@@ -182,15 +799,260 @@ pub fn mark_activity_imported(
         .as_secs() as i64;
 
     conn.execute(
-        "INSERT OR IGNORE INTO imported_activities (activity_id, activity_name, imported_at, distance_km, elevation_gain_m) VALUES (?1, ?2, ?3, ?4, ?5)",
+        "INSERT INTO imported_activities (activity_id, activity_name, imported_at, distance_km, elevation_gain_m) VALUES (?1, ?2, ?3, ?4, ?5)
+         ON CONFLICT(activity_id) DO UPDATE SET
+            distance_km = CASE WHEN COALESCE(distance_km, 0) > 0 THEN distance_km ELSE excluded.distance_km END,
+            elevation_gain_m = CASE WHEN COALESCE(elevation_gain_m, 0) > 0 THEN elevation_gain_m ELSE excluded.elevation_gain_m END",
         params![activity_id, activity_name, now, distance_km, elevation_gain_m],
     )?;
     Ok(())
 }
 
-/// Get all imported activity IDs
+/// Start date (Unix timestamp) of the newest activity up to which all activities are synced
+pub fn get_synced_until(conn: &Connection) -> Result<Option<i64>> {
+    conn.query_row(
+        "SELECT synced_until FROM sync_state WHERE id = 1",
+        [],
+        |row| row.get(0),
+    )
+    .optional()
+}
+
+/// Move the sync checkpoint forward; it never moves back
+pub fn set_synced_until(conn: &Connection, synced_until: i64) -> Result<()> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    conn.execute(
+        "INSERT INTO sync_state (id, synced_until, updated_at) VALUES (1, ?1, ?2)
+         ON CONFLICT(id) DO UPDATE SET
+            synced_until = MAX(synced_until, excluded.synced_until),
+            updated_at = excluded.updated_at",
+        params![synced_until, now],
+    )?;
+    Ok(())
+}
+
+/// Result of a Strava sync
+#[derive(Debug, Clone, Serialize)]
+pub struct SyncRun {
+    /// `manual` or `schedule`
+    pub triggered_by: String,
+    pub started_at: i64,
+    pub finished_at: i64,
+    pub success: bool,
+    pub imported: u32,
+    pub skipped: u32,
+    pub failed: u32,
+    pub message: String,
+}
+
+pub fn insert_sync_run(conn: &Connection, run: &SyncRun) -> Result<()> {
+    conn.execute(
+        "INSERT INTO sync_runs (triggered_by, started_at, finished_at, success, imported, skipped, failed, message)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![
+            run.triggered_by,
+            run.started_at,
+            run.finished_at,
+            run.success,
+            run.imported,
+            run.skipped,
+            run.failed,
+            run.message
+        ],
+    )?;
+    Ok(())
+}
+
+/// The latest sync runs, newest first
+pub fn get_sync_runs(conn: &Connection, limit: u32) -> Result<Vec<SyncRun>> {
+    let mut stmt = conn.prepare(
+        "SELECT triggered_by, started_at, finished_at, success, imported, skipped, failed, message
+         FROM sync_runs ORDER BY id DESC LIMIT ?1",
+    )?;
+    let runs = stmt.query_map(params![limit], |row| {
+        Ok(SyncRun {
+            triggered_by: row.get(0)?,
+            started_at: row.get(1)?,
+            finished_at: row.get(2)?,
+            success: row.get(3)?,
+            imported: row.get(4)?,
+            skipped: row.get(5)?,
+            failed: row.get(6)?,
+            message: row.get(7)?,
+        })
+    })?;
+    runs.collect()
+}
+
+/// Strava OAuth tokens as returned by the token endpoint
+#[derive(Debug, Clone)]
+pub struct StoredToken {
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+    pub expires_at: Option<i64>, // Unix timestamp in seconds
+}
+
+/// Get the stored Strava tokens, if someone logged in
+pub fn get_strava_token(conn: &Connection) -> Result<Option<StoredToken>> {
+    conn.query_row(
+        "SELECT access_token, refresh_token, expires_at FROM strava_tokens WHERE id = 1",
+        [],
+        |row| {
+            Ok(StoredToken {
+                access_token: row.get(0)?,
+                refresh_token: row.get(1)?,
+                expires_at: row.get(2)?,
+            })
+        },
+    )
+    .optional()
+}
+
+/// Replace the stored Strava tokens
+pub fn save_strava_token(conn: &Connection, token: &StoredToken) -> Result<()> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    conn.execute(
+        "INSERT OR REPLACE INTO strava_tokens (id, access_token, refresh_token, expires_at, updated_at)
+         VALUES (1, ?1, ?2, ?3, ?4)",
+        params![token.access_token, token.refresh_token, token.expires_at, now],
+    )?;
+    Ok(())
+}
+
+/// Get the stored name of an imported activity
+pub fn get_activity_name(conn: &Connection, activity_id: i64) -> Result<Option<String>> {
+    let name: Option<Option<String>> = conn
+        .query_row(
+            "SELECT activity_name FROM imported_activities WHERE activity_id = ?1",
+            params![activity_id],
+            |row| row.get(0),
+        )
+        .optional()?;
+    Ok(name.flatten())
+}
+
+/// Get an imported activity with its metadata
+pub fn get_activity(conn: &Connection, activity_id: i64) -> Result<Option<ActivityMetadata>> {
+    conn.query_row(
+        "SELECT activity_id, activity_name, activity_type, start_date, moving_time_s, gear,
+                COALESCE(distance_km, 0.0), COALESCE(elevation_gain_m, 0)
+         FROM imported_activities WHERE activity_id = ?1 AND deleted = 0",
+        params![activity_id],
+        |row| {
+            Ok(ActivityMetadata {
+                activity_id: row.get(0)?,
+                activity_name: row.get(1)?,
+                activity_type: row.get(2)?,
+                start_date: row.get(3)?,
+                moving_time_s: row.get(4)?,
+                gear: row.get(5)?,
+                distance_km: row.get(6)?,
+                elevation_gain_m: row.get(7)?,
+            })
+        },
+    )
+    .optional()
+}
+
+/// Activity metadata as listed in a Strava export's activities.csv
+#[derive(Serialize)]
+pub struct ActivityMetadata {
+    pub activity_id: i64,
+    pub activity_name: Option<String>,
+    pub activity_type: Option<String>,
+    pub start_date: Option<i64>, // Unix timestamp in seconds
+    pub moving_time_s: Option<i64>,
+    pub gear: Option<String>,
+    pub distance_km: f64,
+    pub elevation_gain_m: i32,
+}
+
+/// Insert or update an activity with its metadata and mark it as imported
+pub fn save_activity_metadata(conn: &Connection, activity: &ActivityMetadata) -> Result<()> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+
+    conn.execute(
+        "INSERT INTO imported_activities (activity_id, activity_name, imported_at, distance_km, elevation_gain_m, activity_type, start_date, moving_time_s, gear)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+         ON CONFLICT(activity_id) DO UPDATE SET
+            activity_name = COALESCE(excluded.activity_name, activity_name),
+            activity_type = COALESCE(excluded.activity_type, activity_type),
+            start_date = COALESCE(excluded.start_date, start_date),
+            moving_time_s = COALESCE(excluded.moving_time_s, moving_time_s),
+            gear = COALESCE(excluded.gear, gear)",
+        params![
+            activity.activity_id,
+            activity.activity_name,
+            now,
+            activity.distance_km,
+            activity.elevation_gain_m,
+            activity.activity_type,
+            activity.start_date,
+            activity.moving_time_s,
+            activity.gear
+        ],
+    )?;
+    Ok(())
+}
+
+/// Update distance and elevation gain of an activity, e.g. after its track was fixed
+pub fn update_activity_totals(
+    conn: &Connection,
+    activity_id: i64,
+    distance_km: f64,
+    elevation_gain_m: i32,
+) -> Result<()> {
+    conn.execute(
+        "UPDATE imported_activities SET distance_km = ?2, elevation_gain_m = ?3 WHERE activity_id = ?1",
+        params![activity_id, distance_km, elevation_gain_m],
+    )?;
+    Ok(())
+}
+
+/// Remove an activity from the imported activities, e.g. after a failed import, so it
+/// can be imported again
+pub fn delete_activity(conn: &Connection, activity_id: i64) -> Result<()> {
+    conn.execute(
+        "DELETE FROM imported_activities WHERE activity_id = ?1",
+        params![activity_id],
+    )?;
+    Ok(())
+}
+
+/// Mark an activity as deleted; it no longer counts, and isn't imported again
+pub fn mark_activity_deleted(conn: &Connection, activity_id: i64) -> Result<()> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+
+    conn.execute(
+        "INSERT INTO imported_activities (activity_id, imported_at, deleted) VALUES (?1, ?2, 1)
+         ON CONFLICT(activity_id) DO UPDATE SET deleted = 1",
+        params![activity_id, now],
+    )?;
+    Ok(())
+}
+
+/// Get all imported activity IDs, without deleted ones
 pub fn get_imported_activity_ids(conn: &Connection) -> Result<Vec<i64>> {
-    let mut stmt = conn.prepare("SELECT activity_id FROM imported_activities")?;
+    let mut stmt = conn.prepare("SELECT activity_id FROM imported_activities WHERE deleted = 0")?;
+    let ids = stmt.query_map([], |row| row.get(0))?;
+    ids.collect()
+}
+
+/// Get the IDs of deleted activities
+pub fn get_deleted_activity_ids(conn: &Connection) -> Result<Vec<i64>> {
+    let mut stmt = conn.prepare("SELECT activity_id FROM imported_activities WHERE deleted = 1")?;
     let ids = stmt.query_map([], |row| row.get(0))?;
     ids.collect()
 }
@@ -198,7 +1060,7 @@ pub fn get_imported_activity_ids(conn: &Connection) -> Result<Vec<i64>> {
 /// Get total distance of all imported activities in km
 pub fn get_total_distance(conn: &Connection) -> Result<f64> {
     let total: f64 = conn.query_row(
-        "SELECT COALESCE(SUM(distance_km), 0.0) FROM imported_activities",
+        "SELECT COALESCE(SUM(distance_km), 0.0) FROM imported_activities WHERE deleted = 0",
         [],
         |row| row.get(0),
     )?;
@@ -208,7 +1070,7 @@ pub fn get_total_distance(conn: &Connection) -> Result<f64> {
 /// Get total elevation gain of all imported activities in meters
 pub fn get_total_elevation_gain(conn: &Connection) -> Result<i64> {
     let total: i64 = conn.query_row(
-        "SELECT COALESCE(SUM(elevation_gain_m), 0) FROM imported_activities",
+        "SELECT COALESCE(SUM(elevation_gain_m), 0) FROM imported_activities WHERE deleted = 0",
         [],
         |row| row.get(0),
     )?;
@@ -217,8 +1079,9 @@ pub fn get_total_elevation_gain(conn: &Connection) -> Result<i64> {
 
 /// Get all activity distances in km
 pub fn get_all_distances(conn: &Connection) -> Result<Vec<f64>> {
-    let mut stmt =
-        conn.prepare("SELECT distance_km FROM imported_activities WHERE distance_km > 0")?;
+    let mut stmt = conn.prepare(
+        "SELECT distance_km FROM imported_activities WHERE distance_km > 0 AND deleted = 0",
+    )?;
     let distances = stmt.query_map([], |row| row.get(0))?;
     distances.collect()
 }
@@ -248,3 +1111,203 @@ pub fn calculate_eddington_number(conn: &Connection) -> Result<u32> {
 
     Ok(eddington)
 }
+
+/// A region and the activity that entered it first
+#[derive(Debug, Clone, Serialize)]
+pub struct RegionVisit {
+    pub layer: String,
+    pub region_index: usize,
+    pub region_name: String,
+    pub admin_level: u8,
+    pub activity_id: Option<String>,
+    pub gpx_filename: Option<String>,
+    pub first_visited_at: i64,
+}
+
+/// Replace all recorded first region visits
+pub fn replace_region_visits(conn: &mut Connection, visits: &[RegionVisit]) -> Result<()> {
+    let tx = conn.transaction()?;
+    tx.execute("DELETE FROM region_visits", [])?;
+    {
+        let mut stmt = tx.prepare(
+            "INSERT INTO region_visits (layer, region_index, region_name, admin_level, activity_id, gpx_filename, first_visited_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        )?;
+        for visit in visits {
+            stmt.execute(params![
+                visit.layer,
+                visit.region_index as i64,
+                visit.region_name,
+                visit.admin_level,
+                visit.activity_id,
+                visit.gpx_filename,
+                visit.first_visited_at
+            ])?;
+        }
+    }
+    tx.commit()
+}
+
+/// Record region visits, keeping the stored one where it is earlier
+pub fn record_region_visits(conn: &mut Connection, visits: &[RegionVisit]) -> Result<()> {
+    let tx = conn.transaction()?;
+    {
+        let mut stmt = tx.prepare(
+            "INSERT INTO region_visits (layer, region_index, region_name, admin_level, activity_id, gpx_filename, first_visited_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+             ON CONFLICT(layer, region_index) DO UPDATE SET
+                region_name = excluded.region_name,
+                admin_level = excluded.admin_level,
+                activity_id = excluded.activity_id,
+                gpx_filename = excluded.gpx_filename,
+                first_visited_at = excluded.first_visited_at
+             WHERE excluded.first_visited_at < region_visits.first_visited_at",
+        )?;
+        for visit in visits {
+            stmt.execute(params![
+                visit.layer,
+                visit.region_index as i64,
+                visit.region_name,
+                visit.admin_level,
+                visit.activity_id,
+                visit.gpx_filename,
+                visit.first_visited_at
+            ])?;
+        }
+    }
+    tx.commit()
+}
+
+/// Regions an activity entered first, largest admin units first
+pub fn get_activity_region_visits(
+    conn: &Connection,
+    activity_id: &str,
+) -> Result<Vec<RegionVisit>> {
+    let mut stmt = conn.prepare(
+        "SELECT layer, region_index, region_name, admin_level, activity_id, gpx_filename, first_visited_at
+         FROM region_visits WHERE activity_id = ?1
+         ORDER BY admin_level, region_name",
+    )?;
+    let visits = stmt.query_map(params![activity_id], |row| {
+        Ok(RegionVisit {
+            layer: row.get(0)?,
+            region_index: row.get::<_, i64>(1)? as usize,
+            region_name: row.get(2)?,
+            admin_level: row.get(3)?,
+            activity_id: row.get(4)?,
+            gpx_filename: row.get(5)?,
+            first_visited_at: row.get(6)?,
+        })
+    })?;
+    visits.collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tiles::TILE_ZOOM;
+    use std::collections::HashMap;
+
+    fn test_db() -> Connection {
+        setup_db(Connection::open_in_memory().unwrap()).unwrap()
+    }
+
+    fn insert_file(conn: &mut Connection, filename: &str, visited_at: i64, tiles: &[(u32, u32)]) {
+        let data: Vec<TileData> = tiles
+            .iter()
+            .map(|&(x, y)| TileData {
+                x,
+                y,
+                z: TILE_ZOOM,
+                visited_at,
+                activity_id: filename.to_string(),
+                activity_title: filename.to_string(),
+                gpx_filename: filename.to_string(),
+            })
+            .collect();
+        insert_tiles_batch(conn, &data).unwrap();
+        mark_file_processed(conn, filename, &[TILE_ZOOM]).unwrap();
+    }
+
+    #[test]
+    fn upgrade_backfills_tile_visits_and_keeps_processed_files() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE tiles (
+                x INTEGER NOT NULL,
+                y INTEGER NOT NULL,
+                z INTEGER NOT NULL,
+                first_visited_at INTEGER NOT NULL,
+                activity_id TEXT,
+                activity_title TEXT,
+                gpx_filename TEXT,
+                PRIMARY KEY (x, y, z)
+            );
+            CREATE TABLE processed_files (
+                filename TEXT PRIMARY KEY,
+                processed_at INTEGER NOT NULL
+            );
+            INSERT INTO tiles VALUES (1, 1, 14, 100, '1', 'Ride', 'ride.gpx');
+            INSERT INTO tiles VALUES (2, 1, 14, 100, '1', 'Ride', 'ride.gpx');
+            INSERT INTO processed_files VALUES ('ride.gpx', 100);",
+        )
+        .unwrap();
+
+        let mut conn = setup_db(conn).unwrap();
+        assert_eq!(
+            get_processed_zooms(&conn, "ride.gpx").unwrap(),
+            Some(vec![TILE_ZOOM])
+        );
+        let visits = get_tile_visits(&conn, 2, 1, TILE_ZOOM).unwrap();
+        assert_eq!(visits.len(), 1);
+
+        // The backfilled visits hand over tiles like recorded ones
+        insert_file(&mut conn, "late.gpx", 200, &[(2, 1)]);
+        assert_eq!(remove_file_tiles(&mut conn, "ride.gpx").unwrap(), 2);
+        assert_eq!(get_all_tiles(&conn, TILE_ZOOM).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn deleted_activities_stay_deleted() {
+        let conn = test_db();
+        mark_activity_imported(&conn, 1, Some("Ride"), 42.0, 100).unwrap();
+        mark_activity_deleted(&conn, 1).unwrap();
+        // Deleted before it was ever imported, e.g. from an archive
+        mark_activity_deleted(&conn, 2).unwrap();
+
+        assert!(!is_activity_imported(&conn, 1).unwrap());
+        assert!(is_activity_deleted(&conn, 1).unwrap());
+        assert_eq!(get_deleted_activity_ids(&conn).unwrap().len(), 2);
+        assert!(get_imported_activity_ids(&conn).unwrap().is_empty());
+        assert_eq!(get_total_distance(&conn).unwrap(), 0.0);
+
+        // Importing it again doesn't bring it back
+        mark_activity_imported(&conn, 1, Some("Ride"), 42.0, 100).unwrap();
+        assert!(is_activity_deleted(&conn, 1).unwrap());
+        assert!(get_activity(&conn, 1).unwrap().is_none());
+    }
+
+    #[test]
+    fn removing_a_file_hands_first_visits_to_the_next_visit() {
+        let mut conn = test_db();
+        insert_file(&mut conn, "early.gpx", 100, &[(1, 1), (2, 1)]);
+        insert_file(&mut conn, "late.gpx", 200, &[(2, 1), (3, 1)]);
+        insert_file(&mut conn, "later.gpx", 300, &[(2, 1)]);
+
+        // (1, 1) was visited by nobody else and is removed, (2, 1) goes to late.gpx
+        assert_eq!(remove_file_tiles(&mut conn, "early.gpx").unwrap(), 2);
+        let tiles: HashMap<(u32, u32), TileRecord> = get_all_tiles(&conn, TILE_ZOOM)
+            .unwrap()
+            .into_iter()
+            .map(|t| ((t.x, t.y), t))
+            .collect();
+        assert_eq!(tiles.len(), 2);
+        assert_eq!(tiles[&(2, 1)].gpx_filename.as_deref(), Some("late.gpx"));
+        assert_eq!(tiles[&(2, 1)].first_visited_at, 200);
+        assert_eq!(tiles[&(3, 1)].gpx_filename.as_deref(), Some("late.gpx"));
+
+        let visits = get_tile_visits(&conn, 2, 1, TILE_ZOOM).unwrap();
+        assert_eq!(visits.len(), 2);
+        assert!(get_processed_zooms(&conn, "early.gpx").unwrap().is_none());
+    }
+}
@@ -0,0 +1,459 @@
+use std::collections::HashMap;
+
+use crate::gpx::{Gpx, Lap, Track, TrackPoint, TrackSegment};
+
+/// Seconds between the Unix epoch and the FIT epoch (1989-12-31T00:00:00Z)
+const FIT_EPOCH_OFFSET: i64 = 631_065_600;
+
+/// Smallest FIT file header; fewer bytes after a file are padding
+const MIN_HEADER_SIZE: usize = 12;
+
+/// Degrees per semicircle (2^31 semicircles = 180°)
+const SEMICIRCLES_TO_DEGREES: f64 = 180.0 / 2_147_483_648.0;
+
+// Global message numbers from the FIT profile
+const MESG_FILE_ID: u16 = 0;
+const MESG_LAP: u16 = 19;
+const MESG_RECORD: u16 = 20;
+const MESG_EVENT: u16 = 21;
+
+// Event values for the timer (start/stop) event
+const EVENT_TIMER: i64 = 0;
+const EVENT_TYPE_STOP: i64 = 1;
+const EVENT_TYPE_STOP_ALL: i64 = 4;
+
+/// Field layout of a local message type, set by a definition message
+struct Definition {
+    big_endian: bool,
+    global: u16,
+    fields: Vec<FieldDef>,
+    developer_data_size: usize,
+}
+
+struct FieldDef {
+    number: u8,
+    size: usize,
+    base_type: u8,
+}
+
+/// Decode a FIT activity file into the common track model
+///
+/// Records become track points; a timer stop event starts a new segment so pauses are
+/// not bridged. Heart rate, cadence, power, speed and temperature are stored as point
+/// extensions ("hr", "cad", "power", "speed", "atemp") like in GPX.
+pub fn parse_fit(data: &[u8]) -> Result<Gpx, String> {
+    let mut decoder = Decoder::default();
+    let mut offset = decoder.decode_file(data)?;
+    // A file may consist of several chained FIT files, possibly followed by padding
+    while data.len() - offset >= MIN_HEADER_SIZE && is_fit_header(&data[offset..]) {
+        offset += decoder.decode_file(&data[offset..])?;
+    }
+    Ok(decoder.finish())
+}
+
+#[derive(Default)]
+struct Decoder {
+    definitions: HashMap<u8, Definition>,
+    last_timestamp: Option<u32>,
+    segments: Vec<TrackSegment>,
+    start_new_segment: bool,
+    laps: Vec<Lap>,
+    time_created: Option<i64>,
+}
+
+impl Decoder {
+    /// Decode one FIT file (header, records, CRC) and return the number of bytes consumed
+    fn decode_file(&mut self, data: &[u8]) -> Result<usize, String> {
+        if data.len() < MIN_HEADER_SIZE {
+            return Err("FIT file too short".to_string());
+        }
+        if !is_fit_header(data) {
+            return Err("Not a FIT file".to_string());
+        }
+        let header_size = data[0] as usize;
+        let data_size = u32::from_le_bytes([data[4], data[5], data[6], data[7]]) as usize;
+        let end = header_size + data_size;
+        if data.len() < end {
+            return Err("FIT file is truncated".to_string());
+        }
+
+        self.definitions.clear();
+        let mut pos = header_size;
+        while pos < end {
+            pos = self.decode_record(data, pos, end)?;
+        }
+
+        // Skip the trailing CRC
+        Ok((end + 2).min(data.len()))
+    }
+
+    fn decode_record(&mut self, data: &[u8], mut pos: usize, end: usize) -> Result<usize, String> {
+        let header = data[pos];
+        pos += 1;
+
+        if header & 0x80 != 0 {
+            // Compressed timestamp header: 2 bit local type, 5 bit time offset
+            let local = (header >> 5) & 0x03;
+            let offset = (header & 0x1F) as u32;
+            let timestamp = self.last_timestamp.map(|last| {
+                let mut t = (last & !0x1F) | offset;
+                if offset < (last & 0x1F) {
+                    t += 0x20;
+                }
+                t
+            });
+            return self.decode_data(data, pos, end, local, timestamp);
+        }
+
+        let local = header & 0x0F;
+        if header & 0x40 == 0 {
+            return self.decode_data(data, pos, end, local, None);
+        }
+
+        // Definition message
+        let has_developer_data = header & 0x20 != 0;
+        let fixed = slice(data, pos, 5, end)?;
+        let big_endian = fixed[1] == 1;
+        let global = if big_endian {
+            u16::from_be_bytes([fixed[2], fixed[3]])
+        } else {
+            u16::from_le_bytes([fixed[2], fixed[3]])
+        };
+        let num_fields = fixed[4] as usize;
+        pos += 5;
+
+        let raw_fields = slice(data, pos, num_fields * 3, end)?;
+        let fields = raw_fields
+            .chunks_exact(3)
+            .map(|f| FieldDef {
+                number: f[0],
+                size: f[1] as usize,
+                base_type: f[2] & 0x1F,
+            })
+            .collect();
+        pos += num_fields * 3;
+
+        let mut developer_data_size = 0;
+        if has_developer_data {
+            let num_dev_fields = slice(data, pos, 1, end)?[0] as usize;
+            pos += 1;
+            let dev_fields = slice(data, pos, num_dev_fields * 3, end)?;
+            developer_data_size = dev_fields.chunks_exact(3).map(|f| f[1] as usize).sum();
+            pos += num_dev_fields * 3;
+        }
+
+        self.definitions.insert(
+            local,
+            Definition {
+                big_endian,
+                global,
+                fields,
+                developer_data_size,
+            },
+        );
+        Ok(pos)
+    }
+
+    fn decode_data(
+        &mut self,
+        data: &[u8],
+        mut pos: usize,
+        end: usize,
+        local: u8,
+        compressed_timestamp: Option<u32>,
+    ) -> Result<usize, String> {
+        let definition = self
+            .definitions
+            .get(&local)
+            .ok_or_else(|| format!("FIT data message without definition (local {})", local))?;
+
+        let mut values: HashMap<u8, i64> = HashMap::new();
+        for field in &definition.fields {
+            let raw = slice(data, pos, field.size, end)?;
+            if let Some(value) = read_value(raw, field.base_type, definition.big_endian) {
+                values.insert(field.number, value);
+            }
+            pos += field.size;
+        }
+        slice(data, pos, definition.developer_data_size, end)?;
+        pos += definition.developer_data_size;
+
+        let global = definition.global;
+        // Field 253 is the timestamp in every message that has one
+        let timestamp = match values.get(&253) {
+            Some(&t) => Some(t as u32),
+            None => compressed_timestamp,
+        };
+        if let Some(t) = timestamp {
+            self.last_timestamp = Some(t);
+        }
+
+        match global {
+            MESG_RECORD => self.add_record(&values, timestamp),
+            MESG_LAP => self.add_lap(&values),
+            MESG_EVENT => {
+                let event = values.get(&0).copied();
+                let event_type = values.get(&1).copied();
+                if event == Some(EVENT_TIMER)
+                    && matches!(
+                        event_type,
+                        Some(EVENT_TYPE_STOP) | Some(EVENT_TYPE_STOP_ALL)
+                    )
+                {
+                    self.start_new_segment = true;
+                }
+            }
+            MESG_FILE_ID => {
+                self.time_created = values.get(&4).map(|&t| t + FIT_EPOCH_OFFSET);
+            }
+            _ => {}
+        }
+
+        Ok(pos)
+    }
+
+    fn add_record(&mut self, values: &HashMap<u8, i64>, timestamp: Option<u32>) {
+        // Records without position (e.g. indoor rides, sensor-only samples) are skipped
+        let (lat, lon) = match (values.get(&0), values.get(&1)) {
+            (Some(&lat), Some(&lon)) => (
+                lat as f64 * SEMICIRCLES_TO_DEGREES,
+                lon as f64 * SEMICIRCLES_TO_DEGREES,
+            ),
+            _ => return,
+        };
+
+        // enhanced_altitude (78) supersedes altitude (2); both are scale 5, offset 500
+        let ele = values
+            .get(&78)
+            .or_else(|| values.get(&2))
+            .map(|&a| a as f64 / 5.0 - 500.0);
+
+        let mut extensions = HashMap::new();
+        if let Some(hr) = values.get(&3) {
+            extensions.insert("hr".to_string(), hr.to_string());
+        }
+        if let Some(cad) = values.get(&4) {
+            extensions.insert("cad".to_string(), cad.to_string());
+        }
+        if let Some(distance) = values.get(&5) {
+            extensions.insert(
+                "distance".to_string(),
+                (*distance as f64 / 100.0).to_string(),
+            );
+        }
+        if let Some(speed) = values.get(&73).or_else(|| values.get(&6)) {
+            extensions.insert("speed".to_string(), (*speed as f64 / 1000.0).to_string());
+        }
+        if let Some(power) = values.get(&7) {
+            extensions.insert("power".to_string(), power.to_string());
+        }
+        if let Some(temperature) = values.get(&13) {
+            extensions.insert("atemp".to_string(), temperature.to_string());
+        }
+
+        if self.segments.is_empty() || self.start_new_segment {
+            self.segments.push(TrackSegment::default());
+            self.start_new_segment = false;
+        }
+        if let Some(segment) = self.segments.last_mut() {
+            segment.points.push(TrackPoint {
+                lat,
+                lon,
+                ele,
+                time: timestamp.map(|t| t as i64 + FIT_EPOCH_OFFSET),
+                extensions,
+            });
+        }
+    }
+
+    fn add_lap(&mut self, values: &HashMap<u8, i64>) {
+        self.laps.push(Lap {
+            start_time: values.get(&2).map(|&t| t + FIT_EPOCH_OFFSET),
+            total_time_s: values.get(&7).map(|&t| t as f64 / 1000.0),
+            distance_m: values.get(&9).map(|&d| d as f64 / 100.0),
+            calories: values.get(&11).map(|&c| c as u32),
+            avg_heart_rate: values.get(&15).map(|&hr| hr as u32),
+            max_heart_rate: values.get(&16).map(|&hr| hr as u32),
+            cadence: values.get(&17).map(|&c| c as u32),
+        });
+    }
+
+    fn finish(self) -> Gpx {
+        let segments: Vec<TrackSegment> = self
+            .segments
+            .into_iter()
+            .filter(|s| !s.points.is_empty())
+            .collect();
+        Gpx {
+            name: None,
+            time: self.time_created,
+            tracks: vec![Track {
+                name: None,
+                segments,
+            }],
+            laps: self.laps,
+        }
+    }
+}
+
+/// Whether `data` starts with a FIT file header (at least MIN_HEADER_SIZE bytes)
+fn is_fit_header(data: &[u8]) -> bool {
+    let header_size = data[0] as usize;
+    header_size >= MIN_HEADER_SIZE && data.len() >= header_size && &data[8..12] == b".FIT"
+}
+
+fn slice(data: &[u8], pos: usize, len: usize, end: usize) -> Result<&[u8], String> {
+    if pos + len > end {
+        return Err("FIT record exceeds data size".to_string());
+    }
+    Ok(&data[pos..pos + len])
+}
+
+/// Read the first element of an integer field; returns None for the base type's invalid value
+fn read_value(raw: &[u8], base_type: u8, big_endian: bool) -> Option<i64> {
+    fn bytes<const N: usize>(raw: &[u8], big_endian: bool) -> Option<[u8; N]> {
+        let mut b: [u8; N] = raw.get(..N)?.try_into().ok()?;
+        if big_endian {
+            b.reverse();
+        }
+        Some(b)
+    }
+
+    match base_type {
+        // enum, uint8, byte
+        0x00 | 0x02 | 0x0D => raw.first().filter(|&&v| v != 0xFF).map(|&v| v as i64),
+        // sint8
+        0x01 => raw
+            .first()
+            .map(|&v| v as i8)
+            .filter(|&v| v != 0x7F)
+            .map(|v| v as i64),
+        // uint8z
+        0x0A => raw.first().filter(|&&v| v != 0).map(|&v| v as i64),
+        // sint16
+        0x03 => bytes::<2>(raw, big_endian)
+            .map(i16::from_le_bytes)
+            .filter(|&v| v != 0x7FFF)
+            .map(|v| v as i64),
+        // uint16, uint16z
+        0x04 | 0x0B => bytes::<2>(raw, big_endian)
+            .map(u16::from_le_bytes)
+            .filter(|&v| v != 0xFFFF && !(base_type == 0x0B && v == 0))
+            .map(|v| v as i64),
+        // sint32
+        0x05 => bytes::<4>(raw, big_endian)
+            .map(i32::from_le_bytes)
+            .filter(|&v| v != 0x7FFF_FFFF)
+            .map(|v| v as i64),
+        // uint32, uint32z
+        0x06 | 0x0C => bytes::<4>(raw, big_endian)
+            .map(u32::from_le_bytes)
+            .filter(|&v| v != 0xFFFF_FFFF && !(base_type == 0x0C && v == 0))
+            .map(|v| v as i64),
+        // sint64
+        0x0E => bytes::<8>(raw, big_endian)
+            .map(i64::from_le_bytes)
+            .filter(|&v| v != 0x7FFF_FFFF_FFFF_FFFF),
+        // uint64, uint64z
+        0x0F | 0x10 => bytes::<8>(raw, big_endian)
+            .map(u64::from_le_bytes)
+            .filter(|&v| v != u64::MAX && !(base_type == 0x10 && v == 0))
+            .map(|v| v as i64),
+        // strings and floats are not needed for tracks
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpx;
+
+    /// Seconds of 2024-05-01T10:00:00Z since the FIT epoch
+    const START: u32 = 1_714_557_600 - FIT_EPOCH_OFFSET as u32;
+
+    fn semicircles(degrees: f64) -> i32 {
+        (degrees / SEMICIRCLES_TO_DEGREES).round() as i32
+    }
+
+    /// A FIT file with a record definition (timestamp, position, power, temperature) and
+    /// one record per (seconds after START, latitude) pair
+    fn fit_file(points: &[(u32, f64)]) -> Vec<u8> {
+        let mut records = vec![
+            0x40, 0, 0, // definition of local type 0, little endian
+            20, 0, 5, // record message, 5 fields
+            253, 4, 0x86, // timestamp: uint32
+            0, 4, 0x85, // position_lat: sint32
+            1, 4, 0x85, // position_long: sint32
+            7, 2, 0x84, // power: uint16
+            13, 1, 0x01, // temperature: sint8
+        ];
+        for &(seconds, lat) in points {
+            records.push(0x00);
+            records.extend((START + seconds).to_le_bytes());
+            records.extend(semicircles(lat).to_le_bytes());
+            records.extend(semicircles(13.7).to_le_bytes());
+            records.extend(250u16.to_le_bytes());
+            records.push(-3i8 as u8);
+        }
+
+        let mut file = vec![12, 0x10, 0x08, 0x08];
+        file.extend((records.len() as u32).to_le_bytes());
+        file.extend(b".FIT");
+        file.extend(records);
+        file.extend([0, 0]); // CRC, not checked
+        file
+    }
+
+    #[test]
+    fn decodes_a_minimal_file() {
+        let gpx = parse_fit(&fit_file(&[(0, 51.0), (10, 51.001)])).unwrap();
+        let points: Vec<&TrackPoint> = gpx.points().collect();
+        assert_eq!(points.len(), 2);
+        assert!((points[1].lat - 51.001).abs() < 1e-6);
+        assert!((points[1].lon - 13.7).abs() < 1e-6);
+        assert_eq!(points[0].time, Some(1_714_557_600));
+        assert_eq!(points[1].time, Some(1_714_557_610));
+        assert_eq!(points[0].extensions["power"], "250");
+        assert_eq!(points[0].extensions["atemp"], "-3");
+    }
+
+    #[test]
+    fn decodes_chained_files() {
+        let mut data = fit_file(&[(0, 51.0)]);
+        data.extend(fit_file(&[(60, 51.01), (70, 51.02)]));
+        let gpx = parse_fit(&data).unwrap();
+        assert_eq!(gpx.points().count(), 3);
+    }
+
+    #[test]
+    fn ignores_trailing_padding() {
+        for padding in [1, 11, 12, 40] {
+            let mut data = fit_file(&[(0, 51.0)]);
+            data.extend(vec![0; padding]);
+            let gpx = parse_fit(&data).unwrap();
+            assert_eq!(gpx.points().count(), 1, "{} bytes of padding", padding);
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let data = fit_file(&[(0, 51.0), (10, 51.001)]);
+        assert!(parse_fit(&data[..data.len() - 8]).is_err());
+        assert!(parse_fit(&data[..8]).is_err());
+        assert!(parse_fit(b"not a fit file at all").is_err());
+    }
+
+    #[test]
+    fn writes_power_and_temperature_to_gpx() {
+        let gpx = parse_fit(&fit_file(&[(0, 51.0)])).unwrap();
+        let xml = gpx::to_gpx_xml(&gpx);
+        assert!(xml.contains("<power>250</power>"));
+        assert!(xml.contains("<gpxtpx:atemp>-3</gpxtpx:atemp>"));
+
+        let parsed = gpx::parse_gpx(xml.as_bytes()).unwrap();
+        let point = parsed.points().next().unwrap();
+        assert_eq!(point.extensions["power"], "250");
+        assert_eq!(point.extensions["atemp"], "-3");
+    }
+}
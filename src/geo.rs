@@ -0,0 +1,90 @@
+use chrono::{DateTime, NaiveDateTime};
+
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+/// Calculate distance between two (lat, lon) coordinates in km using the Haversine formula
+pub fn haversine_km(p1: (f64, f64), p2: (f64, f64)) -> f64 {
+    let d_lat = (p2.0 - p1.0).to_radians();
+    let d_lon = (p2.1 - p1.1).to_radians();
+    let lat1 = p1.0.to_radians();
+    let lat2 = p2.0.to_radians();
+
+    let a = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lon / 2.0).sin().powi(2);
+    let c = 2.0 * a.sqrt().asin();
+    EARTH_RADIUS_KM * c
+}
+
+/// Total length of a polyline of (lat, lon) points in km
+pub fn polyline_length_km<I>(points: I) -> f64
+where
+    I: IntoIterator<Item = (f64, f64)>,
+{
+    let mut total_km = 0.0;
+    let mut prev: Option<(f64, f64)> = None;
+    for p in points {
+        if let Some(prev) = prev {
+            total_km += haversine_km(prev, p);
+        }
+        prev = Some(p);
+    }
+    total_km
+}
+
+/// Sum of all positive elevation changes (climbing) in meters
+pub fn climb_m<I>(elevations: I) -> f64
+where
+    I: IntoIterator<Item = f64>,
+{
+    let mut total_gain = 0.0;
+    let mut prev: Option<f64> = None;
+    for ele in elevations {
+        if let Some(prev) = prev {
+            let diff = ele - prev;
+            if diff > 0.0 {
+                total_gain += diff;
+            }
+        }
+        prev = Some(ele);
+    }
+    total_gain
+}
+
+/// Round a distance in km to 10 m, as reported everywhere in the UI and database
+pub fn round_km(km: f64) -> f64 {
+    (km * 100.0).round() / 100.0
+}
+
+/// Total length of a polyline of (lat, lon) points in km, rounded to 10 m
+pub fn distance_km<I>(points: I) -> f64
+where
+    I: IntoIterator<Item = (f64, f64)>,
+{
+    round_km(polyline_length_km(points))
+}
+
+/// Elevation gain in whole meters
+pub fn elevation_gain_m<I>(elevations: I) -> i32
+where
+    I: IntoIterator<Item = f64>,
+{
+    climb_m(elevations).round() as i32
+}
+
+/// Parse an ISO8601 timestamp to Unix epoch seconds
+///
+/// Accepts `Z` and numeric offsets (`+02:00`), fractional seconds and missing seconds.
+/// Timestamps without an offset are taken as UTC.
+pub fn parse_iso8601(s: &str) -> Option<i64> {
+    let s = s.trim();
+    if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        return Some(t.timestamp());
+    }
+    // Offset but no seconds, e.g. 2024-01-15T10:30+01:00
+    if let Ok(t) = DateTime::parse_from_str(s, "%Y-%m-%dT%H:%M%:z") {
+        return Some(t.timestamp());
+    }
+    ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%dT%H:%M"]
+        .iter()
+        .find_map(|fmt| NaiveDateTime::parse_from_str(s.trim_end_matches('Z'), fmt).ok())
+        .map(|t| t.and_utc().timestamp())
+}
@@ -0,0 +1,272 @@
+use chrono::DateTime;
+use quick_xml::events::BytesStart;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::BufRead;
+
+use crate::geo;
+use crate::xml::{self, XmlHandler};
+
+/// A single recorded position of a track
+#[derive(Debug, Clone, Default)]
+pub struct TrackPoint {
+    pub lat: f64,
+    pub lon: f64,
+    pub ele: Option<f64>,
+    pub time: Option<i64>, // Unix timestamp in seconds
+    /// Leaf values from `<extensions>` keyed by local tag name (e.g. "hr", "cad", "atemp")
+    pub extensions: HashMap<String, String>,
+}
+
+/// A continuous run of points (`<trkseg>`)
+#[derive(Debug, Clone, Default)]
+pub struct TrackSegment {
+    pub points: Vec<TrackPoint>,
+}
+
+/// A track (`<trk>`) consisting of one or more segments
+#[derive(Debug, Clone, Default)]
+pub struct Track {
+    pub name: Option<String>,
+    pub segments: Vec<TrackSegment>,
+}
+
+/// Lap summary as recorded by the device (TCX `<Lap>`, FIT lap message)
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Lap {
+    pub start_time: Option<i64>, // Unix timestamp in seconds
+    pub total_time_s: Option<f64>,
+    pub distance_m: Option<f64>,
+    pub calories: Option<u32>,
+    pub avg_heart_rate: Option<u32>,
+    pub max_heart_rate: Option<u32>,
+    pub cadence: Option<u32>,
+}
+
+/// Parsed content of a GPX file
+///
+/// Other track formats (TCX, FIT) are converted into this model so they share one pipeline.
+#[derive(Debug, Clone, Default)]
+pub struct Gpx {
+    pub name: Option<String>,
+    pub time: Option<i64>, // Unix timestamp in seconds from <metadata><time>
+    pub tracks: Vec<Track>,
+    pub laps: Vec<Lap>,
+}
+
+impl Gpx {
+    /// Iterate over all track points of all tracks and segments in file order
+    pub fn points(&self) -> impl Iterator<Item = &TrackPoint> {
+        self.segments().flat_map(|s| s.points.iter())
+    }
+
+    /// Name of the activity: first track name, falling back to the metadata name
+    pub fn track_name(&self) -> Option<&str> {
+        self.tracks
+            .iter()
+            .find_map(|t| t.name.as_deref())
+            .or(self.name.as_deref())
+    }
+
+    /// Start time of the activity: metadata time, falling back to the first timed point
+    pub fn start_time(&self) -> Option<i64> {
+        self.time.or_else(|| self.points().find_map(|p| p.time))
+    }
+
+    /// Iterate over all segments of all tracks; each is a separate polyline
+    pub fn segments(&self) -> impl Iterator<Item = &TrackSegment> {
+        self.tracks.iter().flat_map(|t| t.segments.iter())
+    }
+
+    /// Total track length in km, without bridging gaps between segments or tracks
+    pub fn distance_km(&self) -> f64 {
+        geo::round_km(self.segments().map(|s| s.length_km()).sum())
+    }
+
+    /// Total climbing in meters, without counting jumps between segments or tracks
+    pub fn elevation_gain_m(&self) -> i32 {
+        let climb: f64 = self.segments().map(|s| s.climb_m()).sum();
+        climb.round() as i32
+    }
+}
+
+impl TrackSegment {
+    /// Unrounded length of this segment in km
+    pub fn length_km(&self) -> f64 {
+        geo::polyline_length_km(self.points.iter().map(|p| (p.lat, p.lon)))
+    }
+
+    /// Unrounded climbing within this segment in meters
+    pub fn climb_m(&self) -> f64 {
+        geo::climb_m(self.points.iter().filter_map(|p| p.ele))
+    }
+}
+
+/// Point extensions written into Garmin's TrackPointExtension, in schema order
+const TRACK_POINT_EXTENSIONS: [&str; 3] = ["atemp", "hr", "cad"];
+
+/// Serialize tracks back to GPX 1.1, e.g. to show converted FIT files on the map
+///
+/// Temperature, heart rate and cadence are written as Garmin TrackPointExtension, power
+/// as `<power>` directly in `<extensions>` like Strava does.
+pub fn to_gpx_xml(gpx: &Gpx) -> String {
+    let mut out = String::new();
+    out.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    out.push_str("<gpx version=\"1.1\" creator=\"rust-strava-example\" xmlns=\"http://www.topografix.com/GPX/1/1\" xmlns:gpxtpx=\"http://www.garmin.com/xmlschemas/TrackPointExtension/v1\">\n");
+    if let Some(time) = gpx.time.and_then(|t| DateTime::from_timestamp(t, 0)) {
+        out.push_str(&format!(
+            "  <metadata>\n    <time>{}</time>\n  </metadata>\n",
+            time.to_rfc3339()
+        ));
+    }
+    for track in &gpx.tracks {
+        out.push_str("  <trk>\n");
+        if let Some(name) = &track.name {
+            out.push_str(&format!("    <name>{}</name>\n", xml::escape(name)));
+        }
+        for segment in &track.segments {
+            out.push_str("    <trkseg>\n");
+            for p in &segment.points {
+                out.push_str(&format!(
+                    "      <trkpt lat=\"{:.7}\" lon=\"{:.7}\">\n",
+                    p.lat, p.lon
+                ));
+                if let Some(e) = p.ele {
+                    out.push_str(&format!("        <ele>{:.2}</ele>\n", e));
+                }
+                if let Some(t) = p.time.and_then(|t| DateTime::from_timestamp(t, 0)) {
+                    out.push_str(&format!("        <time>{}</time>\n", t.to_rfc3339()));
+                }
+                push_extensions(&mut out, p);
+                out.push_str("      </trkpt>\n");
+            }
+            out.push_str("    </trkseg>\n");
+        }
+        out.push_str("  </trk>\n");
+    }
+    out.push_str("</gpx>\n");
+    out
+}
+
+fn push_extensions(out: &mut String, point: &TrackPoint) {
+    let garmin: Vec<(&str, &String)> = TRACK_POINT_EXTENSIONS
+        .iter()
+        .filter_map(|&name| point.extensions.get(name).map(|v| (name, v)))
+        .collect();
+    let power = point.extensions.get("power");
+    if garmin.is_empty() && power.is_none() {
+        return;
+    }
+    out.push_str("        <extensions>\n");
+    if let Some(power) = power {
+        out.push_str(&format!(
+            "          <power>{}</power>\n",
+            xml::escape(power)
+        ));
+    }
+    if !garmin.is_empty() {
+        out.push_str("          <gpxtpx:TrackPointExtension>\n");
+        for (name, value) in garmin {
+            out.push_str(&format!(
+                "            <gpxtpx:{}>{}</gpxtpx:{}>\n",
+                name,
+                xml::escape(value),
+                name
+            ));
+        }
+        out.push_str("          </gpxtpx:TrackPointExtension>\n");
+    }
+    out.push_str("        </extensions>\n");
+}
+
+/// Parse GPX from any buffered reader using a streaming XML parser
+///
+/// Namespace prefixes are ignored, so `<gpx:trkpt>` and `<trkpt>` are treated the same.
+/// Waypoints and routes are skipped; only `<trk>` content ends up in the result.
+pub fn parse_gpx<R: BufRead>(source: R) -> Result<Gpx, String> {
+    let mut handler = GpxHandler::default();
+    xml::stream(source, &mut handler)?;
+    Ok(handler.gpx)
+}
+
+#[derive(Default)]
+struct GpxHandler {
+    gpx: Gpx,
+    current_point: Option<TrackPoint>,
+}
+
+impl XmlHandler for GpxHandler {
+    fn open(&mut self, stack: &[String], name: &str, e: &BytesStart) -> Result<(), String> {
+        match (stack.last().map(String::as_str), name) {
+            (Some("gpx"), "trk") => self.gpx.tracks.push(Track::default()),
+            (Some("trk"), "trkseg") => {
+                if let Some(track) = self.gpx.tracks.last_mut() {
+                    track.segments.push(TrackSegment::default());
+                }
+            }
+            (Some("trkseg"), "trkpt") => {
+                let lat = xml::attr(e, "lat")?.and_then(|v| v.parse::<f64>().ok());
+                let lon = xml::attr(e, "lon")?.and_then(|v| v.parse::<f64>().ok());
+                // Points without valid coordinates are dropped when closed
+                self.current_point = match (lat, lon) {
+                    (Some(lat), Some(lon)) => Some(TrackPoint {
+                        lat,
+                        lon,
+                        ..Default::default()
+                    }),
+                    _ => None,
+                };
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn close(&mut self, stack: &[String], text: &str) {
+        let gpx = &mut self.gpx;
+        match xml::leaf(stack) {
+            (Some("trkseg"), "trkpt") => {
+                if let Some(point) = self.current_point.take() {
+                    if let Some(segment) = gpx.tracks.last_mut().and_then(|t| t.segments.last_mut())
+                    {
+                        segment.points.push(point);
+                    }
+                }
+            }
+            (Some("trkpt"), "ele") => {
+                if let Some(point) = self.current_point.as_mut() {
+                    point.ele = text.parse().ok();
+                }
+            }
+            (Some("trkpt"), "time") => {
+                if let Some(point) = self.current_point.as_mut() {
+                    point.time = geo::parse_iso8601(text);
+                }
+            }
+            (Some("trk"), "name") => {
+                if let Some(track) = gpx.tracks.last_mut() {
+                    track.name = xml::non_empty(text);
+                }
+            }
+            (Some("metadata"), "name") | (Some("gpx"), "name") => {
+                gpx.name = xml::non_empty(text);
+            }
+            // GPX 1.1 stores the file time in <metadata>, GPX 1.0 directly under <gpx>
+            (Some("metadata"), "time") | (Some("gpx"), "time") => {
+                gpx.time = geo::parse_iso8601(text);
+            }
+            (Some(_), name) if !text.is_empty() => {
+                // Leaf values anywhere below <trkpt><extensions>, e.g. gpxtpx:TrackPointExtension/hr
+                let ancestors = &stack[..stack.len() - 1];
+                let in_extensions = ancestors.iter().any(|s| s == "extensions")
+                    && ancestors.iter().any(|s| s == "trkpt");
+                if in_extensions {
+                    if let Some(point) = self.current_point.as_mut() {
+                        point.extensions.insert(name.to_string(), text.to_string());
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
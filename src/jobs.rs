@@ -0,0 +1,116 @@
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+/// Finished jobs kept for late subscribers
+const KEEP_FINISHED: usize = 10;
+/// Events buffered per subscriber; slower subscribers miss events
+const CHANNEL_CAPACITY: usize = 256;
+
+/// A task running in the background that reports its progress as JSON events
+pub struct Job {
+    pub id: u64,
+    cancelled: AtomicBool,
+    events: Mutex<JobEvents>,
+}
+
+struct JobEvents {
+    /// Every event so far, replayed to subscribers that connect late
+    history: Vec<String>,
+    /// None once the job is finished, which ends the subscriptions
+    sender: Option<broadcast::Sender<String>>,
+}
+
+impl Job {
+    fn new(id: u64) -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        Job {
+            id,
+            cancelled: AtomicBool::new(false),
+            events: Mutex::new(JobEvents {
+                history: Vec::new(),
+                sender: Some(sender),
+            }),
+        }
+    }
+
+    /// Report an event to all subscribers
+    pub fn send<T: Serialize>(&self, event: &T) {
+        let data = match serde_json::to_string(event) {
+            Ok(data) => data,
+            Err(e) => {
+                eprintln!("Error serializing event of job {}: {}", self.id, e);
+                return;
+            }
+        };
+        let mut events = self.events.lock().unwrap();
+        if let Some(sender) = &events.sender {
+            // Fails only without subscribers; they get the history when they connect
+            let _ = sender.send(data.clone());
+            events.history.push(data);
+        }
+    }
+
+    /// Report the last event and end the subscriptions
+    pub fn finish<T: Serialize>(&self, event: &T) {
+        self.send(event);
+        self.events.lock().unwrap().sender = None;
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.events.lock().unwrap().sender.is_none()
+    }
+
+    /// Ask the job to stop; it checks the flag between steps
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn cancel_flag(&self) -> &AtomicBool {
+        &self.cancelled
+    }
+
+    /// Events so far and a receiver for the following ones, None if the job is finished
+    pub fn subscribe(&self) -> (Vec<String>, Option<broadcast::Receiver<String>>) {
+        let events = self.events.lock().unwrap();
+        (
+            events.history.clone(),
+            events.sender.as_ref().map(|s| s.subscribe()),
+        )
+    }
+}
+
+/// Background jobs of the server; only one runs at a time
+#[derive(Default)]
+pub struct Jobs {
+    next_id: AtomicU64,
+    jobs: Mutex<Vec<Arc<Job>>>,
+}
+
+impl Jobs {
+    /// Register a new job, or return the running one as error
+    pub fn start(&self) -> Result<Arc<Job>, Arc<Job>> {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(running) = jobs.iter().find(|j| !j.is_finished()) {
+            return Err(running.clone());
+        }
+        // All others are finished
+        let excess = jobs.len().saturating_sub(KEEP_FINISHED);
+        jobs.drain(..excess);
+        let job = Arc::new(Job::new(self.next_id.fetch_add(1, Ordering::Relaxed) + 1));
+        jobs.push(job.clone());
+        Ok(job)
+    }
+
+    pub fn get(&self, id: u64) -> Option<Arc<Job>> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.iter().find(|j| j.id == id).cloned()
+    }
+
+    /// The job that is currently running
+    pub fn running(&self) -> Option<Arc<Job>> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.iter().find(|j| !j.is_finished()).cloned()
+    }
+}
@@ -4,14 +4,31 @@ use dotenvy::dotenv;
 use std::env;
 use std::path::PathBuf;
 use std::process::Command;
+use std::sync::atomic::AtomicBool;
 use std::sync::{Arc, Mutex};
 use tokio::net::TcpListener;
 use tokio::sync::oneshot;
 
+mod archive;
+mod auth;
 mod database;
+mod fit;
+mod geo;
+mod gpx;
+mod jobs;
 mod map_server;
+mod regions;
+mod scheduler;
 mod strava;
+mod sync;
+mod tcx;
 mod tiles;
+mod timeline;
+mod track_file;
+mod xml;
+
+/// Default port of the command line OAuth callback, next to the map server's 8080
+const DEFAULT_CALLBACK_PORT: u16 = 8081;
 
 #[derive(Debug, Parser)]
 #[command(name = "rust_strava", about = "Strava API Rust example")]
@@ -20,13 +37,14 @@ struct Cli {
     #[arg(long)]
     exchange_code: Option<String>,
 
-    /// Per-page for activities list
-    #[arg(long, default_value_t = 5)]
-    per_page: u32,
+    /// Only sync activities started after this date (YYYY-MM-DD or Unix timestamp);
+    /// defaults to where the last sync stopped
+    #[arg(long, value_name = "DATE", value_parser = sync::parse_time)]
+    after: Option<i64>,
 
-    /// Page number for activities list
-    #[arg(long, default_value_t = 1)]
-    page: u32,
+    /// Only sync activities started before this date (YYYY-MM-DD or Unix timestamp)
+    #[arg(long, value_name = "DATE", value_parser = sync::parse_time)]
+    before: Option<i64>,
 
     /// Start a web server to display GPX files on a Leaflet map
     #[arg(long)]
@@ -35,6 +53,36 @@ struct Cli {
     /// Fetch all activities, including already imported ones
     #[arg(long)]
     fetch_all: bool,
+
+    /// Import a Strava account export (ZIP from "Download your data") and exit
+    #[arg(long, value_name = "ZIP")]
+    import_archive: Option<PathBuf>,
+
+    /// Delete an activity (track file, tiles and import record) and exit
+    #[arg(long, value_name = "ACTIVITY_ID")]
+    delete_activity: Option<String>,
+
+    /// Recompute the tiles of an activity from its track file and exit
+    #[arg(long, value_name = "ACTIVITY_ID")]
+    reprocess_activity: Option<String>,
+
+    /// Address the map server listens on [env: BIND] [default: 127.0.0.1]
+    #[arg(long, value_name = "ADDRESS")]
+    bind: Option<String>,
+
+    /// Port of the map server [env: PORT] [default: 8080]
+    #[arg(long)]
+    port: Option<u16>,
+
+    /// URL the map server is reached at, e.g. behind a reverse proxy; used for its OAuth
+    /// redirect URI [env: PUBLIC_URL] [default: http://localhost:<port>]
+    #[arg(long, value_name = "URL")]
+    public_url: Option<String>,
+
+    /// Local port of the command line OAuth callback, redirected to as
+    /// http://localhost:<port>/callback [env: CALLBACK_PORT] [default: 8081]
+    #[arg(long, value_name = "PORT")]
+    callback_port: Option<u16>,
 }
 
 #[tokio::main]
@@ -43,9 +91,67 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let _ = dotenv();
     let args = Cli::parse();
 
+    // Command line options take precedence over the environment
+    let mut server_options = map_server::ServerOptions::from_env();
+    if let Some(bind) = args.bind {
+        server_options.bind = bind;
+    }
+    if let Some(port) = args.port {
+        server_options.port = port;
+    }
+    if let Some(public_url) = args.public_url {
+        server_options.public_url = Some(public_url);
+    }
+
     // Serve map mode - start web server to display GPX files
     if args.serve_map {
-        return map_server::serve_map_server().await;
+        return map_server::serve_map_server(server_options).await;
+    }
+
+    // Archive import mode - no Strava API access needed
+    if let Some(path) = args.import_archive {
+        let file = std::fs::File::open(&path)?;
+        let mut conn = database::init_db()?;
+        let summary = archive::import_archive(
+            &mut conn,
+            file,
+            &PathBuf::from(tiles::GPX_DIR),
+            &tiles::TileOptions::from_env(),
+            &regions::Regions::from_env(tiles::TILE_ZOOM),
+        )?;
+        println!(
+            "Imported {} activities ({} without track, {} already imported or deleted), {} new tiles, {} new regions",
+            summary.imported,
+            summary.without_track,
+            summary.skipped,
+            summary.new_tiles,
+            summary.new_regions.len()
+        );
+        for error in &summary.errors {
+            eprintln!("Error: {}", error);
+        }
+        return Ok(());
+    }
+
+    // Delete or reprocess a single activity; first visits and regions may go to other
+    // activities, so the region visits are recomputed as in the map server
+    if let Some(activity_id) = args.delete_activity {
+        let mut conn = database::init_db()?;
+        let affected = tiles::delete_activity(&mut conn, &activity_id)?;
+        regions::update_region_visits(&mut conn, &regions::Regions::from_env(tiles::TILE_ZOOM))?;
+        println!(
+            "Deleted activity {}, {} tiles affected",
+            activity_id, affected
+        );
+        return Ok(());
+    }
+    if let Some(activity_id) = args.reprocess_activity {
+        let mut conn = database::init_db()?;
+        let options = tiles::TileOptions::from_env();
+        let count = tiles::reprocess_activity(&mut conn, &activity_id, &options)?;
+        regions::update_region_visits(&mut conn, &regions::Regions::from_env(tiles::TILE_ZOOM))?;
+        println!("Reprocessed activity {}: {} tiles", activity_id, count);
+        return Ok(());
     }
 
     // Credentials are read from environment (.env supported):
@@ -66,10 +172,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         match strava::exchange_code(&client, &client_id, &client_secret, &code).await {
             Ok(token) => {
                 println!("Access token: {}", token.access_token);
-                if let Some(rt) = token.refresh_token {
+                if let Some(rt) = &token.refresh_token {
                     println!("Refresh token: {}", rt);
                 }
                 println!("Note: Save tokens securely. Do NOT commit them.");
+                save_token(&token);
             }
             Err(e) => {
                 eprintln!("{}", e);
@@ -85,9 +192,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     #[derive(Clone)]
     struct AppState {
         tx: Arc<Mutex<Option<oneshot::Sender<String>>>>,
+        // Random OAuth state; callbacks without it are rejected
+        oauth_state: String,
     }
+    let oauth_state = auth::random_state()?;
     let state = AppState {
         tx: Arc::new(Mutex::new(Some(tx))),
+        oauth_state: oauth_state.clone(),
     };
 
     // Minimal axum server to capture `code` at /callback
@@ -97,7 +208,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             get(
                 |State(state): State<AppState>,
                  Query(params): Query<std::collections::HashMap<String, String>>| async move {
-                    if let Some(code) = params.get("code").cloned() {
+                    if params.get("state") != Some(&state.oauth_state) {
+                        "Invalid state parameter, ignoring callback"
+                    } else if !params
+                        .get("scope")
+                        .is_some_and(|scope| strava::has_required_scope(scope))
+                    {
+                        "Missing activity:read_all permission. Run again and keep the private activities box ticked."
+                    } else if let Some(code) = params.get("code").cloned() {
                         if let Some(sender) = state.tx.lock().unwrap().take() {
                             let _ = sender.send(code);
                         }
@@ -110,7 +228,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         )
         .with_state(state);
 
-    let listener = TcpListener::bind("127.0.0.1:8080").await?;
+    // Always on this machine, where the browser opened for the login runs
+    let callback_port = args
+        .callback_port
+        .or_else(|| {
+            env::var("CALLBACK_PORT")
+                .ok()
+                .and_then(|v| v.trim().parse().ok())
+        })
+        .unwrap_or(DEFAULT_CALLBACK_PORT);
+    let listener = TcpListener::bind(("127.0.0.1", callback_port)).await?;
     let server_handle = tokio::spawn(async move {
         if let Err(e) = axum::serve(listener, app).await {
             eprintln!("Server error: {}", e);
@@ -132,9 +259,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         return Ok(());
     }
 
-    // Use localhost redirect and ensure it matches your Strava app settings exactly.
-    let redirect_uri = "http://localhost:8080/callback";
-    let authorize_url = strava::get_authorize_url(&client_id, redirect_uri);
+    // The redirect URI's host must match the callback domain in your Strava app settings.
+    let redirect_uri = format!("http://localhost:{}/callback", callback_port);
+    let authorize_url = strava::get_authorize_url(&client_id, &redirect_uri, &oauth_state);
     println!("Opening browser for OAuth: {}", authorize_url);
     let _ = Command::new("open").arg(&authorize_url).status();
 
@@ -159,6 +286,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     {
         Ok(token) => {
             println!("Obtained access token via OAuth.");
+            save_token(&token);
             token.access_token
         }
         Err(e) => {
@@ -187,42 +315,59 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         athlete.username.as_deref().unwrap_or("")
     );
 
-    // Example 2: List recent activities
-    let activities =
-        match strava::get_activities(&client, &token_value, args.per_page, args.page).await {
-            Ok(a) => a,
-            Err(e) => {
-                eprintln!("{}", e);
-                return Ok(());
-            }
-        };
-
-    if activities.is_empty() {
-        println!("No activities found.");
-        return Ok(());
-    }
-
-    // Initialize database for tracking imported activities
-    let db_conn = match database::init_db() {
-        Ok(conn) => Some(conn),
+    // Example 2: Sync all new activities, page by page
+    let db = match database::init_db() {
+        Ok(conn) => Arc::new(Mutex::new(conn)),
         Err(e) => {
-            eprintln!("Warning: Could not initialize database: {}. Proceeding without import tracking.", e);
-            None
+            eprintln!("Could not initialize database: {}", e);
+            return Ok(());
         }
     };
-
-    // Export activities as GPX files
-    let out_dir = PathBuf::from("gpx");
-    if let Err(e) = strava::export_activities_as_gpx(
+    let tokens = auth::TokenProvider::from_env(db.clone());
+    let options = sync::SyncOptions {
+        after: args.after,
+        before: args.before,
+        fetch_all: args.fetch_all,
+    };
+    let summary = sync::sync_activities(
         &client,
-        &token_value,
-        &activities,
-        &out_dir,
-        db_conn.as_ref(),
-        args.fetch_all,
-    ).await {
-        eprintln!("Export failed: {}", e);
+        &tokens,
+        &db,
+        &PathBuf::from(tiles::GPX_DIR),
+        &options,
+        &|_| {},
+        &AtomicBool::new(false),
+    )
+    .await;
+    println!(
+        "Imported {} activities ({} already imported, {} failed)",
+        summary.imported, summary.skipped, summary.failed
+    );
+    if let Some(error) = summary.error {
+        eprintln!("Sync stopped: {}", error);
+    }
+
+    // Add the new tracks' tiles and regions, as the map server does after a sync
+    if summary.imported > 0 {
+        let new_regions = sync::process_new_tracks(
+            &mut db.lock().unwrap(),
+            &tiles::TileOptions::from_env(),
+            &regions::Regions::from_env(tiles::TILE_ZOOM),
+            &summary.imported_ids,
+        );
+        println!("{} new regions", new_regions.len());
     }
 
     Ok(())
 }
+
+/// Store tokens in the database, so the map server can use them without another login
+fn save_token(token: &strava::TokenResponse) {
+    let result = database::init_db()
+        .map_err(|e| e.to_string())
+        .and_then(|conn| auth::TokenProvider::from_env(Arc::new(Mutex::new(conn))).save(token));
+    match result {
+        Ok(()) => println!("Tokens saved in the database."),
+        Err(e) => eprintln!("Could not save tokens: {}", e),
+    }
+}
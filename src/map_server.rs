@@ -1,28 +1,122 @@
 use axum::{
+    body::Body,
     extract::Path as AxumPath,
     extract::Query,
     extract::State,
     http::header,
+    response::sse::{Event, KeepAlive, Sse},
     response::IntoResponse,
     routing::{get, post},
     Json, Router,
 };
+use chrono::Utc;
+use futures_util::stream::{self, Stream, StreamExt};
 use rusqlite::Connection;
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::convert::Infallible;
 use std::fs;
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex, RwLock};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncWriteExt;
 use tokio::net::TcpListener;
+use tokio::sync::broadcast::error::RecvError;
 
+use crate::archive;
+use crate::auth::{PendingLogins, TokenProvider};
 use crate::database;
-use crate::strava;
+use crate::database::{RegionVisit, SyncRun};
+use crate::gpx::Lap;
+use crate::jobs::{Job, Jobs};
+use crate::regions::{self, RegionCoverage, Regions};
+use crate::scheduler::{self, ScheduleOptions};
+use crate::strava::{self, StravaClient};
+use crate::sync::{self, SyncOptions};
 use crate::tiles;
+use crate::timeline::{self, TimelineEntry, TimelineStep};
+use crate::track_file::{self, TrackFormat};
 
 #[derive(Clone)]
 struct AppState {
     db: Arc<Mutex<Connection>>,
-    // Store the current access token (refreshed via OAuth)
-    access_token: Arc<RwLock<Option<String>>>,
+    /// Strava tokens, stored in the database and refreshed before they expire
+    tokens: Arc<TokenProvider>,
+    /// OAuth states of logins started at /auth/start
+    logins: Arc<PendingLogins>,
+    /// Shared by all requests, so they count against the same rate limits
+    strava: Arc<StravaClient>,
+    /// Imports running in the background
+    jobs: Arc<Jobs>,
+    tile_options: tiles::TileOptions,
+    /// Region layers with their tiles, loaded once at startup
+    regions: Arc<Regions>,
+    /// Base URL the browser reaches the server at, without trailing slash
+    public_url: String,
+}
+
+/// Default address the server listens on
+const DEFAULT_BIND: &str = "127.0.0.1";
+/// Default port of the map server
+pub const DEFAULT_PORT: u16 = 8080;
+
+/// Where the server listens and how it is reached from the browser
+#[derive(Debug, Clone)]
+pub struct ServerOptions {
+    /// Address to listen on, e.g. 0.0.0.0 behind a reverse proxy
+    pub bind: String,
+    pub port: u16,
+    /// URL the server is reached at, e.g. https://strava.example.org behind a reverse
+    /// proxy; also used for the OAuth redirect URI
+    pub public_url: Option<String>,
+    /// Sync with Strava in the background; off without an interval
+    pub schedule: Option<ScheduleOptions>,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        ServerOptions {
+            bind: DEFAULT_BIND.to_string(),
+            port: DEFAULT_PORT,
+            public_url: None,
+            schedule: None,
+        }
+    }
+}
+
+impl ServerOptions {
+    /// Read options from the environment (.env supported): BIND, PORT, PUBLIC_URL and
+    /// the SYNC_* schedule
+    pub fn from_env() -> Self {
+        let defaults = ServerOptions::default();
+        let bind = std::env::var("BIND")
+            .ok()
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+            .unwrap_or(defaults.bind);
+        let port = std::env::var("PORT")
+            .ok()
+            .and_then(|v| v.trim().parse().ok())
+            .unwrap_or(defaults.port);
+        let public_url = std::env::var("PUBLIC_URL")
+            .ok()
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty());
+        ServerOptions {
+            bind,
+            port,
+            public_url,
+            schedule: ScheduleOptions::from_env(),
+        }
+    }
+
+    /// The public URL without trailing slash, `http://localhost:<port>` if none is set
+    pub fn base_url(&self) -> String {
+        match &self.public_url {
+            Some(url) => url.trim_end_matches('/').to_string(),
+            None => format!("http://localhost:{}", self.port),
+        }
+    }
 }
 
 #[derive(Serialize)]
@@ -31,56 +125,110 @@ struct GpxFileInfo {
     modified: u64, // Unix timestamp in seconds
     distance_km: f64,
     elevation_gain_m: i32,
+    laps: Vec<Lap>,
 }
 
-pub async fn serve_map_server() -> Result<(), Box<dyn std::error::Error>> {
+pub async fn serve_map_server(options: ServerOptions) -> Result<(), Box<dyn std::error::Error>> {
     // Initialize database
     let mut conn = database::init_db()?;
+    let tile_options = tiles::TileOptions::from_env();
 
     // Process any new GPX files on startup
     println!("Processing GPX files...");
-    let new_tiles = tiles::process_all_gpx_files(&mut conn)?;
+    let new_tiles = tiles::process_all_gpx_files(&mut conn, &tile_options)?;
     if new_tiles > 0 {
         println!("Added {} new tile entries", new_tiles);
     }
 
-    let total_tiles = database::get_tile_count(&conn)?;
-    println!("Total tiles in database: {}", total_tiles);
+    for &zoom in &tile_options.zooms {
+        let total_tiles = database::get_tile_count(&conn, zoom)?;
+        println!("Total tiles in database at zoom {}: {}", zoom, total_tiles);
+    }
+
+    println!("Loading regions...");
+    let regions = Regions::from_env(tiles::TILE_ZOOM);
+    for layer in &regions.layers {
+        println!(
+            "Region layer {}: {} regions",
+            layer.name,
+            layer.regions.len()
+        );
+    }
+    if let Err(e) = regions::update_region_visits(&mut conn, &regions) {
+        eprintln!("Error updating region visits: {}", e);
+    }
 
+    let db = Arc::new(Mutex::new(conn));
     let state = AppState {
-        db: Arc::new(Mutex::new(conn)),
-        access_token: Arc::new(RwLock::new(None)),
+        tokens: Arc::new(TokenProvider::from_env(db.clone())),
+        logins: Arc::new(PendingLogins::default()),
+        strava: Arc::new(strava::create_client()?),
+        jobs: Arc::new(Jobs::default()),
+        db,
+        tile_options,
+        regions: Arc::new(regions),
+        public_url: options.base_url(),
     };
 
+    if let Some(schedule) = options.schedule.clone() {
+        match schedule.quiet_hours {
+            Some(quiet) => println!(
+                "Syncing with Strava every {} min, except {}:00-{}:00",
+                schedule.interval.as_secs() / 60,
+                quiet.start,
+                quiet.end
+            ),
+            None => println!(
+                "Syncing with Strava every {} min",
+                schedule.interval.as_secs() / 60
+            ),
+        }
+        let state = state.clone();
+        tokio::spawn(scheduler::run_scheduled(schedule, move || {
+            let state = state.clone();
+            async move { scheduled_import(&state).await }
+        }));
+    }
+
     let app = Router::new()
         .route("/", get(serve_map_html))
         .route("/gpx", get(list_gpx_files))
         .route("/gpx/:filename", get(serve_gpx_file))
         .route("/tiles", get(list_visited_tiles))
-        .route("/gemeinden.geojson", get(serve_gemeinden_geojson))
-        .route(
-            "/sachsen_gemeinden.geojson",
-            get(serve_sachsen_gemeinden_geojson),
-        )
-        .route("/sachsen_kreise.geojson", get(serve_sachsen_kreise_geojson))
-        .route(
-            "/thueringen_gemeinden.geojson",
-            get(serve_thueringen_gemeinden_geojson),
-        )
+        .route("/tiles/:z/:x/:y", get(get_tile_detail))
+        .route("/tile-visits", get(list_tile_visits))
+        .route("/regions", get(list_region_layers))
+        .route("/regions/coverage", get(get_region_coverage))
+        .route("/regions/:layer", get(serve_region_layer))
+        .route("/fetch-activities", post(fetch_activities))
+        .route("/jobs/current", get(get_current_job))
+        .route("/sync-runs", get(list_sync_runs))
+        .route("/jobs/:id/events", get(job_events))
+        .route("/jobs/:id/cancel", post(cancel_job))
         .route(
-            "/thueringen_kreise.geojson",
-            get(serve_thueringen_kreise_geojson),
+            "/activities/:id",
+            get(get_activity_detail).delete(delete_activity),
         )
-        .route("/fetch-activities", post(fetch_activities))
+        .route("/activities/:id/reprocess", post(reprocess_activity))
+        .route("/import-archive", post(import_archive))
         .route("/stats", get(get_stats))
         .route("/square-cluster", get(get_square_cluster))
+        .route("/clusters", get(get_clusters))
+        .route("/suggestions", get(get_suggestions))
+        .route("/timeline", get(get_timeline))
         .route("/auth/start", get(auth_start))
         .route("/auth/callback", get(auth_callback))
         .route("/auth/status", get(auth_status))
+        .route("/rate-limit", get(get_rate_limit))
         .with_state(state);
 
-    let listener = TcpListener::bind("127.0.0.1:8080").await?;
-    println!("Map server running at http://127.0.0.1:8080");
+    let listener = TcpListener::bind((options.bind.as_str(), options.port)).await?;
+    println!(
+        "Map server listening on {}:{}, reachable at {}",
+        options.bind,
+        options.port,
+        options.base_url()
+    );
     axum::serve(listener, app).await?;
     Ok(())
 }
@@ -101,559 +249,808 @@ async fn serve_map_html() -> impl IntoResponse {
     }
 }
 
-async fn serve_gemeinden_geojson() -> impl IntoResponse {
-    let path = PathBuf::from("static/gemeinden.geojson");
-    match fs::read_to_string(&path) {
-        Ok(content) => (
-            axum::http::StatusCode::OK,
-            [(header::CONTENT_TYPE, "application/geo+json")],
-            content,
-        ),
-        Err(_) => (
-            axum::http::StatusCode::NOT_FOUND,
-            [(header::CONTENT_TYPE, "text/plain")],
-            "gemeinden.geojson not found".to_string(),
-        ),
-    }
-}
-
-async fn serve_sachsen_gemeinden_geojson() -> impl IntoResponse {
-    let path = PathBuf::from("static/sachsen_gemeinden.geojson");
-    match fs::read_to_string(&path) {
-        Ok(content) => (
-            axum::http::StatusCode::OK,
-            [(header::CONTENT_TYPE, "application/geo+json")],
-            content,
-        ),
-        Err(_) => (
-            axum::http::StatusCode::NOT_FOUND,
-            [(header::CONTENT_TYPE, "text/plain")],
-            "sachsen_gemeinden.geojson not found".to_string(),
-        ),
-    }
-}
-
-async fn serve_sachsen_kreise_geojson() -> impl IntoResponse {
-    let path = PathBuf::from("static/sachsen_kreise.geojson");
-    match fs::read_to_string(&path) {
-        Ok(content) => (
-            axum::http::StatusCode::OK,
-            [(header::CONTENT_TYPE, "application/geo+json")],
-            content,
-        ),
-        Err(_) => (
-            axum::http::StatusCode::NOT_FOUND,
-            [(header::CONTENT_TYPE, "text/plain")],
-            "sachsen_kreise.geojson not found".to_string(),
-        ),
-    }
-}
-
-async fn serve_thueringen_gemeinden_geojson() -> impl IntoResponse {
-    let path = PathBuf::from("static/thueringen_gemeinden.geojson");
-    match fs::read_to_string(&path) {
-        Ok(content) => (
-            axum::http::StatusCode::OK,
-            [(header::CONTENT_TYPE, "application/geo+json")],
-            content,
-        ),
-        Err(_) => (
-            axum::http::StatusCode::NOT_FOUND,
-            [(header::CONTENT_TYPE, "text/plain")],
-            "thueringen_gemeinden.geojson not found".to_string(),
-        ),
-    }
-}
-
-async fn serve_thueringen_kreise_geojson() -> impl IntoResponse {
-    let path = PathBuf::from("static/thueringen_kreise.geojson");
-    match fs::read_to_string(&path) {
-        Ok(content) => (
-            axum::http::StatusCode::OK,
-            [(header::CONTENT_TYPE, "application/geo+json")],
-            content,
-        ),
-        Err(_) => (
-            axum::http::StatusCode::NOT_FOUND,
-            [(header::CONTENT_TYPE, "text/plain")],
-            "thueringen_kreise.geojson not found".to_string(),
-        ),
-    }
-}
-
 async fn list_gpx_files() -> Json<Vec<GpxFileInfo>> {
-    let gpx_dir = PathBuf::from("gpx");
+    let gpx_dir = PathBuf::from(tiles::GPX_DIR);
     let mut files = Vec::new();
     if let Ok(entries) = fs::read_dir(&gpx_dir) {
         for entry in entries.flatten() {
             if let Some(name) = entry.file_name().to_str() {
-                if name.ends_with(".gpx") {
+                if track_file::is_track_file(name) {
                     let path = entry.path();
-                    let (modified, distance_km, elevation_gain_m) = parse_gpx_info(&path);
+                    let (modified, distance_km, elevation_gain_m, laps) = parse_gpx_info(&path);
                     files.push(GpxFileInfo {
                         filename: name.to_string(),
                         modified,
                         distance_km,
                         elevation_gain_m,
+                        laps,
                     });
                 }
             }
         }
     }
     // Sort by modified time, newest first
-    files.sort_by(|a, b| b.modified.cmp(&a.modified));
+    files.sort_by_key(|f| std::cmp::Reverse(f.modified));
     Json(files)
 }
 
-fn parse_gpx_info(path: &PathBuf) -> (u64, f64, i32) {
-    let content = match fs::read_to_string(path) {
-        Ok(c) => c,
-        Err(_) => return (0, 0.0, 0),
+fn parse_gpx_info(path: &Path) -> (u64, f64, i32, Vec<Lap>) {
+    let gpx = match track_file::read_track_file(path) {
+        Ok(g) => g,
+        Err(_) => return (0, 0.0, 0, Vec::new()),
     };
 
-    let timestamp = extract_gpx_time(&content);
-    let distance = calculate_distance_from_content(&content);
-    let elevation_gain = calculate_elevation_gain(&content);
+    let timestamp = gpx.start_time().unwrap_or(0).max(0) as u64;
+    let distance = gpx.distance_km();
+    let elevation_gain = gpx.elevation_gain_m();
 
-    (timestamp, distance, elevation_gain)
+    (timestamp, distance, elevation_gain, gpx.laps)
 }
 
-fn extract_gpx_time(content: &str) -> u64 {
-    // Try to find <time> element in metadata or first trackpoint
-    if let Some(start) = content.find("<time>") {
-        let rest = &content[start + 6..];
-        if let Some(end) = rest.find("</time>") {
-            let time_str = &rest[..end];
-            return parse_iso8601(time_str);
-        }
+async fn serve_gpx_file(AxumPath(filename): AxumPath<String>) -> impl IntoResponse {
+    if filename.contains("..") || filename.contains('/') || filename.contains('\\') {
+        return (
+            axum::http::StatusCode::BAD_REQUEST,
+            [(header::CONTENT_TYPE, "text/plain")],
+            "Invalid filename".to_string(),
+        );
     }
-    0
-}
-
-fn parse_iso8601(s: &str) -> u64 {
-    // Parse ISO 8601 format: 2024-01-15T10:30:00Z or 2024-01-15T10:30:00+00:00
-    let s = s.trim();
-
-    // Remove timezone suffix for simpler parsing
-    let s = s.trim_end_matches('Z');
-    let s = if let Some(pos) = s.rfind('+') {
-        &s[..pos]
-    } else if let Some(pos) = s.rfind('-') {
-        // Check if this is a date separator or timezone
-        if pos > 10 {
-            &s[..pos]
-        } else {
-            s
+    let content_type = match TrackFormat::from_filename(&filename) {
+        Some(format) => format.content_type(),
+        None => {
+            return (
+                axum::http::StatusCode::BAD_REQUEST,
+                [(header::CONTENT_TYPE, "text/plain")],
+                "Unsupported file type".to_string(),
+            )
         }
-    } else {
-        s
     };
-
-    // Parse: YYYY-MM-DDTHH:MM:SS
-    let parts: Vec<&str> = s.split('T').collect();
-    if parts.len() != 2 {
-        return 0;
-    }
-
-    let date_parts: Vec<u32> = parts[0].split('-').filter_map(|p| p.parse().ok()).collect();
-    let time_parts: Vec<u32> = parts[1].split(':').filter_map(|p| p.parse().ok()).collect();
-
-    if date_parts.len() < 3 || time_parts.len() < 3 {
-        return 0;
-    }
-
-    let year = date_parts[0];
-    let month = date_parts[1];
-    let day = date_parts[2];
-    let hour = time_parts[0];
-    let min = time_parts[1];
-    let sec = time_parts[2];
-
-    // Simple conversion to Unix timestamp (not accounting for leap seconds, etc.)
-    // Days from 1970-01-01
-    let mut days: i64 = 0;
-    for y in 1970..year {
-        days += if is_leap_year(y) { 366 } else { 365 };
-    }
-    let month_days = [0, 31, 59, 90, 120, 151, 181, 212, 243, 273, 304, 334];
-    days += month_days[(month - 1) as usize] as i64;
-    if month > 2 && is_leap_year(year) {
-        days += 1;
+    let path = PathBuf::from(tiles::GPX_DIR).join(&filename);
+    match track_file::read_track_xml(&path) {
+        Ok(content) => (
+            axum::http::StatusCode::OK,
+            [(header::CONTENT_TYPE, content_type)],
+            content,
+        ),
+        Err(_) => (
+            axum::http::StatusCode::NOT_FOUND,
+            [(header::CONTENT_TYPE, "text/plain")],
+            "File not found".to_string(),
+        ),
     }
-    days += (day - 1) as i64;
-
-    let secs = days * 86400 + hour as i64 * 3600 + min as i64 * 60 + sec as i64;
-    secs.max(0) as u64
 }
 
-fn is_leap_year(year: u32) -> bool {
-    (year % 4 == 0 && year % 100 != 0) || (year % 400 == 0)
+/// Zoom level selection for tile based endpoints (`?z=17`), zoom 14 by default
+#[derive(Deserialize)]
+struct ZoomParams {
+    #[serde(default = "default_zoom")]
+    z: u32,
 }
 
-fn calculate_distance_from_content(content: &str) -> f64 {
-    let mut points: Vec<(f64, f64)> = Vec::new();
-
-    for line in content.lines() {
-        if let Some(start) = line.find("<trkpt") {
-            let segment = &line[start..];
-            let lat = extract_attr(segment, "lat");
-            let lon = extract_attr(segment, "lon");
-            if let (Some(lat), Some(lon)) = (lat, lon) {
-                points.push((lat, lon));
-            }
-        }
-    }
+fn default_zoom() -> u32 {
+    tiles::TILE_ZOOM
+}
 
-    let mut total_km = 0.0;
-    for i in 1..points.len() {
-        total_km += haversine_km(points[i - 1], points[i]);
+/// Only zoom levels tiles are computed for (`TILE_ZOOMS`); others have no tiles and
+/// large ones overflow the tile math
+fn check_zoom(state: &AppState, zoom: u32) -> Result<(), (axum::http::StatusCode, String)> {
+    if state.tile_options.zooms.contains(&zoom) {
+        return Ok(());
     }
-    (total_km * 100.0).round() / 100.0
+    let zooms: Vec<String> = state
+        .tile_options
+        .zooms
+        .iter()
+        .map(u32::to_string)
+        .collect();
+    Err((
+        axum::http::StatusCode::BAD_REQUEST,
+        format!(
+            "Zoomstufe {} wird nicht berechnet, verfügbar: {}",
+            zoom,
+            zooms.join(", ")
+        ),
+    ))
 }
 
-fn calculate_elevation_gain(content: &str) -> i32 {
-    let mut elevations: Vec<f64> = Vec::new();
-
-    // Extract all elevation values from <ele> tags
-    let mut remaining = content;
-    while let Some(start) = remaining.find("<ele>") {
-        let after_tag = &remaining[start + 5..];
-        if let Some(end) = after_tag.find("</ele>") {
-            let ele_str = &after_tag[..end];
-            if let Ok(ele) = ele_str.trim().parse::<f64>() {
-                elevations.push(ele);
-            }
-        }
-        remaining = &remaining[start + 5..];
-    }
+async fn list_visited_tiles(
+    State(state): State<AppState>,
+    Query(params): Query<ZoomParams>,
+) -> Result<Json<tiles::TilesResponse>, (axum::http::StatusCode, String)> {
+    check_zoom(&state, params.z)?;
+    let conn = state.db.lock().unwrap();
+    Ok(Json(tiles::get_visited_tiles(&conn, params.z)))
+}
 
-    // Sum only positive elevation changes (climbing)
-    let mut total_gain = 0.0;
-    for i in 1..elevations.len() {
-        let diff = elevations[i] - elevations[i - 1];
-        if diff > 0.0 {
-            total_gain += diff;
-        }
-    }
+#[derive(Deserialize)]
+struct TileVisitsParams {
+    #[serde(default = "default_zoom")]
+    z: u32,
+    /// Only tiles whose last visit is longer ago than this many years
+    not_visited_years: Option<u32>,
+}
 
-    total_gain.round() as i32
+async fn list_tile_visits(
+    State(state): State<AppState>,
+    Query(params): Query<TileVisitsParams>,
+) -> Result<Json<Vec<tiles::TileVisitInfo>>, (axum::http::StatusCode, String)> {
+    check_zoom(&state, params.z)?;
+    let conn = state.db.lock().unwrap();
+    Ok(Json(tiles::get_tile_visit_summaries(
+        &conn,
+        params.z,
+        params.not_visited_years,
+    )))
 }
 
-fn extract_attr(s: &str, attr: &str) -> Option<f64> {
-    let pattern = format!("{}=\"", attr);
-    let start = s.find(&pattern)? + pattern.len();
-    let rest = &s[start..];
-    let end = rest.find('"')?;
-    rest[..end].parse().ok()
+async fn get_tile_detail(
+    State(state): State<AppState>,
+    AxumPath((z, x, y)): AxumPath<(u32, u32, u32)>,
+) -> Result<Json<tiles::TileDetail>, (axum::http::StatusCode, String)> {
+    check_zoom(&state, z)?;
+    let conn = state.db.lock().unwrap();
+    Ok(Json(tiles::get_tile_detail(&conn, x, y, z)))
 }
 
-fn haversine_km(p1: (f64, f64), p2: (f64, f64)) -> f64 {
-    let r = 6371.0; // Earth radius in km
-    let d_lat = (p2.0 - p1.0).to_radians();
-    let d_lon = (p2.1 - p1.1).to_radians();
-    let lat1 = p1.0.to_radians();
-    let lat2 = p2.0.to_radians();
+#[derive(Serialize)]
+struct RegionLayerInfo {
+    name: String,
+    admin_level: u8,
+    label_key: String,
+    region_count: usize,
+}
 
-    let a = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lon / 2.0).sin().powi(2);
-    let c = 2.0 * a.sqrt().asin();
-    r * c
+/// Region layers of the registry
+async fn list_region_layers(State(state): State<AppState>) -> Json<Vec<RegionLayerInfo>> {
+    Json(
+        state
+            .regions
+            .layers
+            .iter()
+            .map(|layer| RegionLayerInfo {
+                name: layer.name.clone(),
+                admin_level: layer.admin_level,
+                label_key: layer.label_key.clone(),
+                region_count: layer.regions.len(),
+            })
+            .collect(),
+    )
 }
 
-async fn serve_gpx_file(AxumPath(filename): AxumPath<String>) -> impl IntoResponse {
-    if filename.contains("..") || filename.contains('/') || filename.contains('\\') {
-        return (
-            axum::http::StatusCode::BAD_REQUEST,
-            [(header::CONTENT_TYPE, "text/plain")],
-            "Invalid filename".to_string(),
-        );
-    }
-    let path = PathBuf::from("gpx").join(&filename);
-    match fs::read_to_string(&path) {
-        Ok(content) => (
+/// GeoJSON of a region layer
+async fn serve_region_layer(
+    State(state): State<AppState>,
+    AxumPath(layer): AxumPath<String>,
+) -> impl IntoResponse {
+    match state.regions.layer(&layer) {
+        Some(layer) => (
             axum::http::StatusCode::OK,
-            [(header::CONTENT_TYPE, "application/gpx+xml")],
-            content,
+            [(header::CONTENT_TYPE, "application/geo+json")],
+            layer.geojson.clone(),
         ),
-        Err(_) => (
+        None => (
             axum::http::StatusCode::NOT_FOUND,
             [(header::CONTENT_TYPE, "text/plain")],
-            "File not found".to_string(),
+            format!("Region layer {} not found", layer),
         ),
     }
 }
 
-async fn list_visited_tiles(State(state): State<AppState>) -> Json<tiles::TilesResponse> {
-    let conn = state.db.lock().unwrap();
-    Json(tiles::get_visited_tiles(&conn))
-}
-
 #[derive(Deserialize)]
-struct FetchParams {
-    #[serde(default)]
-    fetch_all: bool,
-    #[serde(default = "default_per_page")]
-    per_page: u32,
-    #[serde(default = "default_page")]
-    page: u32,
+struct RegionCoverageParams {
+    /// Only regions of this layer, e.g. `sachsen_gemeinden`
+    layer: Option<String>,
 }
 
-fn default_per_page() -> u32 {
-    50
+#[derive(Serialize)]
+struct RegionCoverageResponse {
+    zoom: u32,
+    regions: Vec<RegionCoverage>,
 }
-fn default_page() -> u32 {
-    1
+
+/// Visited and total tiles per Gemeinde and Kreis
+async fn get_region_coverage(
+    State(state): State<AppState>,
+    Query(params): Query<RegionCoverageParams>,
+) -> Json<RegionCoverageResponse> {
+    let zoom = state.regions.zoom;
+    let visited: HashSet<(u32, u32)> = {
+        let conn = state.db.lock().unwrap();
+        tiles::get_visited_tiles(&conn, zoom)
+            .tiles
+            .iter()
+            .map(|t| (t.x, t.y))
+            .collect()
+    };
+    let mut regions = state.regions.coverage(&visited);
+    if let Some(layer) = &params.layer {
+        regions.retain(|r| &r.layer == layer);
+    }
+    Json(RegionCoverageResponse { zoom, regions })
 }
 
 #[derive(Serialize)]
 struct FetchResponse {
+    success: bool,
+    message: String,
+    /// Import job whose progress is streamed at /jobs/<id>/events
+    job_id: Option<u64>,
+}
+
+/// Result of an import job, sent as its last event
+#[derive(Serialize)]
+struct ImportResult {
     success: bool,
     message: String,
     imported: u32,
     skipped: u32,
+    failed: u32,
+    cancelled: bool,
+    /// Regions the imported activities entered first
+    new_regions: Vec<RegionVisit>,
 }
 
+/// Events of an import job besides the progress of the sync
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ImportEvent {
+    /// Downloads are done, tiles and regions are being updated
+    Processing,
+    Finished(ImportResult),
+}
+
+/// Start importing new activities from Strava in the background
+///
+/// Answers right away with the job ID; if an import is already running, with that one.
 async fn fetch_activities(
     State(state): State<AppState>,
-    Json(params): Json<FetchParams>,
+    Json(options): Json<SyncOptions>,
 ) -> Json<FetchResponse> {
-    // First, check if we have a token from OAuth in state
-    let state_token = {
-        let token_guard = state.access_token.read().unwrap();
-        token_guard.clone()
-    };
-
-    // Use state token if available, otherwise fall back to environment
-    let mut access_token =
-        state_token.unwrap_or_else(|| std::env::var("STRAVA_ACCESS_TOKEN").unwrap_or_default());
-    let refresh_token = std::env::var("STRAVA_REFRESH_TOKEN").unwrap_or_default();
-    let client_id = std::env::var("STRAVA_CLIENT_ID").unwrap_or_default();
-    let client_secret = std::env::var("STRAVA_CLIENT_SECRET").unwrap_or_default();
-
-    if access_token.is_empty() && refresh_token.is_empty() {
+    // Stored token, refreshed if it is about to expire
+    if let Err(e) = state.tokens.access_token(&state.strava).await {
         return Json(FetchResponse {
             success: false,
-            message: "Nicht authentifiziert. Bitte zuerst 'Bei Strava anmelden' klicken."
-                .to_string(),
-            imported: 0,
-            skipped: 0,
+            message: format!(
+                "Kein gültiger Strava-Token ({}). Bitte zuerst 'Bei Strava anmelden' klicken.",
+                e
+            ),
+            job_id: None,
         });
     }
 
-    // Create HTTP client
-    let client = match strava::create_client() {
-        Ok(c) => c,
-        Err(e) => {
-            return Json(FetchResponse {
+    match start_import(state, options, "manual") {
+        Ok(job_id) => Json(FetchResponse {
+            success: true,
+            message: "Import gestartet".to_string(),
+            job_id: Some(job_id),
+        }),
+        Err(running) => Json(FetchResponse {
+            success: true,
+            message: "Es läuft bereits ein Import".to_string(),
+            job_id: Some(running),
+        }),
+    }
+}
+
+/// Incremental sync started by the scheduler, with the stored tokens
+async fn scheduled_import(state: &AppState) {
+    if let Err(e) = state.tokens.access_token(&state.strava).await {
+        eprintln!("Scheduled sync skipped: {}", e);
+        let now = Utc::now().timestamp();
+        record_sync_run(
+            state,
+            SyncRun {
+                triggered_by: "schedule".to_string(),
+                started_at: now,
+                finished_at: now,
                 success: false,
-                message: format!("HTTP Client Fehler: {}", e),
                 imported: 0,
                 skipped: 0,
-            });
-        }
-    };
+                failed: 0,
+                message: format!("Kein gültiger Strava-Token ({})", e),
+            },
+        );
+        return;
+    }
+    match start_import(state.clone(), SyncOptions::default(), "schedule") {
+        Ok(job_id) => println!("Scheduled sync started as job {}", job_id),
+        Err(running) => println!("Scheduled sync skipped, job {} is still running", running),
+    }
+}
 
-    // Try to fetch activities, refresh token if needed
-    let activities = match strava::get_activities(
-        &client,
-        &access_token,
-        params.per_page,
-        params.page,
+/// Run an import as a background job and record its result
+///
+/// Returns the job ID, or the ID of the import that is already running as error.
+fn start_import(state: AppState, options: SyncOptions, triggered_by: &str) -> Result<u64, u64> {
+    let job = state.jobs.start().map_err(|running| running.id)?;
+    let job_id = job.id;
+    let triggered_by = triggered_by.to_string();
+    tokio::spawn(async move {
+        let started_at = Utc::now().timestamp();
+        // A panicking import must still finish its job, or it would block all later ones
+        let import = tokio::spawn({
+            let (state, job) = (state.clone(), job.clone());
+            async move { run_import(&state, &options, &job).await }
+        });
+        let result = import.await.unwrap_or_else(|e| ImportResult {
+            success: false,
+            message: format!("Import abgestürzt: {}", e),
+            imported: 0,
+            skipped: 0,
+            failed: 0,
+            cancelled: false,
+            new_regions: Vec::new(),
+        });
+        record_sync_run(
+            &state,
+            SyncRun {
+                triggered_by,
+                started_at,
+                finished_at: Utc::now().timestamp(),
+                success: result.success,
+                imported: result.imported,
+                skipped: result.skipped,
+                failed: result.failed,
+                message: result.message.clone(),
+            },
+        );
+        job.finish(&ImportEvent::Finished(result));
+    });
+    Ok(job_id)
+}
+
+fn record_sync_run(state: &AppState, run: SyncRun) {
+    let conn = state.db.lock().unwrap();
+    if let Err(e) = database::insert_sync_run(&conn, &run) {
+        eprintln!("Error recording sync run: {}", e);
+    }
+}
+
+/// Sync activities from Strava, then update tiles and regions for the new ones
+async fn run_import(state: &AppState, options: &SyncOptions, job: &Job) -> ImportResult {
+    // Page through all activities since the last sync
+    let summary = sync::sync_activities(
+        &state.strava,
+        &state.tokens,
+        &state.db,
+        &PathBuf::from(tiles::GPX_DIR),
+        options,
+        &|event| job.send(&event),
+        job.cancel_flag(),
     )
-    .await
-    {
-        Ok(a) => a,
-        Err(e) => {
-            let error_str = e.to_string();
-            // Check if it's a 401 error and we have a refresh token
-            if error_str.contains("401")
-                && !refresh_token.is_empty()
-                && !client_id.is_empty()
-                && !client_secret.is_empty()
-            {
-                println!("Access token expired, attempting refresh...");
-
-                // Try to refresh the token
-                match strava::refresh_token(&client, &client_id, &client_secret, &refresh_token)
-                    .await
-                {
-                    Ok(new_tokens) => {
-                        println!("Token refreshed successfully!");
-                        println!("New access token: {}", new_tokens.access_token);
-                        if let Some(ref rt) = new_tokens.refresh_token {
-                            println!("New refresh token: {}", rt);
-                        }
-                        println!("Please update your .env file with the new tokens.");
-
-                        access_token = new_tokens.access_token;
-
-                        // Retry with new token
-                        match strava::get_activities(
-                            &client,
-                            &access_token,
-                            params.per_page,
-                            params.page,
-                        )
-                        .await
-                        {
-                            Ok(a) => a,
-                            Err(e2) => {
-                                return Json(FetchResponse {
-                                    success: false,
-                                    message: format!(
-                                        "Strava API Fehler nach Token-Refresh: {}",
-                                        e2
-                                    ),
-                                    imported: 0,
-                                    skipped: 0,
-                                });
-                            }
-                        }
-                    }
-                    Err(refresh_err) => {
-                        return Json(FetchResponse {
-                            success: false,
-                            message: format!("Token-Refresh fehlgeschlagen: {}. Bitte erneut via CLI authentifizieren.", refresh_err),
-                            imported: 0,
-                            skipped: 0,
-                        });
-                    }
-                }
-            } else {
-                return Json(FetchResponse {
-                    success: false,
-                    message: format!("Strava API Fehler: {}", e),
-                    imported: 0,
-                    skipped: 0,
-                });
+    .await;
+
+    let mut message = if summary.imported == 0 && summary.failed == 0 {
+        format!(
+            "Keine neuen Aktivitäten, {} bereits importiert",
+            summary.skipped
+        )
+    } else {
+        format!(
+            "{} Aktivitäten importiert, {} übersprungen",
+            summary.imported, summary.skipped
+        )
+    };
+    if summary.failed > 0 {
+        message.push_str(&format!(", {} fehlgeschlagen", summary.failed));
+    }
+    if let Some(error) = &summary.error {
+        message.push_str(&format!(
+            ". Synchronisierung abgebrochen: {}. Beim nächsten Abruf geht es dort weiter",
+            error
+        ));
+    } else if summary.cancelled {
+        message.push_str(". Import abgebrochen, beim nächsten Abruf geht es dort weiter");
+    }
+
+    // Process new GPX files to update tiles, on a blocking thread since it parses every
+    // new file while holding the database
+    let (milestones, new_regions) = if summary.imported > 0 {
+        job.send(&ImportEvent::Processing);
+        let state = state.clone();
+        let imported_ids = summary.imported_ids.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut conn = state.db.lock().unwrap();
+            let new_regions = sync::process_new_tracks(
+                &mut conn,
+                &state.tile_options,
+                &state.regions,
+                &imported_ids,
+            );
+            let mut milestones = square_growth_messages(&conn, &imported_ids);
+            milestones.extend(new_regions_message(&new_regions));
+            (milestones, new_regions)
+        })
+        .await
+        .unwrap_or_else(|e| {
+            eprintln!("Fehler beim Verarbeiten der GPX-Dateien: {}", e);
+            (Vec::new(), Vec::new())
+        })
+    } else {
+        (Vec::new(), Vec::new())
+    };
+
+    ImportResult {
+        success: summary.error.is_none() || summary.imported > 0,
+        message: with_milestones(message, &milestones),
+        imported: summary.imported,
+        skipped: summary.skipped,
+        failed: summary.failed,
+        cancelled: summary.cancelled,
+        new_regions,
+    }
+}
+
+/// The latest syncs with their results, newest first
+async fn list_sync_runs(State(state): State<AppState>) -> Json<Vec<SyncRun>> {
+    let conn = state.db.lock().unwrap();
+    Json(database::get_sync_runs(&conn, 20).unwrap_or_else(|e| {
+        eprintln!("Error reading sync runs: {}", e);
+        Vec::new()
+    }))
+}
+
+#[derive(Serialize)]
+struct CurrentJobResponse {
+    job_id: Option<u64>,
+}
+
+/// The running import, so a reloaded page can follow it again
+async fn get_current_job(State(state): State<AppState>) -> Json<CurrentJobResponse> {
+    Json(CurrentJobResponse {
+        job_id: state.jobs.running().map(|job| job.id),
+    })
+}
+
+/// Progress of a job as server-sent events: the events so far, then new ones until the
+/// job is finished
+async fn job_events(
+    State(state): State<AppState>,
+    AxumPath(id): AxumPath<u64>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, axum::http::StatusCode> {
+    let job = state
+        .jobs
+        .get(id)
+        .ok_or(axum::http::StatusCode::NOT_FOUND)?;
+    let (history, receiver) = job.subscribe();
+    let live = stream::unfold(receiver, |receiver| async move {
+        let mut receiver = receiver?;
+        loop {
+            match receiver.recv().await {
+                Ok(data) => return Some((data, Some(receiver))),
+                // The history has everything, but a subscriber that fell behind only
+                // needs the latest progress
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
             }
         }
+    });
+    let events = stream::iter(history)
+        .chain(live)
+        .map(|data| Ok(Event::default().data(data)));
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+#[derive(Serialize)]
+struct CancelJobResponse {
+    success: bool,
+    message: String,
+}
+
+/// Stop a job after the current step; what it finished so far is kept
+async fn cancel_job(
+    State(state): State<AppState>,
+    AxumPath(id): AxumPath<u64>,
+) -> Json<CancelJobResponse> {
+    let (success, message) = match state.jobs.get(id) {
+        None => (false, "Import nicht gefunden"),
+        Some(job) if job.is_finished() => (false, "Import ist bereits beendet"),
+        Some(job) => {
+            job.cancel();
+            (true, "Import wird abgebrochen")
+        }
     };
+    Json(CancelJobResponse {
+        success,
+        message: message.to_string(),
+    })
+}
 
-    if activities.is_empty() {
-        return Json(FetchResponse {
-            success: true,
-            message: "Keine neuen Aktivitäten gefunden.".to_string(),
+#[derive(Serialize)]
+struct ImportArchiveResponse {
+    success: bool,
+    message: String,
+    imported: u32,
+    skipped: u32,
+    without_track: u32,
+    new_tiles: usize,
+    /// Regions the imported activities entered first
+    new_regions: Vec<RegionVisit>,
+    errors: Vec<String>,
+}
+
+impl ImportArchiveResponse {
+    fn failed(message: String) -> Self {
+        ImportArchiveResponse {
+            success: false,
+            message,
             imported: 0,
             skipped: 0,
-        });
+            without_track: 0,
+            new_tiles: 0,
+            new_regions: Vec::new(),
+            errors: Vec::new(),
+        }
     }
+}
 
-    let out_dir = PathBuf::from("gpx");
+/// Largest account export accepted at /import-archive (4 GB)
+const MAX_ARCHIVE_BYTES: u64 = 4 << 30;
 
-    // Export activities as GPX - we handle database operations separately
-    // to avoid holding non-Send types across await points
-    let mut imported_count: u32 = 0;
-    let mut skipped_count: u32 = 0;
+/// Numbers the temporary files of uploads running at the same time
+static UPLOAD_COUNTER: AtomicU64 = AtomicU64::new(0);
 
-    // First, check which activities are already imported (using a separate connection)
-    let already_imported: std::collections::HashSet<i64> = if !params.fetch_all {
-        match database::init_db() {
-            Ok(conn) => database::get_imported_activity_ids(&conn)
-                .unwrap_or_default()
-                .into_iter()
-                .collect(),
-            Err(_) => std::collections::HashSet::new(),
+/// Import a Strava account export uploaded as the raw ZIP request body
+///
+/// Account exports easily reach gigabytes, so the body is streamed to a temporary file
+/// instead of being held in memory, and the import runs on a blocking thread.
+async fn import_archive(
+    State(state): State<AppState>,
+    body: Body,
+) -> (axum::http::StatusCode, Json<ImportArchiveResponse>) {
+    let path = std::env::temp_dir().join(format!(
+        "rust_strava_upload_{}_{}.zip",
+        std::process::id(),
+        UPLOAD_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    if let Err((status, message)) = save_upload(body, &path).await {
+        let _ = fs::remove_file(&path);
+        return (status, Json(ImportArchiveResponse::failed(message)));
+    }
+
+    let result = tokio::task::spawn_blocking({
+        let path = path.clone();
+        move || {
+            let file = fs::File::open(&path).map_err(|e| e.to_string())?;
+            let mut conn = state.db.lock().unwrap();
+            archive::import_archive(
+                &mut conn,
+                std::io::BufReader::new(file),
+                &PathBuf::from(tiles::GPX_DIR),
+                &state.tile_options,
+                &state.regions,
+            )
+            .map(|summary| {
+                let mut milestones = square_growth_messages(&conn, &summary.track_activity_ids);
+                milestones.extend(new_regions_message(&summary.new_regions));
+                (summary, milestones)
+            })
         }
-    } else {
-        std::collections::HashSet::new()
+    })
+    .await
+    .unwrap_or_else(|e| Err(e.to_string()));
+    let _ = fs::remove_file(&path);
+
+    match result {
+        Ok((summary, milestones)) => (
+            axum::http::StatusCode::OK,
+            Json(ImportArchiveResponse {
+                success: true,
+                message: with_milestones(
+                    format!(
+                        "{} Aktivitäten importiert ({} ohne Track), {} übersprungen, {} neue Kacheln",
+                        summary.imported, summary.without_track, summary.skipped, summary.new_tiles
+                    ),
+                    &milestones,
+                ),
+                imported: summary.imported,
+                skipped: summary.skipped,
+                without_track: summary.without_track,
+                new_tiles: summary.new_tiles,
+                new_regions: summary.new_regions,
+                errors: summary.errors,
+            }),
+        ),
+        Err(e) => (
+            axum::http::StatusCode::OK,
+            Json(ImportArchiveResponse::failed(format!(
+                "Import fehlgeschlagen: {}",
+                e
+            ))),
+        ),
+    }
+}
+
+/// Stream a request body into a file, failing once it exceeds MAX_ARCHIVE_BYTES
+async fn save_upload(body: Body, path: &Path) -> Result<(), (axum::http::StatusCode, String)> {
+    let write_error = |e: std::io::Error| {
+        (
+            axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Upload konnte nicht gespeichert werden: {}", e),
+        )
     };
+    let mut file = tokio::fs::File::create(path).await.map_err(write_error)?;
+    let mut size = 0u64;
+    let mut chunks = body.into_data_stream();
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk.map_err(|e| {
+            (
+                axum::http::StatusCode::BAD_REQUEST,
+                format!("Upload abgebrochen: {}", e),
+            )
+        })?;
+        size += chunk.len() as u64;
+        if size > MAX_ARCHIVE_BYTES {
+            return Err((
+                axum::http::StatusCode::PAYLOAD_TOO_LARGE,
+                format!("Archiv ist größer als {} GB", MAX_ARCHIVE_BYTES >> 30),
+            ));
+        }
+        file.write_all(&chunk).await.map_err(write_error)?;
+    }
+    file.flush().await.map_err(write_error)
+}
 
-    // Filter activities
-    let activities_to_import: Vec<_> = activities
-        .into_iter()
-        .filter(|act| {
-            if already_imported.contains(&act.id) {
-                skipped_count += 1;
-                false
-            } else {
-                true
-            }
+/// Messages for imported activities that grew the Übersquadrat, e.g.
+/// "„Morning Ride“ hat dein Übersquadrat auf 23×23 vergrößert"
+fn square_growth_messages(conn: &Connection, activity_ids: &[i64]) -> Vec<String> {
+    if activity_ids.is_empty() {
+        return Vec::new();
+    }
+    let entries = match timeline::build_timeline(conn, tiles::TILE_ZOOM, TimelineStep::Activity) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("Fehler beim Berechnen der Zeitleiste: {}", e);
+            return Vec::new();
+        }
+    };
+    entries
+        .iter()
+        .filter_map(|entry| {
+            let activity = entry.square_grown_by.as_ref()?;
+            let id = activity.activity_id.as_deref()?.parse::<i64>().ok()?;
+            activity_ids.contains(&id).then(|| {
+                format!(
+                    "„{}“ hat dein Übersquadrat auf {}×{} vergrößert",
+                    activity.activity_title.as_deref().unwrap_or("Aktivität"),
+                    entry.max_square,
+                    entry.max_square
+                )
+            })
         })
-        .collect();
+        .collect()
+}
 
-    if activities_to_import.is_empty() {
-        return Json(FetchResponse {
-            success: true,
-            message: format!(
-                "Keine neuen Aktivitäten. {} bereits importiert.",
-                skipped_count
-            ),
-            imported: 0,
-            skipped: skipped_count,
-        });
+/// Recompute all first region visits, e.g. after first visits were handed to other
+/// activities
+fn update_region_visits(conn: &mut Connection, regions: &Regions) {
+    if let Err(e) = regions::update_region_visits(conn, regions) {
+        eprintln!("Fehler beim Aktualisieren der Regionen: {}", e);
     }
+}
 
-    // Now export each activity
-    let mut imported_ids: Vec<(i64, Option<String>, f64, i32)> = Vec::new();
+/// e.g. "Neue Regionen: Leipzig, Markkleeberg und 3 weitere"
+fn new_regions_message(new_regions: &[RegionVisit]) -> Option<String> {
+    const MAX_NAMES: usize = 5;
+    if new_regions.is_empty() {
+        return None;
+    }
+    let names: Vec<&str> = new_regions
+        .iter()
+        .take(MAX_NAMES)
+        .map(|r| r.region_name.as_str())
+        .collect();
+    let mut message = format!("Neue Regionen: {}", names.join(", "));
+    if new_regions.len() > MAX_NAMES {
+        message.push_str(&format!(" und {} weitere", new_regions.len() - MAX_NAMES));
+    }
+    Some(message)
+}
 
-    for act in &activities_to_import {
-        let id = act.id;
-        let name = act.name.as_deref().unwrap_or("");
-        println!("Exporting GPX for activity {} - {}", id, name);
+fn with_milestones(message: String, milestones: &[String]) -> String {
+    std::iter::once(message)
+        .chain(milestones.iter().cloned())
+        .collect::<Vec<_>>()
+        .join(". ")
+}
 
-        match strava::get_activity_streams(&client, &access_token, id).await {
-            Ok(streams) => {
-                let file_path = out_dir.join(format!("activity_{}.gpx", id));
-                let start_date = act.start_date.as_deref();
-                let gpx = strava::build_gpx_xml(name, start_date, &streams);
+#[derive(Serialize)]
+struct ActivityDetailResponse {
+    activity_id: String,
+    /// None if the activity isn't imported
+    activity: Option<database::ActivityMetadata>,
+    /// Regions this activity entered first
+    new_regions: Vec<RegionVisit>,
+}
 
-                // Calculate distance and elevation from streams
-                let distance_km = strava::calculate_distance_from_streams(&streams);
-                let elevation_gain_m = strava::calculate_elevation_gain_from_streams(&streams);
+async fn get_activity_detail(
+    State(state): State<AppState>,
+    AxumPath(id): AxumPath<String>,
+) -> Json<ActivityDetailResponse> {
+    let conn = state.db.lock().unwrap();
+    let activity = id
+        .parse::<i64>()
+        .ok()
+        .and_then(|activity_id| database::get_activity(&conn, activity_id).ok().flatten());
+    let new_regions = database::get_activity_region_visits(&conn, &id).unwrap_or_default();
+    Json(ActivityDetailResponse {
+        activity_id: id,
+        activity,
+        new_regions,
+    })
+}
 
-                if let Err(e) = std::fs::write(&file_path, &gpx) {
-                    eprintln!("Failed to write GPX file: {}", e);
-                    continue;
-                }
-                println!(
-                    "Saved GPX: {} ({:.2} km, {} hm)",
-                    file_path.display(),
-                    distance_km,
-                    elevation_gain_m
-                );
-                imported_ids.push((id, act.name.clone(), distance_km, elevation_gain_m));
-                imported_count += 1;
-            }
-            Err(e) => {
-                eprintln!("Failed to get streams for activity {}: {}", id, e);
-                continue;
-            }
-        }
-    }
+#[derive(Serialize)]
+struct ActivityChangeResponse {
+    success: bool,
+    message: String,
+    tiles: usize,
+}
 
-    // Mark activities as imported in database (after all awaits are done)
-    if !imported_ids.is_empty() {
-        if let Ok(conn) = database::init_db() {
-            for (id, name, distance_km, elevation_gain_m) in &imported_ids {
-                if let Err(e) = database::mark_activity_imported(
-                    &conn,
-                    *id,
-                    name.as_deref(),
-                    *distance_km,
-                    *elevation_gain_m,
-                ) {
-                    eprintln!("Warning: Failed to mark activity {} as imported: {}", id, e);
-                }
-            }
+/// Delete an activity and hand its first visits to the remaining activities
+async fn delete_activity(
+    State(state): State<AppState>,
+    AxumPath(id): AxumPath<String>,
+) -> Json<ActivityChangeResponse> {
+    // Removes files and rewrites tiles and regions, so on a blocking thread
+    let result = tokio::task::spawn_blocking({
+        let id = id.clone();
+        move || {
+            let mut conn = state.db.lock().unwrap();
+            let result = tiles::delete_activity(&mut conn, &id);
+            update_region_visits(&mut conn, &state.regions);
+            result
         }
-    }
+    })
+    .await
+    .unwrap_or_else(|e| Err(e.to_string()));
 
-    // Process new GPX files to update tiles
-    {
-        let mut conn = state.db.lock().unwrap();
-        if let Err(e) = tiles::process_all_gpx_files(&mut conn) {
-            eprintln!("Fehler beim Verarbeiten der GPX-Dateien: {}", e);
+    Json(match result {
+        Ok(affected) => ActivityChangeResponse {
+            success: true,
+            message: format!(
+                "Aktivität {} gelöscht, {} Tiles neu zugeordnet",
+                id, affected
+            ),
+            tiles: affected,
+        },
+        Err(e) => ActivityChangeResponse {
+            success: false,
+            message: format!("Löschen fehlgeschlagen: {}", e),
+            tiles: 0,
+        },
+    })
+}
+
+/// Recompute the tiles of an activity from its track file
+async fn reprocess_activity(
+    State(state): State<AppState>,
+    AxumPath(id): AxumPath<String>,
+) -> Json<ActivityChangeResponse> {
+    // Parses the track and rewrites tiles and regions, so on a blocking thread
+    let result = tokio::task::spawn_blocking({
+        let id = id.clone();
+        move || {
+            let mut conn = state.db.lock().unwrap();
+            let result = tiles::reprocess_activity(&mut conn, &id, &state.tile_options);
+            update_region_visits(&mut conn, &state.regions);
+            result
         }
-    }
+    })
+    .await
+    .unwrap_or_else(|e| Err(e.to_string()));
 
-    Json(FetchResponse {
-        success: true,
-        message: format!(
-            "{} Aktivitäten importiert, {} übersprungen",
-            imported_count, skipped_count
-        ),
-        imported: imported_count,
-        skipped: skipped_count,
+    Json(match result {
+        Ok(count) => ActivityChangeResponse {
+            success: true,
+            message: format!("Aktivität {} neu verarbeitet: {} Tiles", id, count),
+            tiles: count,
+        },
+        Err(e) => ActivityChangeResponse {
+            success: false,
+            message: format!("Neu verarbeiten fehlgeschlagen: {}", e),
+            tiles: 0,
+        },
     })
 }
 
@@ -666,7 +1063,7 @@ struct AuthStartResponse {
     message: String,
 }
 
-async fn auth_start() -> Json<AuthStartResponse> {
+async fn auth_start(State(state): State<AppState>) -> Json<AuthStartResponse> {
     let client_id = std::env::var("STRAVA_CLIENT_ID").unwrap_or_default();
 
     if client_id.is_empty() {
@@ -677,8 +1074,18 @@ async fn auth_start() -> Json<AuthStartResponse> {
         });
     }
 
-    let redirect_uri = "http://localhost:8080/auth/callback";
-    let auth_url = strava::get_authorize_url(&client_id, redirect_uri);
+    let oauth_state = match state.logins.start() {
+        Ok(s) => s,
+        Err(e) => {
+            return Json(AuthStartResponse {
+                success: false,
+                auth_url: None,
+                message: format!("Anmeldung konnte nicht gestartet werden: {}", e),
+            });
+        }
+    };
+    let redirect_uri = format!("{}/auth/callback", state.public_url);
+    let auth_url = strava::get_authorize_url(&client_id, &redirect_uri, &oauth_state);
 
     Json(AuthStartResponse {
         success: true,
@@ -691,25 +1098,87 @@ async fn auth_start() -> Json<AuthStartResponse> {
 struct AuthCallbackParams {
     code: Option<String>,
     error: Option<String>,
+    state: Option<String>,
+    /// Scopes the user actually granted, comma separated
+    scope: Option<String>,
+}
+
+/// Escape text for use in HTML
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Error page of the OAuth callback; `message` is escaped
+fn auth_error_page(
+    title: &str,
+    heading: &str,
+    message: &str,
+) -> (
+    axum::http::StatusCode,
+    [(header::HeaderName, &'static str); 1],
+    String,
+) {
+    (
+        axum::http::StatusCode::OK,
+        [(header::CONTENT_TYPE, "text/html; charset=utf-8")],
+        format!(
+            r#"<!DOCTYPE html>
+<html><head><title>{}</title></head>
+<body style="font-family: sans-serif; padding: 40px; text-align: center;">
+<h1 style="color: #dc3545;">❌ {}</h1>
+<p>{}</p>
+<p><a href="/">Zurück zur Karte</a></p>
+</body></html>"#,
+            escape_html(title),
+            escape_html(heading),
+            escape_html(message)
+        ),
+    )
 }
 
 async fn auth_callback(
     State(state): State<AppState>,
     Query(params): Query<AuthCallbackParams>,
 ) -> impl IntoResponse {
+    // Only callbacks of a login started here, each once
+    let state_valid = params
+        .state
+        .as_deref()
+        .is_some_and(|s| state.logins.finish(s));
+    if !state_valid {
+        return auth_error_page(
+            "Authentifizierung fehlgeschlagen",
+            "Ungültige Anmeldung",
+            "Die Anmeldung wurde nicht auf dieser Karte gestartet oder ist abgelaufen. Bitte erneut 'Bei Strava anmelden' klicken.",
+        );
+    }
+
     if let Some(error) = params.error {
-        return (
-            axum::http::StatusCode::OK,
-            [(header::CONTENT_TYPE, "text/html; charset=utf-8")],
-            format!(
-                r#"<!DOCTYPE html>
-<html><head><title>Authentifizierung fehlgeschlagen</title></head>
-<body style="font-family: sans-serif; padding: 40px; text-align: center;">
-<h1 style="color: #dc3545;">❌ Fehler</h1>
-<p>{}</p>
-<p><a href="/">Zurück zur Karte</a></p>
-</body></html>"#,
-                error
+        return auth_error_page("Authentifizierung fehlgeschlagen", "Fehler", &error);
+    }
+
+    if !params
+        .scope
+        .as_deref()
+        .is_some_and(strava::has_required_scope)
+    {
+        return auth_error_page(
+            "Authentifizierung fehlgeschlagen",
+            "Fehlende Berechtigung",
+            &format!(
+                "Ohne Zugriff auf alle Aktivitäten ({}) können keine Aktivitäten importiert werden. Bitte erneut anmelden und das Häkchen bei den privaten Aktivitäten gesetzt lassen.",
+                strava::REQUIRED_SCOPE
             ),
         );
     }
@@ -717,18 +1186,7 @@ async fn auth_callback(
     let code = match params.code {
         Some(c) => c,
         None => {
-            return (
-                axum::http::StatusCode::OK,
-                [(header::CONTENT_TYPE, "text/html; charset=utf-8")],
-                r#"<!DOCTYPE html>
-<html><head><title>Fehler</title></head>
-<body style="font-family: sans-serif; padding: 40px; text-align: center;">
-<h1 style="color: #dc3545;">❌ Fehler</h1>
-<p>Kein Autorisierungscode erhalten.</p>
-<p><a href="/">Zurück zur Karte</a></p>
-</body></html>"#
-                    .to_string(),
-            );
+            return auth_error_page("Fehler", "Fehler", "Kein Autorisierungscode erhalten.");
         }
     };
 
@@ -736,107 +1194,61 @@ async fn auth_callback(
     let client_secret = std::env::var("STRAVA_CLIENT_SECRET").unwrap_or_default();
 
     if client_id.is_empty() || client_secret.is_empty() {
-        return (
-            axum::http::StatusCode::OK,
-            [(header::CONTENT_TYPE, "text/html; charset=utf-8")],
-            r#"<!DOCTYPE html>
-<html><head><title>Fehler</title></head>
-<body style="font-family: sans-serif; padding: 40px; text-align: center;">
-<h1 style="color: #dc3545;">❌ Konfigurationsfehler</h1>
-<p>STRAVA_CLIENT_ID oder STRAVA_CLIENT_SECRET nicht gesetzt.</p>
-<p><a href="/">Zurück zur Karte</a></p>
-</body></html>"#
-                .to_string(),
+        return auth_error_page(
+            "Fehler",
+            "Konfigurationsfehler",
+            "STRAVA_CLIENT_ID oder STRAVA_CLIENT_SECRET nicht gesetzt.",
         );
     }
 
     // Exchange code for token
-    let client = match strava::create_client() {
-        Ok(c) => c,
-        Err(e) => {
-            return (
-                axum::http::StatusCode::OK,
-                [(header::CONTENT_TYPE, "text/html; charset=utf-8")],
-                format!(
-                    r#"<!DOCTYPE html>
-<html><head><title>Fehler</title></head>
-<body style="font-family: sans-serif; padding: 40px; text-align: center;">
-<h1 style="color: #dc3545;">❌ Fehler</h1>
-<p>HTTP Client konnte nicht erstellt werden: {}</p>
-<p><a href="/">Zurück zur Karte</a></p>
-</body></html>"#,
-                    e
-                ),
-            );
-        }
-    };
-
-    match strava::exchange_code(&client, &client_id, &client_secret, &code).await {
+    match strava::exchange_code(&state.strava, &client_id, &client_secret, &code).await {
         Ok(token) => {
-            // Store the token in state
-            {
-                let mut token_guard = state.access_token.write().unwrap();
-                *token_guard = Some(token.access_token.clone());
-            }
-
-            println!("OAuth successful! Access token obtained.");
-            if let Some(ref rt) = token.refresh_token {
-                println!("Refresh token: {}", rt);
-                println!(
-                    "Speichere diesen Refresh Token in deiner .env Datei als STRAVA_REFRESH_TOKEN"
-                );
+            // Keep the tokens across restarts
+            if let Err(e) = state.tokens.save(&token) {
+                eprintln!("Error saving Strava tokens: {}", e);
             }
+            println!("OAuth successful! Tokens saved in the database.");
 
             (
                 axum::http::StatusCode::OK,
                 [(header::CONTENT_TYPE, "text/html; charset=utf-8")],
-                format!(
-                    r#"<!DOCTYPE html>
+                r#"<!DOCTYPE html>
 <html><head><title>Authentifizierung erfolgreich</title>
 <script>
-  // Notify parent window if this was opened as popup
-  if (window.opener) {{
-    window.opener.postMessage({{ type: 'strava-auth-success' }}, '*');
+  // Notify parent window if this was opened as popup; only the map on this server
+  // receives the message
+  if (window.opener) {
+    window.opener.postMessage({ type: 'strava-auth-success' }, window.location.origin);
     setTimeout(() => window.close(), 2000);
-  }}
+  }
 </script>
 </head>
 <body style="font-family: sans-serif; padding: 40px; text-align: center;">
 <h1 style="color: #28a745;">✅ Erfolgreich authentifiziert!</h1>
 <p>Du kannst dieses Fenster jetzt schließen und Aktivitäten abrufen.</p>
-<p style="font-size: 12px; color: #666;">Refresh Token (für .env): <code>{}</code></p>
 <p><a href="/">Zurück zur Karte</a></p>
-</body></html>"#,
-                    token.refresh_token.as_deref().unwrap_or("(keiner)")
-                ),
+</body></html>"#
+                    .to_string(),
             )
         }
-        Err(e) => (
-            axum::http::StatusCode::OK,
-            [(header::CONTENT_TYPE, "text/html; charset=utf-8")],
-            format!(
-                r#"<!DOCTYPE html>
-<html><head><title>Fehler</title></head>
-<body style="font-family: sans-serif; padding: 40px; text-align: center;">
-<h1 style="color: #dc3545;">❌ Token-Austausch fehlgeschlagen</h1>
-<p>{}</p>
-<p><a href="/">Zurück zur Karte</a></p>
-</body></html>"#,
-                e
-            ),
-        ),
+        Err(e) => auth_error_page("Fehler", "Token-Austausch fehlgeschlagen", &e.to_string()),
     }
 }
 
+/// Remaining Strava API budget of this server
+async fn get_rate_limit(State(state): State<AppState>) -> Json<strava::RateLimitBudget> {
+    Json(state.strava.budget())
+}
+
 #[derive(Serialize)]
 struct AuthStatusResponse {
     authenticated: bool,
 }
 
 async fn auth_status(State(state): State<AppState>) -> Json<AuthStatusResponse> {
-    let token_guard = state.access_token.read().unwrap();
     Json(AuthStatusResponse {
-        authenticated: token_guard.is_some(),
+        authenticated: state.tokens.is_authenticated(),
     })
 }
 
@@ -845,13 +1257,81 @@ struct StatsResponse {
     total_distance_km: f64,
     total_elevation_m: i64,
     activity_count: usize,
+    zoom: u32,
+    tile_count: usize,
     max_square: u32,
     max_cluster: usize,
+    max_rectangle_width: u32,
+    max_rectangle_height: u32,
+    square_holes: u32,
+    max_square_with_holes: u32,
     eddington: u32,
 }
 
-async fn get_stats(State(state): State<AppState>) -> Json<StatsResponse> {
+/// Zoom level and the number of unvisited tiles allowed in the square with holes
+#[derive(Deserialize)]
+struct MetricsParams {
+    #[serde(default = "default_zoom")]
+    z: u32,
+    #[serde(default = "default_square_holes")]
+    holes: u32,
+}
+
+fn default_square_holes() -> u32 {
+    1
+}
+
+/// Largest fully visited rectangle and largest square with holes
+///
+/// Neither is kept up to date on import, so they are computed from a copy of all tiles
+/// on a blocking thread, outside of the database lock.
+async fn shape_metrics(
+    state: &AppState,
+    params: &MetricsParams,
+) -> Result<(tiles::MaxRectangleResult, tiles::HoledSquareResult), (axum::http::StatusCode, String)>
+{
+    if params.holes > tiles::MAX_SQUARE_HOLES {
+        return Err((
+            axum::http::StatusCode::BAD_REQUEST,
+            format!("Höchstens {} Löcher erlaubt", tiles::MAX_SQUARE_HOLES),
+        ));
+    }
+    let all_coords = visited_coords(state, params.z);
+    let holes = params.holes;
+    tokio::task::spawn_blocking(move || {
+        (
+            tiles::calculate_max_rectangle(&all_coords),
+            tiles::calculate_max_square_with_holes(&all_coords, holes),
+        )
+    })
+    .await
+    .map_err(calculation_failed)
+}
+
+/// Copy of the visited tiles at a zoom level, so they can be used without the lock
+fn visited_coords(state: &AppState, zoom: u32) -> Vec<(u32, u32)> {
     let conn = state.db.lock().unwrap();
+    tiles::get_visited_tiles(&conn, zoom)
+        .tiles
+        .iter()
+        .map(|t| (t.x, t.y))
+        .collect()
+}
+
+fn calculation_failed(e: tokio::task::JoinError) -> (axum::http::StatusCode, String) {
+    (
+        axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Berechnung fehlgeschlagen: {}", e),
+    )
+}
+
+async fn get_stats(
+    State(state): State<AppState>,
+    Query(params): Query<MetricsParams>,
+) -> Result<Json<StatsResponse>, (axum::http::StatusCode, String)> {
+    check_zoom(&state, params.z)?;
+    let (max_rectangle, holed_square) = shape_metrics(&state, &params).await?;
+    let mut conn = state.db.lock().unwrap();
 
     let total_distance = database::get_total_distance(&conn).unwrap_or(0.0);
     let total_elevation = database::get_total_elevation_gain(&conn).unwrap_or(0);
@@ -860,29 +1340,49 @@ async fn get_stats(State(state): State<AppState>) -> Json<StatsResponse> {
         .unwrap_or(0);
     let eddington = database::calculate_eddington_number(&conn).unwrap_or(0);
 
-    // Calculate Yard and Übersquadrat (independently from all tiles)
-    let tiles_response = tiles::get_visited_tiles(&conn);
-    let max_cluster = tiles::calculate_max_cluster(&tiles_response.tiles);
-    let all_coords: Vec<(u32, u32)> = tiles_response.tiles.iter().map(|t| (t.x, t.y)).collect();
-    let max_square = tiles::calculate_max_square_from_coords(&all_coords);
+    let metrics = tiles::get_tile_metrics(&mut conn, params.z);
 
-    Json(StatsResponse {
+    Ok(Json(StatsResponse {
         total_distance_km: (total_distance * 100.0).round() / 100.0,
         total_elevation_m: total_elevation,
         activity_count,
-        max_square: max_square.size,
-        max_cluster: max_cluster.size,
+        zoom: params.z,
+        tile_count: metrics.tile_count,
+        max_square: metrics.max_square.size,
+        max_cluster: metrics.max_cluster.size,
+        max_rectangle_width: max_rectangle.width,
+        max_rectangle_height: max_rectangle.height,
+        square_holes: params.holes,
+        max_square_with_holes: holed_square.size,
         eddington,
-    })
+    }))
 }
 
 #[derive(Serialize)]
 struct SquareClusterResponse {
     max_square: SquareGeometry,
     max_cluster: ClusterGeometry,
+    max_rectangle: RectangleGeometry,
+    max_square_with_holes: HoledSquareGeometry,
     zoom: u32,
 }
 
+#[derive(Serialize)]
+struct RectangleGeometry {
+    width: u32,
+    height: u32,
+    bounds: [[f64; 2]; 2],
+}
+
+#[derive(Serialize)]
+struct HoledSquareGeometry {
+    size: u32,
+    /// Number of unvisited tiles allowed
+    holes: u32,
+    bounds: [[f64; 2]; 2],
+    missing_tiles: Vec<[[f64; 2]; 2]>,
+}
+
 #[derive(Serialize)]
 struct SquareGeometry {
     size: u32,
@@ -895,28 +1395,26 @@ struct ClusterGeometry {
     tiles: Vec<[[f64; 2]; 2]>, // Array of tile bounds
 }
 
-async fn get_square_cluster(State(state): State<AppState>) -> Json<SquareClusterResponse> {
-    let conn = state.db.lock().unwrap();
-    let tiles_response = tiles::get_visited_tiles(&conn);
-
-    // Calculate Yard and Übersquadrat (independently from all tiles)
-    let max_cluster = tiles::calculate_max_cluster(&tiles_response.tiles);
-    let all_coords: Vec<(u32, u32)> = tiles_response.tiles.iter().map(|t| (t.x, t.y)).collect();
-    let max_square = tiles::calculate_max_square_from_coords(&all_coords);
+async fn get_square_cluster(
+    State(state): State<AppState>,
+    Query(params): Query<MetricsParams>,
+) -> Result<Json<SquareClusterResponse>, (axum::http::StatusCode, String)> {
+    check_zoom(&state, params.z)?;
+    let (max_rectangle, holed_square) = shape_metrics(&state, &params).await?;
+    let metrics = {
+        let mut conn = state.db.lock().unwrap();
+        tiles::get_tile_metrics(&mut conn, params.z)
+    };
+    let (max_square, max_cluster) = (metrics.max_square, metrics.max_cluster);
 
     // Convert square to bounds
     let square_bounds = if max_square.size > 0 {
-        let (lat_min, lon_min, _, _) = tiles::tile_to_bounds(
+        square_bounds(
             max_square.top_left_x,
-            max_square.top_left_y + max_square.size - 1,
-            tiles::TILE_ZOOM,
-        );
-        let (_, _, lat_max, lon_max) = tiles::tile_to_bounds(
-            max_square.top_left_x + max_square.size - 1,
             max_square.top_left_y,
-            tiles::TILE_ZOOM,
-        );
-        [[lat_min, lon_min], [lat_max, lon_max]]
+            max_square.size,
+            params.z,
+        )
     } else {
         [[0.0, 0.0], [0.0, 0.0]]
     };
@@ -925,14 +1423,10 @@ async fn get_square_cluster(State(state): State<AppState>) -> Json<SquareCluster
     let cluster_tiles: Vec<[[f64; 2]; 2]> = max_cluster
         .tiles
         .iter()
-        .map(|(x, y)| {
-            let (lat_min, lon_min, lat_max, lon_max) =
-                tiles::tile_to_bounds(*x, *y, tiles::TILE_ZOOM);
-            [[lat_min, lon_min], [lat_max, lon_max]]
-        })
+        .map(|(x, y)| tile_bounds(*x, *y, params.z))
         .collect();
 
-    Json(SquareClusterResponse {
+    Ok(Json(SquareClusterResponse {
         max_square: SquareGeometry {
             size: max_square.size,
             bounds: square_bounds,
@@ -941,6 +1435,241 @@ async fn get_square_cluster(State(state): State<AppState>) -> Json<SquareCluster
             size: max_cluster.size,
             tiles: cluster_tiles,
         },
-        zoom: tiles::TILE_ZOOM,
+        max_rectangle: RectangleGeometry {
+            width: max_rectangle.width,
+            height: max_rectangle.height,
+            bounds: rect_bounds(
+                max_rectangle.top_left_x,
+                max_rectangle.top_left_y,
+                max_rectangle.width,
+                max_rectangle.height,
+                params.z,
+            ),
+        },
+        max_square_with_holes: HoledSquareGeometry {
+            size: holed_square.size,
+            holes: params.holes,
+            bounds: rect_bounds(
+                holed_square.top_left_x,
+                holed_square.top_left_y,
+                holed_square.size,
+                holed_square.size,
+                params.z,
+            ),
+            missing_tiles: holed_square
+                .missing_tiles
+                .iter()
+                .map(|(x, y)| tile_bounds(*x, *y, params.z))
+                .collect(),
+        },
+        zoom: params.z,
+    }))
+}
+
+#[derive(Deserialize)]
+struct ClustersParams {
+    #[serde(default = "default_zoom")]
+    z: u32,
+    /// Only the largest clusters and islands
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct ClustersResponse {
+    zoom: u32,
+    clusters: Vec<ClusterSummaryGeometry>,
+    islands: Vec<ClusterSummaryGeometry>,
+}
+
+#[derive(Serialize)]
+struct ClusterSummaryGeometry {
+    size: usize,
+    bounds: [[f64; 2]; 2],
+    tiles: Vec<[[f64; 2]; 2]>,
+}
+
+/// Every cluster of surrounded tiles (the largest is the Yard) and every island of
+/// visited tiles outside of them, largest first
+async fn get_clusters(
+    State(state): State<AppState>,
+    Query(params): Query<ClustersParams>,
+) -> Result<Json<ClustersResponse>, (axum::http::StatusCode, String)> {
+    check_zoom(&state, params.z)?;
+    let visited = {
+        let conn = state.db.lock().unwrap();
+        tiles::get_visited_tiles(&conn, params.z).tiles
+    };
+    let overview = tokio::task::spawn_blocking(move || tiles::calculate_clusters(&visited))
+        .await
+        .map_err(calculation_failed)?;
+
+    let to_geometry = |clusters: Vec<tiles::TileCluster>| {
+        clusters
+            .into_iter()
+            .take(params.limit.unwrap_or(usize::MAX))
+            .map(|c| ClusterSummaryGeometry {
+                size: c.size,
+                bounds: rect_bounds(
+                    c.min_x,
+                    c.min_y,
+                    c.max_x - c.min_x + 1,
+                    c.max_y - c.min_y + 1,
+                    params.z,
+                ),
+                tiles: c
+                    .tiles
+                    .iter()
+                    .map(|(x, y)| tile_bounds(*x, *y, params.z))
+                    .collect(),
+            })
+            .collect()
+    };
+
+    Ok(Json(ClustersResponse {
+        zoom: params.z,
+        clusters: to_geometry(overview.clusters),
+        islands: to_geometry(overview.islands),
+    }))
+}
+
+/// Bounds of a square of `size` tiles starting at the top-left tile
+fn square_bounds(top_left_x: u32, top_left_y: u32, size: u32, zoom: u32) -> [[f64; 2]; 2] {
+    rect_bounds(top_left_x, top_left_y, size, size, zoom)
+}
+
+/// Bounds of a rectangle of tiles starting at the top-left tile ([0, 0] if empty)
+fn rect_bounds(
+    top_left_x: u32,
+    top_left_y: u32,
+    width: u32,
+    height: u32,
+    zoom: u32,
+) -> [[f64; 2]; 2] {
+    if width == 0 || height == 0 {
+        return [[0.0, 0.0], [0.0, 0.0]];
+    }
+    let (lat_min, lon_min, _, _) = tiles::tile_to_bounds(top_left_x, top_left_y + height - 1, zoom);
+    let (_, _, lat_max, lon_max) = tiles::tile_to_bounds(top_left_x + width - 1, top_left_y, zoom);
+    [[lat_min, lon_min], [lat_max, lon_max]]
+}
+
+fn tile_bounds(x: u32, y: u32, zoom: u32) -> [[f64; 2]; 2] {
+    let (lat_min, lon_min, lat_max, lon_max) = tiles::tile_to_bounds(x, y, zoom);
+    [[lat_min, lon_min], [lat_max, lon_max]]
+}
+
+#[derive(Deserialize)]
+struct SuggestionParams {
+    #[serde(default = "default_zoom")]
+    z: u32,
+    #[serde(default = "default_suggestion_limit")]
+    limit: usize,
+}
+
+fn default_suggestion_limit() -> usize {
+    10
+}
+
+/// Most suggestions of each kind per request
+const MAX_SUGGESTIONS: usize = 50;
+
+#[derive(Serialize)]
+struct SuggestionsResponse {
+    zoom: u32,
+    max_square: u32,
+    max_cluster: usize,
+    square: Vec<SquareSuggestionGeometry>,
+    yard: Vec<YardSuggestionGeometry>,
+}
+
+#[derive(Serialize)]
+struct SquareSuggestionGeometry {
+    size: u32,
+    missing_count: usize,
+    bounds: [[f64; 2]; 2],
+    missing_tiles: Vec<[[f64; 2]; 2]>,
+}
+
+#[derive(Serialize)]
+struct YardSuggestionGeometry {
+    new_yard_tiles: usize,
+    yard_size_after: usize,
+    bounds: [[f64; 2]; 2],
+}
+
+/// Unvisited tiles worth riding next: square positions one larger than the Übersquadrat
+/// with the fewest missing tiles, and tiles that grow the Yard the most
+async fn get_suggestions(
+    State(state): State<AppState>,
+    Query(params): Query<SuggestionParams>,
+) -> Result<Json<SuggestionsResponse>, (axum::http::StatusCode, String)> {
+    check_zoom(&state, params.z)?;
+    let limit = params.limit.min(MAX_SUGGESTIONS);
+    let metrics = {
+        let mut conn = state.db.lock().unwrap();
+        tiles::get_tile_metrics(&mut conn, params.z)
+    };
+    let all_coords = visited_coords(&state, params.z);
+    let (square, yard) = tokio::task::spawn_blocking(move || {
+        (
+            tiles::suggest_square_growth(&all_coords, limit),
+            tiles::suggest_yard_growth(&all_coords, limit),
+        )
     })
+    .await
+    .map_err(calculation_failed)?;
+
+    let square = square
+        .into_iter()
+        .map(|s| SquareSuggestionGeometry {
+            size: s.size,
+            missing_count: s.missing_tiles.len(),
+            bounds: square_bounds(s.top_left_x, s.top_left_y, s.size, params.z),
+            missing_tiles: s
+                .missing_tiles
+                .iter()
+                .map(|(x, y)| tile_bounds(*x, *y, params.z))
+                .collect(),
+        })
+        .collect();
+
+    let yard = yard
+        .into_iter()
+        .map(|s| YardSuggestionGeometry {
+            new_yard_tiles: s.new_yard_tiles,
+            yard_size_after: s.yard_size_after,
+            bounds: tile_bounds(s.x, s.y, params.z),
+        })
+        .collect();
+
+    Ok(Json(SuggestionsResponse {
+        zoom: params.z,
+        max_square: metrics.max_square.size,
+        max_cluster: metrics.max_cluster.size,
+        square,
+        yard,
+    }))
+}
+
+#[derive(Deserialize)]
+struct TimelineParams {
+    #[serde(default = "default_zoom")]
+    z: u32,
+    #[serde(default)]
+    step: TimelineStep,
+}
+
+/// Tile count, Übersquadrat and Yard after each activity or day, in first-visit order
+async fn get_timeline(
+    State(state): State<AppState>,
+    Query(params): Query<TimelineParams>,
+) -> Result<Json<Vec<TimelineEntry>>, (axum::http::StatusCode, String)> {
+    check_zoom(&state, params.z)?;
+    let conn = state.db.lock().unwrap();
+    Ok(Json(
+        timeline::build_timeline(&conn, params.z, params.step).unwrap_or_else(|e| {
+            eprintln!("Fehler beim Berechnen der Zeitleiste: {}", e);
+            Vec::new()
+        }),
+    ))
 }
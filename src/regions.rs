@@ -0,0 +1,660 @@
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::database::{self, RegionVisit};
+use crate::tiles;
+
+/// Default location of the region registry
+pub const DEFAULT_REGIONS_CONFIG: &str = "static/regions.json";
+
+/// Layer of states derived from the Kreise if the registry has no state layer
+pub const STATES_LAYER: &str = "laender";
+
+/// A region layer in the registry: a GeoJSON file (or part of it) with one region per
+/// feature
+#[derive(Debug, Clone, Deserialize)]
+pub struct LayerConfig {
+    /// Used in URLs, e.g. `/regions/sachsen_gemeinden`
+    pub name: String,
+    /// GeoJSON file, relative to the registry file
+    pub file: PathBuf,
+    /// OpenStreetMap admin level, e.g. 6 for Kreise and 8 for Gemeinden
+    pub admin_level: u8,
+    /// Property with the region name
+    pub label_key: String,
+    /// Property with the name of the enclosing region, e.g. the Kreis of a Gemeinde
+    #[serde(default)]
+    pub parent_key: Option<String>,
+    /// State all regions of the layer lie in, e.g. "Sachsen" for its Kreise
+    #[serde(default)]
+    pub state: Option<String>,
+    /// Property with the state of a region, for layers covering several states
+    #[serde(default)]
+    pub state_key: Option<String>,
+    /// Only features whose property has one of the listed values
+    #[serde(default)]
+    pub filter: Option<LayerFilter>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LayerFilter {
+    pub property: String,
+    pub values: Vec<String>,
+}
+
+/// Polygon as rings of (lon, lat); the first ring is the outline, the others are holes
+type Polygon = Vec<Vec<(f64, f64)>>;
+
+/// One region with the tiles whose center lies inside it (see `tiles_in_polygons`)
+pub struct Region {
+    /// Position of the feature in the layer's GeoJSON
+    pub index: usize,
+    pub name: String,
+    pub parent: Option<String>,
+    pub state: Option<String>,
+    pub tiles: Vec<(u32, u32)>,
+}
+
+pub struct RegionLayer {
+    pub name: String,
+    pub admin_level: u8,
+    pub label_key: String,
+    /// The layer's features as a GeoJSON FeatureCollection, ready to serve
+    pub geojson: String,
+    pub regions: Vec<Region>,
+}
+
+/// All region layers of the registry, with tiles precomputed at one zoom
+pub struct Regions {
+    pub zoom: u32,
+    pub layers: Vec<RegionLayer>,
+    /// Regions containing each tile, as positions in `layers` and their `regions`
+    tile_regions: HashMap<(u32, u32), Vec<(usize, usize)>>,
+}
+
+/// Visited and total tiles of a region
+#[derive(Debug, Serialize)]
+pub struct RegionCoverage {
+    pub layer: String,
+    pub admin_level: u8,
+    pub index: usize,
+    pub name: String,
+    pub parent: Option<String>,
+    pub visited: usize,
+    pub total: usize,
+    pub percent: f64,
+}
+
+impl Regions {
+    /// Load the layers listed in the registry
+    ///
+    /// `config` is either a JSON file with one layer or a list of layers, or a directory
+    /// whose `*.json` files are read that way. Layers whose GeoJSON file is missing are
+    /// skipped. Without a state layer (admin level 4), states are put together from the
+    /// Kreise whose state is known.
+    pub fn load(config: &Path, zoom: u32) -> Regions {
+        let mut layers = Vec::new();
+        for (layer_config, base_dir) in read_registry(config) {
+            let path = base_dir.join(&layer_config.file);
+            if !path.exists() {
+                continue;
+            }
+            match load_layer(&path, &layer_config, zoom) {
+                Ok(layer) => layers.push(layer),
+                Err(e) => eprintln!("Error loading region layer {}: {}", layer_config.name, e),
+            }
+        }
+        if !layers.iter().any(|l| l.admin_level == 4) {
+            if let Some(states) = derive_states(&layers) {
+                layers.push(states);
+            }
+        }
+
+        let mut tile_regions: HashMap<(u32, u32), Vec<(usize, usize)>> = HashMap::new();
+        for (layer_pos, layer) in layers.iter().enumerate() {
+            for (region_pos, region) in layer.regions.iter().enumerate() {
+                for &tile in &region.tiles {
+                    tile_regions
+                        .entry(tile)
+                        .or_default()
+                        .push((layer_pos, region_pos));
+                }
+            }
+        }
+        Regions {
+            zoom,
+            layers,
+            tile_regions,
+        }
+    }
+
+    /// Registry from `REGIONS_CONFIG`, `static/regions.json` by default
+    pub fn from_env(zoom: u32) -> Regions {
+        let config =
+            std::env::var("REGIONS_CONFIG").unwrap_or_else(|_| DEFAULT_REGIONS_CONFIG.to_string());
+        Regions::load(Path::new(&config), zoom)
+    }
+
+    pub fn layer(&self, name: &str) -> Option<&RegionLayer> {
+        self.layers.iter().find(|l| l.name == name)
+    }
+
+    /// Visited and total tiles of every region
+    pub fn coverage(&self, visited: &HashSet<(u32, u32)>) -> Vec<RegionCoverage> {
+        self.layers
+            .iter()
+            .flat_map(|layer| {
+                layer.regions.iter().map(move |region| {
+                    let visited_count = region.tiles.iter().filter(|t| visited.contains(t)).count();
+                    let total = region.tiles.len();
+                    RegionCoverage {
+                        layer: layer.name.clone(),
+                        admin_level: layer.admin_level,
+                        index: region.index,
+                        name: region.name.clone(),
+                        parent: region.parent.clone(),
+                        visited: visited_count,
+                        total,
+                        percent: if total > 0 {
+                            (visited_count as f64 / total as f64 * 1000.0).round() / 10.0
+                        } else {
+                            0.0
+                        },
+                    }
+                })
+            })
+            .collect()
+    }
+}
+
+/// Recompute which activity first entered each region, from the first visits of its tiles
+///
+/// Regions are recomputed as a whole, so deleted or reprocessed activities and changes
+/// to the registry are picked up as well.
+pub fn update_region_visits(conn: &mut Connection, regions: &Regions) -> Result<(), String> {
+    let first_visits: HashMap<(u32, u32), database::TileRecord> =
+        database::get_all_tiles(conn, regions.zoom)
+            .map_err(|e| e.to_string())?
+            .into_iter()
+            .map(|t| ((t.x, t.y), t))
+            .collect();
+
+    let visits: Vec<RegionVisit> = regions
+        .layers
+        .iter()
+        .flat_map(|layer| {
+            let first_visits = &first_visits;
+            layer.regions.iter().filter_map(move |region| {
+                let first = region
+                    .tiles
+                    .iter()
+                    .filter_map(|t| first_visits.get(t))
+                    .min_by_key(|t| t.first_visited_at)?;
+                Some(region_visit(layer, region, first))
+            })
+        })
+        .collect();
+
+    database::replace_region_visits(conn, &visits).map_err(|e| e.to_string())
+}
+
+/// Record the regions entered by the tiles the given activities visited first
+///
+/// Only regions containing one of these tiles are written, and only where the tile was
+/// visited before the recorded first visit. Returns the regions the activities entered
+/// first.
+pub fn record_region_visits(
+    conn: &mut Connection,
+    regions: &Regions,
+    activity_ids: &[i64],
+) -> Result<Vec<RegionVisit>, String> {
+    let mut earliest: HashMap<(usize, usize), database::TileRecord> = HashMap::new();
+    for id in activity_ids {
+        let tiles = database::get_activity_tiles(conn, regions.zoom, &id.to_string())
+            .map_err(|e| e.to_string())?;
+        for tile in tiles {
+            for &key in regions
+                .tile_regions
+                .get(&(tile.x, tile.y))
+                .into_iter()
+                .flatten()
+            {
+                match earliest.get(&key) {
+                    Some(first) if first.first_visited_at <= tile.first_visited_at => {}
+                    _ => {
+                        earliest.insert(key, tile.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    let visits: Vec<RegionVisit> = earliest
+        .iter()
+        .map(|(&(layer_pos, region_pos), first)| {
+            let layer = &regions.layers[layer_pos];
+            region_visit(layer, &layer.regions[region_pos], first)
+        })
+        .collect();
+    database::record_region_visits(conn, &visits).map_err(|e| e.to_string())?;
+
+    let mut new_regions = Vec::new();
+    for id in activity_ids {
+        new_regions.extend(
+            database::get_activity_region_visits(conn, &id.to_string())
+                .map_err(|e| e.to_string())?,
+        );
+    }
+    Ok(new_regions)
+}
+
+fn region_visit(layer: &RegionLayer, region: &Region, first: &database::TileRecord) -> RegionVisit {
+    RegionVisit {
+        layer: layer.name.clone(),
+        region_index: region.index,
+        region_name: region.name.clone(),
+        admin_level: layer.admin_level,
+        activity_id: first.activity_id.clone(),
+        gpx_filename: first.gpx_filename.clone(),
+        first_visited_at: first.first_visited_at,
+    }
+}
+
+/// States put together from the Kreise (admin level 6) whose state is known
+///
+/// Each state is one feature with the outlines of its Kreise as a MultiPolygon.
+fn derive_states(layers: &[RegionLayer]) -> Option<RegionLayer> {
+    // BTreeMap keeps the states in a stable order, so their indices survive restarts
+    let mut outlines: BTreeMap<String, Vec<Value>> = BTreeMap::new();
+    let mut state_tiles: HashMap<String, HashSet<(u32, u32)>> = HashMap::new();
+    for layer in layers.iter().filter(|l| l.admin_level == 6) {
+        if !layer.regions.iter().any(|r| r.state.is_some()) {
+            continue;
+        }
+        let Ok(geojson) = serde_json::from_str::<Value>(&layer.geojson) else {
+            continue;
+        };
+        for region in &layer.regions {
+            let Some(state) = &region.state else {
+                continue;
+            };
+            let polygons = outlines.entry(state.clone()).or_default();
+            let geometry = &geojson["features"][region.index]["geometry"];
+            match (geometry["type"].as_str(), &geometry["coordinates"]) {
+                (Some("Polygon"), polygon) => polygons.push(polygon.clone()),
+                (Some("MultiPolygon"), Value::Array(list)) => polygons.extend(list.clone()),
+                _ => {}
+            }
+            state_tiles
+                .entry(state.clone())
+                .or_default()
+                .extend(&region.tiles);
+        }
+    }
+    if outlines.is_empty() {
+        return None;
+    }
+
+    let mut features = Vec::new();
+    let mut regions = Vec::new();
+    for (index, (name, polygons)) in outlines.into_iter().enumerate() {
+        features.push(serde_json::json!({
+            "type": "Feature",
+            "properties": { "name": name },
+            "geometry": { "type": "MultiPolygon", "coordinates": polygons },
+        }));
+        let mut tiles: Vec<(u32, u32)> = state_tiles
+            .remove(&name)
+            .unwrap_or_default()
+            .into_iter()
+            .collect();
+        tiles.sort_unstable();
+        regions.push(Region {
+            index,
+            name,
+            parent: None,
+            state: None,
+            tiles,
+        });
+    }
+    Some(RegionLayer {
+        name: STATES_LAYER.to_string(),
+        admin_level: 4,
+        label_key: "name".to_string(),
+        geojson: serde_json::json!({ "type": "FeatureCollection", "features": features })
+            .to_string(),
+        regions,
+    })
+}
+
+/// Layer configs with the directory their file paths are relative to
+fn read_registry(config: &Path) -> Vec<(LayerConfig, PathBuf)> {
+    let files: Vec<PathBuf> = if config.is_dir() {
+        let mut files: Vec<PathBuf> = fs::read_dir(config)
+            .map(|entries| {
+                entries
+                    .filter_map(|e| e.ok().map(|e| e.path()))
+                    .filter(|p| p.extension().is_some_and(|ext| ext == "json"))
+                    .collect()
+            })
+            .unwrap_or_default();
+        files.sort();
+        files
+    } else if config.exists() {
+        vec![config.to_path_buf()]
+    } else {
+        eprintln!("Region registry {} not found", config.display());
+        Vec::new()
+    };
+
+    let mut layers = Vec::new();
+    for file in files {
+        let parsed = fs::read_to_string(&file)
+            .map_err(|e| e.to_string())
+            .and_then(|content| serde_json::from_str::<Value>(&content).map_err(|e| e.to_string()))
+            .and_then(|value| {
+                let list = match value {
+                    Value::Array(list) => list,
+                    single => vec![single],
+                };
+                list.into_iter()
+                    .map(serde_json::from_value::<LayerConfig>)
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| e.to_string())
+            });
+        match parsed {
+            Ok(configs) => {
+                let base_dir = file.parent().unwrap_or(Path::new(".")).to_path_buf();
+                layers.extend(configs.into_iter().map(|c| (c, base_dir.clone())));
+            }
+            Err(e) => eprintln!("Error reading region registry {}: {}", file.display(), e),
+        }
+    }
+    layers
+}
+
+fn load_layer(path: &Path, config: &LayerConfig, zoom: u32) -> Result<RegionLayer, String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut geojson: Value =
+        serde_json::from_str(&content).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let features = geojson["features"]
+        .as_array_mut()
+        .ok_or_else(|| format!("{}: no features", path.display()))?;
+
+    let property = |feature: &Value, key: &str| -> Option<String> {
+        match &feature["properties"][key] {
+            Value::String(s) => Some(s.clone()),
+            Value::Null => None,
+            other => Some(other.to_string()),
+        }
+    };
+
+    if let Some(filter) = &config.filter {
+        features.retain(|feature| {
+            property(feature, &filter.property).is_some_and(|v| filter.values.contains(&v))
+        });
+    }
+
+    let regions = features
+        .iter()
+        .enumerate()
+        .map(|(index, feature)| Region {
+            index,
+            name: property(feature, &config.label_key).unwrap_or_else(|| "Unbekannt".to_string()),
+            parent: config
+                .parent_key
+                .as_deref()
+                .and_then(|key| property(feature, key)),
+            state: config.state.clone().or_else(|| {
+                config
+                    .state_key
+                    .as_deref()
+                    .and_then(|key| property(feature, key))
+            }),
+            tiles: tiles_in_polygons(&parse_geometry(&feature["geometry"]), zoom),
+        })
+        .collect();
+
+    Ok(RegionLayer {
+        name: config.name.clone(),
+        admin_level: config.admin_level,
+        label_key: config.label_key.clone(),
+        geojson: geojson.to_string(),
+        regions,
+    })
+}
+
+/// Polygons of a Polygon or MultiPolygon geometry; other types have none
+fn parse_geometry(geometry: &Value) -> Vec<Polygon> {
+    let parse_polygon = |polygon: &Value| -> Polygon {
+        polygon
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|ring| {
+                ring.as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|p| Some((p[0].as_f64()?, p[1].as_f64()?)))
+                    .collect()
+            })
+            .collect()
+    };
+    let coordinates = &geometry["coordinates"];
+    match geometry["type"].as_str() {
+        Some("Polygon") => vec![parse_polygon(coordinates)],
+        Some("MultiPolygon") => coordinates
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(parse_polygon)
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Tiles whose center lies inside one of the polygons
+///
+/// Regions too small or narrow to contain a tile center get the tiles their outlines
+/// pass through instead, so they can still be visited.
+fn tiles_in_polygons(polygons: &[Polygon], zoom: u32) -> Vec<(u32, u32)> {
+    let points = || polygons.iter().flatten().flatten();
+    let min_lon = points().map(|p| p.0).fold(f64::INFINITY, f64::min);
+    let max_lon = points().map(|p| p.0).fold(f64::NEG_INFINITY, f64::max);
+    let min_lat = points().map(|p| p.1).fold(f64::INFINITY, f64::min);
+    let max_lat = points().map(|p| p.1).fold(f64::NEG_INFINITY, f64::max);
+    if min_lon > max_lon {
+        return Vec::new();
+    }
+
+    let (min_x, min_y) = tiles::lat_lon_to_tile(max_lat, min_lon, zoom);
+    let (max_x, max_y) = tiles::lat_lon_to_tile(min_lat, max_lon, zoom);
+    let mut result = Vec::new();
+    for x in min_x..=max_x {
+        for y in min_y..=max_y {
+            let (lat, lon) = tiles::tile_center(x, y, zoom);
+            if polygons.iter().any(|polygon| in_polygon(polygon, lon, lat)) {
+                result.push((x, y));
+            }
+        }
+    }
+    if result.is_empty() {
+        let outline_tiles: BTreeSet<(u32, u32)> = polygons
+            .iter()
+            .filter_map(|polygon| polygon.first())
+            .flat_map(|outline| outline.windows(2))
+            .flat_map(|edge| {
+                tiles::tiles_between((edge[0].1, edge[0].0), (edge[1].1, edge[1].0), zoom)
+            })
+            .collect();
+        result.extend(outline_tiles);
+    }
+    result
+}
+
+/// Inside the outline and outside all holes
+fn in_polygon(polygon: &Polygon, lon: f64, lat: f64) -> bool {
+    let mut rings = polygon.iter();
+    rings
+        .next()
+        .is_some_and(|outline| in_ring(outline, lon, lat))
+        && !rings.any(|hole| in_ring(hole, lon, lat))
+}
+
+/// Ray casting point-in-polygon test
+fn in_ring(ring: &[(f64, f64)], lon: f64, lat: f64) -> bool {
+    let mut inside = false;
+    let mut j = ring.len().wrapping_sub(1);
+    for i in 0..ring.len() {
+        let (xi, yi) = ring[i];
+        let (xj, yj) = ring[j];
+        if (yi > lat) != (yj > lat) && lon < (xj - xi) * (lat - yi) / (yj - yi) + xi {
+            inside = !inside;
+        }
+        j = i;
+    }
+    inside
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ZOOM: u32 = 14;
+
+    /// GeoJSON polygon covering the tiles from `(x0, y0)` to `(x1, y1)`
+    fn tile_box((x0, y0): (u32, u32), (x1, y1): (u32, u32)) -> Value {
+        let (_, lon_min, lat_max, _) = tiles::tile_to_bounds(x0, y0, ZOOM);
+        let (lat_min, _, _, lon_max) = tiles::tile_to_bounds(x1, y1, ZOOM);
+        serde_json::json!({
+            "type": "Polygon",
+            "coordinates": [[
+                [lon_min, lat_min], [lon_max, lat_min], [lon_max, lat_max],
+                [lon_min, lat_max], [lon_min, lat_min]
+            ]],
+        })
+    }
+
+    /// Two Kreise of 2×2 tiles next to each other, both in Sachsen
+    fn load_kreise(dir: &Path) -> Regions {
+        fs::create_dir_all(dir).unwrap();
+        let features: Vec<Value> = [("Bautzen", 100), ("Görlitz", 102)]
+            .iter()
+            .map(|&(name, x)| {
+                serde_json::json!({
+                    "type": "Feature",
+                    "properties": { "KREIS": name },
+                    "geometry": tile_box((x, 100), (x + 1, 101)),
+                })
+            })
+            .collect();
+        let geojson = serde_json::json!({ "type": "FeatureCollection", "features": features });
+        fs::write(dir.join("kreise.geojson"), geojson.to_string()).unwrap();
+        fs::write(
+            dir.join("regions.json"),
+            r#"{ "name": "kreise", "file": "kreise.geojson", "admin_level": 6,
+                 "label_key": "KREIS", "state": "Sachsen" }"#,
+        )
+        .unwrap();
+        Regions::load(&dir.join("regions.json"), ZOOM)
+    }
+
+    fn insert_tiles(
+        conn: &mut Connection,
+        activity_id: i64,
+        visited_at: i64,
+        tiles: &[(u32, u32)],
+    ) {
+        let data: Vec<database::TileData> = tiles
+            .iter()
+            .map(|&(x, y)| database::TileData {
+                x,
+                y,
+                z: ZOOM,
+                visited_at,
+                activity_id: activity_id.to_string(),
+                activity_title: format!("Ride {}", activity_id),
+                gpx_filename: format!("activity_{}.gpx", activity_id),
+            })
+            .collect();
+        database::insert_tiles_batch(conn, &data).unwrap();
+    }
+
+    fn entered(conn: &Connection, activity_id: i64) -> Vec<String> {
+        database::get_activity_region_visits(conn, &activity_id.to_string())
+            .unwrap()
+            .into_iter()
+            .map(|v| format!("{}/{}", v.layer, v.region_name))
+            .collect()
+    }
+
+    #[test]
+    fn recorded_visits_match_recomputed_ones() {
+        let dir = std::env::temp_dir().join(format!("rust_strava_regions_{}", std::process::id()));
+        let regions = load_kreise(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        let states = regions.layer(STATES_LAYER).unwrap();
+        assert_eq!(states.admin_level, 4);
+        assert_eq!(states.regions.len(), 1);
+        assert_eq!(states.regions[0].tiles.len(), 8);
+
+        let mut conn = database::setup_db(Connection::open_in_memory().unwrap()).unwrap();
+        insert_tiles(&mut conn, 1, 200, &[(100, 100), (101, 100)]);
+        let new_regions = record_region_visits(&mut conn, &regions, &[1]).unwrap();
+        assert_eq!(new_regions.len(), 2);
+        assert_eq!(entered(&conn, 1), vec!["laender/Sachsen", "kreise/Bautzen"]);
+
+        // An older activity takes over Bautzen and the state and enters Görlitz
+        insert_tiles(&mut conn, 2, 100, &[(101, 101), (102, 100)]);
+        record_region_visits(&mut conn, &regions, &[2]).unwrap();
+        // A later one in Görlitz changes nothing
+        insert_tiles(&mut conn, 3, 300, &[(103, 101)]);
+        assert!(record_region_visits(&mut conn, &regions, &[3])
+            .unwrap()
+            .is_empty());
+        let recorded = (entered(&conn, 1), entered(&conn, 2));
+        assert!(recorded.0.is_empty());
+        assert_eq!(
+            recorded.1,
+            vec!["laender/Sachsen", "kreise/Bautzen", "kreise/Görlitz"]
+        );
+
+        update_region_visits(&mut conn, &regions).unwrap();
+        assert_eq!((entered(&conn, 1), entered(&conn, 2)), recorded);
+    }
+
+    #[test]
+    fn regions_without_a_tile_center_get_their_outline_tiles() {
+        let (lat_min, lon_min, lat_max, lon_max) = tiles::tile_to_bounds(100, 100, ZOOM);
+        let (lat_step, lon_step) = ((lat_max - lat_min) / 10.0, (lon_max - lon_min) / 10.0);
+        // Corner of one tile, away from its center
+        let small = vec![vec![
+            (lon_min + lon_step, lat_min + lat_step),
+            (lon_min + 2.0 * lon_step, lat_min + lat_step),
+            (lon_min + 2.0 * lon_step, lat_min + 2.0 * lat_step),
+            (lon_min + lon_step, lat_min + lat_step),
+        ]];
+        assert_eq!(tiles_in_polygons(&[small], ZOOM), vec![(100, 100)]);
+
+        // A thin strip along the bottom of three tiles
+        let strip = vec![vec![
+            (lon_min + lon_step, lat_min + lat_step),
+            (lon_min + 25.0 * lon_step, lat_min + lat_step),
+            (lon_min + 25.0 * lon_step, lat_min + 2.0 * lat_step),
+            (lon_min + lon_step, lat_min + lat_step),
+        ]];
+        assert_eq!(
+            tiles_in_polygons(&[strip], ZOOM),
+            vec![(100, 100), (101, 100), (102, 100)]
+        );
+
+        // Regions containing tile centers keep only those
+        let large = tile_box((100, 100), (101, 101));
+        let polygons = parse_geometry(&large);
+        assert_eq!(tiles_in_polygons(&polygons, ZOOM).len(), 4);
+    }
+}
@@ -0,0 +1,115 @@
+use chrono::{Local, Timelike};
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::MissedTickBehavior;
+
+/// When the map server syncs with Strava by itself
+#[derive(Debug, Clone)]
+pub struct ScheduleOptions {
+    pub interval: Duration,
+    /// No syncs during these hours
+    pub quiet_hours: Option<QuietHours>,
+}
+
+/// Hours of the day in local time, from `start` up to `end`; may wrap past midnight
+#[derive(Debug, Clone, Copy)]
+pub struct QuietHours {
+    pub start: u32,
+    pub end: u32,
+}
+
+impl ScheduleOptions {
+    /// Read SYNC_INTERVAL_MINUTES and SYNC_QUIET_HOURS; None if no interval is set
+    pub fn from_env() -> Option<Self> {
+        let minutes: u64 = std::env::var("SYNC_INTERVAL_MINUTES")
+            .ok()
+            .and_then(|v| v.trim().parse().ok())
+            .filter(|&m| m > 0)?;
+        let quiet_hours = std::env::var("SYNC_QUIET_HOURS")
+            .ok()
+            .filter(|v| !v.trim().is_empty())
+            .and_then(|v| {
+                QuietHours::parse(&v)
+                    .map_err(|e| eprintln!("Ignoring SYNC_QUIET_HOURS: {}", e))
+                    .ok()
+            });
+        Some(ScheduleOptions {
+            interval: Duration::from_secs(minutes * 60),
+            quiet_hours,
+        })
+    }
+}
+
+impl QuietHours {
+    /// Parse hours like `22-6`
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let hour = |s: &str| -> Option<u32> { s.trim().parse().ok().filter(|&h| h < 24) };
+        value
+            .split_once('-')
+            .and_then(|(start, end)| Some((hour(start)?, hour(end)?)))
+            .map(|(start, end)| QuietHours { start, end })
+            .ok_or_else(|| format!("expected hours like 22-6, got {}", value))
+    }
+
+    pub fn contains(&self, hour: u32) -> bool {
+        if self.start <= self.end {
+            (self.start..self.end).contains(&hour)
+        } else {
+            hour >= self.start || hour < self.end
+        }
+    }
+}
+
+/// Call `run` right away and then every interval, skipping quiet hours
+pub async fn run_scheduled<F, Fut>(options: ScheduleOptions, mut run: F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let mut ticks = tokio::time::interval(options.interval);
+    // A run that took longer than the interval doesn't cause a burst of runs
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticks.tick().await;
+        let hour = Local::now().hour();
+        if options.quiet_hours.is_some_and(|q| q.contains(hour)) {
+            continue;
+        }
+        run().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quiet_hours_are_parsed() {
+        let quiet = QuietHours::parse("22-6").unwrap();
+        assert_eq!((quiet.start, quiet.end), (22, 6));
+        let quiet = QuietHours::parse(" 0 - 23 ").unwrap();
+        assert_eq!((quiet.start, quiet.end), (0, 23));
+
+        for invalid in ["", "22", "22-", "-6", "24-6", "22-24", "a-b", "22:00-06:00"] {
+            assert!(QuietHours::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn quiet_hours_contain_start_but_not_end() {
+        let quiet = QuietHours::parse("1-5").unwrap();
+        let hours: Vec<u32> = (0..24).filter(|&h| quiet.contains(h)).collect();
+        assert_eq!(hours, vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn quiet_hours_wrap_past_midnight() {
+        let quiet = QuietHours::parse("22-6").unwrap();
+        let hours: Vec<u32> = (0..24).filter(|&h| quiet.contains(h)).collect();
+        assert_eq!(hours, vec![0, 1, 2, 3, 4, 5, 22, 23]);
+
+        let quiet = QuietHours::parse("23-0").unwrap();
+        let hours: Vec<u32> = (0..24).filter(|&h| quiet.contains(h)).collect();
+        assert_eq!(hours, vec![23]);
+    }
+}
@@ -1,11 +1,21 @@
-use chrono::{DateTime, Duration, Utc};
-use reqwest::header::{AUTHORIZATION, USER_AGENT};
+use chrono::{DateTime, Utc};
+use reqwest::header::{HeaderMap, AUTHORIZATION, RETRY_AFTER, USER_AGENT};
+use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
-use std::fs;
-use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::geo;
+use crate::xml;
 
 const USER_AGENT_VALUE: &str = "rust-strava-example/0.1";
 
+/// Length of Strava's short rate limit window; windows start at :00, :15, :30 and :45 UTC
+const SHORT_WINDOW_SECS: i64 = 15 * 60;
+const DAY_SECS: i64 = 24 * 60 * 60;
+/// Retries of a request answered with 429 or a server error
+const MAX_RETRIES: u32 = 3;
+
 #[derive(Debug, Deserialize)]
 pub struct Athlete {
     pub id: i64,
@@ -28,6 +38,20 @@ pub struct ActivitySummary {
     pub id: i64,
     pub name: Option<String>,
     pub start_date: Option<String>,
+    #[serde(default, rename = "type")]
+    pub activity_type: Option<String>,
+    #[serde(default)]
+    pub moving_time: Option<i64>,
+}
+
+impl ActivitySummary {
+    /// Start date as Unix timestamp in seconds
+    pub fn start_timestamp(&self) -> Option<i64> {
+        self.start_date
+            .as_deref()
+            .and_then(|d| DateTime::parse_from_rfc3339(d).ok())
+            .map(|d| d.timestamp())
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -46,29 +70,248 @@ pub struct StreamSet {
     pub altitude: Option<TypedStream<f64>>,
 }
 
-/// Creates a new reqwest client with the appropriate user agent
-pub fn create_client() -> Result<reqwest::Client, reqwest::Error> {
-    reqwest::Client::builder()
+/// HTTP client for the Strava API that keeps to its rate limits
+///
+/// Every response updates the known usage of the 15-minute and daily budgets. Requests
+/// wait for the next 15-minute window when its budget is used up and fail once the daily
+/// budget is; 429 and server errors are retried with backoff.
+pub struct StravaClient {
+    http: reqwest::Client,
+    usage: Mutex<RateLimitUsage>,
+}
+
+/// Limits and usage from the last response's rate limit headers
+#[derive(Debug, Default)]
+struct RateLimitUsage {
+    short_limit: Option<u32>,
+    short_used: u32,
+    daily_limit: Option<u32>,
+    daily_used: u32,
+    /// Unix timestamp of the response
+    updated_at: i64,
+}
+
+/// Requests left in the current windows, for the UI
+#[derive(Debug, Clone, Serialize)]
+pub struct RateLimitBudget {
+    /// None until the first response
+    pub short_limit: Option<u32>,
+    pub short_remaining: Option<u32>,
+    pub daily_limit: Option<u32>,
+    pub daily_remaining: Option<u32>,
+    /// End of the current 15-minute window (Unix timestamp)
+    pub short_resets_at: i64,
+    /// End of the current day in UTC (Unix timestamp)
+    pub daily_resets_at: i64,
+}
+
+/// Creates a new client with the appropriate user agent
+pub fn create_client() -> Result<StravaClient, reqwest::Error> {
+    let http = reqwest::Client::builder()
         .user_agent(USER_AGENT_VALUE)
-        .build()
+        .build()?;
+    Ok(StravaClient {
+        http,
+        usage: Mutex::new(RateLimitUsage::default()),
+    })
+}
+
+impl StravaClient {
+    /// When requests may continue if the 15-minute budget is used up (Unix timestamp,
+    /// a few seconds after the window resets); None as well once the daily budget is
+    /// used up, since waiting doesn't help then
+    pub fn pause_until(&self) -> Option<i64> {
+        let budget = self.budget();
+        (budget.short_remaining == Some(0) && budget.daily_remaining != Some(0))
+            .then_some(budget.short_resets_at + 5)
+    }
+
+    /// Requests left in the current 15-minute window and day
+    pub fn budget(&self) -> RateLimitBudget {
+        let now = Utc::now().timestamp();
+        let usage = self.usage.lock().unwrap();
+        let short_start = now - now.rem_euclid(SHORT_WINDOW_SECS);
+        let day_start = now - now.rem_euclid(DAY_SECS);
+        // Usage of a past window doesn't count anymore
+        let short_used = if usage.updated_at >= short_start {
+            usage.short_used
+        } else {
+            0
+        };
+        let daily_used = if usage.updated_at >= day_start {
+            usage.daily_used
+        } else {
+            0
+        };
+        RateLimitBudget {
+            short_limit: usage.short_limit,
+            short_remaining: usage.short_limit.map(|l| l.saturating_sub(short_used)),
+            daily_limit: usage.daily_limit,
+            daily_remaining: usage.daily_limit.map(|l| l.saturating_sub(daily_used)),
+            short_resets_at: short_start + SHORT_WINDOW_SECS,
+            daily_resets_at: day_start + DAY_SECS,
+        }
+    }
+
+    /// Send a request to the API, waiting for the budget and retrying 429 and 5xx
+    async fn send(
+        &self,
+        build: impl Fn(&reqwest::Client) -> reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, Box<dyn std::error::Error + Send + Sync>> {
+        self.send_with_retries(build, true).await
+    }
+
+    /// Send an OAuth token request, retrying only 429
+    ///
+    /// A 5xx doesn't tell whether Strava handled the request: a refresh may already have
+    /// rotated the refresh token, and an authorization code is only valid once, so
+    /// sending it again could only fail with a misleading error.
+    async fn send_token_request(
+        &self,
+        build: impl Fn(&reqwest::Client) -> reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, Box<dyn std::error::Error + Send + Sync>> {
+        self.send_with_retries(build, false).await
+    }
+
+    /// Send a request, waiting for the budget and retrying 429 and, if
+    /// `retry_server_errors`, 5xx
+    ///
+    /// `build` is called again for every attempt. Retries honor `Retry-After` and
+    /// otherwise back off exponentially, unless the usage headers of a 429 show the used
+    /// up budget: then wait_for_budget pauses until the window resets.
+    async fn send_with_retries(
+        &self,
+        build: impl Fn(&reqwest::Client) -> reqwest::RequestBuilder,
+        retry_server_errors: bool,
+    ) -> Result<reqwest::Response, Box<dyn std::error::Error + Send + Sync>> {
+        let mut attempt = 0;
+        loop {
+            self.wait_for_budget().await?;
+            let resp = build(&self.http).send().await?;
+            let has_usage = self.record_usage(resp.headers());
+
+            let status = resp.status();
+            let retry = status == StatusCode::TOO_MANY_REQUESTS
+                || (retry_server_errors && status.is_server_error());
+            if attempt >= MAX_RETRIES || !retry {
+                return Ok(resp);
+            }
+            attempt += 1;
+            let delay = match retry_after(resp.headers()) {
+                Some(delay) => delay,
+                None if status == StatusCode::TOO_MANY_REQUESTS
+                    && has_usage
+                    && self.pause_until().is_some() =>
+                {
+                    Duration::from_secs(1)
+                }
+                None => Duration::from_secs(2u64.pow(attempt)),
+            };
+            println!(
+                "Strava answered {}, retrying in {} s ({}/{})",
+                status,
+                delay.as_secs(),
+                attempt,
+                MAX_RETRIES
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Pause until the 15-minute window resets if its budget is used up; fail if the
+    /// daily budget is
+    async fn wait_for_budget(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let budget = self.budget();
+        if budget.daily_remaining == Some(0) {
+            let resets_at = DateTime::from_timestamp(budget.daily_resets_at, 0).unwrap_or_default();
+            return Err(format!(
+                "Strava daily rate limit reached, resets at {}",
+                resets_at.format("%Y-%m-%d %H:%M UTC")
+            )
+            .into());
+        }
+        if let Some(until) = self.pause_until() {
+            let wait = (until - Utc::now().timestamp()).max(0);
+            println!(
+                "Strava 15-minute rate limit reached, pausing for {} s",
+                wait
+            );
+            tokio::time::sleep(Duration::from_secs(wait as u64)).await;
+        }
+        Ok(())
+    }
+
+    /// Remember limits and usage from the response headers; false if there were none
+    ///
+    /// Strava reports overall limits (`X-RateLimit-*`) and, on newer apps, stricter limits
+    /// for reading (`X-ReadRateLimit-*`); the one with less left counts.
+    fn record_usage(&self, headers: &HeaderMap) -> bool {
+        let pair = |name: &str| -> Option<(u32, u32)> {
+            let value = headers.get(name)?.to_str().ok()?;
+            let (short, daily) = value.split_once(',')?;
+            Some((short.trim().parse().ok()?, daily.trim().parse().ok()?))
+        };
+        // (limit, used) of the 15-minute window and the day, per header pair
+        let windows: Vec<[(u32, u32); 2]> = [
+            ("X-RateLimit-Limit", "X-RateLimit-Usage"),
+            ("X-ReadRateLimit-Limit", "X-ReadRateLimit-Usage"),
+        ]
+        .iter()
+        .filter_map(|(limit, usage)| {
+            let (limit, usage) = (pair(limit)?, pair(usage)?);
+            Some([(limit.0, usage.0), (limit.1, usage.1)])
+        })
+        .collect();
+        if windows.is_empty() {
+            return false;
+        }
+
+        let tightest = |window: usize| {
+            windows
+                .iter()
+                .map(|w| w[window])
+                .min_by_key(|(limit, used)| limit.saturating_sub(*used))
+                .unwrap()
+        };
+        let (short_limit, short_used) = tightest(0);
+        let (daily_limit, daily_used) = tightest(1);
+        *self.usage.lock().unwrap() = RateLimitUsage {
+            short_limit: Some(short_limit),
+            short_used,
+            daily_limit: Some(daily_limit),
+            daily_used,
+            updated_at: Utc::now().timestamp(),
+        };
+        true
+    }
+}
+
+/// Wait asked for by a `Retry-After` header, in seconds or as an HTTP date; at most one
+/// rate limit window
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    let secs = match value.parse::<i64>() {
+        Ok(secs) => secs,
+        Err(_) => DateTime::parse_from_rfc2822(value).ok()?.timestamp() - Utc::now().timestamp(),
+    };
+    Some(Duration::from_secs(secs.clamp(1, SHORT_WINDOW_SECS) as u64))
 }
 
 /// Exchange an authorization code for an access token
 pub async fn exchange_code(
-    client: &reqwest::Client,
+    client: &StravaClient,
     client_id: &str,
     client_secret: &str,
     code: &str,
 ) -> Result<TokenResponse, Box<dyn std::error::Error + Send + Sync>> {
+    let form = serde_json::json!({
+        "client_id": client_id,
+        "client_secret": client_secret,
+        "code": code,
+        "grant_type": "authorization_code",
+    });
     let resp = client
-        .post("https://www.strava.com/oauth/token")
-        .form(&serde_json::json!({
-            "client_id": client_id,
-            "client_secret": client_secret,
-            "code": code,
-            "grant_type": "authorization_code",
-        }))
-        .send()
+        .send_token_request(|http| http.post("https://www.strava.com/oauth/token").form(&form))
         .await?;
 
     if !resp.status().is_success() {
@@ -83,20 +326,19 @@ pub async fn exchange_code(
 
 /// Refresh an expired access token using a refresh token
 pub async fn refresh_token(
-    client: &reqwest::Client,
+    client: &StravaClient,
     client_id: &str,
     client_secret: &str,
     refresh_token: &str,
 ) -> Result<TokenResponse, Box<dyn std::error::Error + Send + Sync>> {
+    let form = serde_json::json!({
+        "client_id": client_id,
+        "client_secret": client_secret,
+        "refresh_token": refresh_token,
+        "grant_type": "refresh_token",
+    });
     let resp = client
-        .post("https://www.strava.com/oauth/token")
-        .form(&serde_json::json!({
-            "client_id": client_id,
-            "client_secret": client_secret,
-            "refresh_token": refresh_token,
-            "grant_type": "refresh_token",
-        }))
-        .send()
+        .send_token_request(|http| http.post("https://www.strava.com/oauth/token").form(&form))
         .await?;
 
     if !resp.status().is_success() {
@@ -109,24 +351,46 @@ pub async fn refresh_token(
     Ok(token)
 }
 
-/// Get the OAuth authorization URL
-pub fn get_authorize_url(client_id: &str, redirect_uri: &str) -> String {
-    format!(
-        "https://www.strava.com/oauth/authorize?client_id={}&response_type=code&redirect_uri={}&approval_prompt=auto&scope=read,activity:read,activity:read_all",
-        client_id, redirect_uri
+/// Scope needed to read private activities; users can untick it on the consent page
+pub const REQUIRED_SCOPE: &str = "activity:read_all";
+
+/// Get the OAuth authorization URL; `state` comes back unchanged in the callback
+pub fn get_authorize_url(client_id: &str, redirect_uri: &str, state: &str) -> String {
+    let scope = format!("read,activity:read,{}", REQUIRED_SCOPE);
+    // The parameters are percent-encoded; parsing the fixed base URL can't fail
+    reqwest::Url::parse_with_params(
+        "https://www.strava.com/oauth/authorize",
+        &[
+            ("client_id", client_id),
+            ("response_type", "code"),
+            ("redirect_uri", redirect_uri),
+            ("approval_prompt", "auto"),
+            ("scope", &scope),
+            ("state", state),
+        ],
     )
+    .unwrap()
+    .into()
+}
+
+/// Whether the comma separated scopes granted in the callback include `REQUIRED_SCOPE`
+pub fn has_required_scope(granted: &str) -> bool {
+    granted
+        .split(',')
+        .any(|scope| scope.trim() == REQUIRED_SCOPE)
 }
 
 /// Fetch the authenticated athlete's profile
 pub async fn get_athlete(
-    client: &reqwest::Client,
+    client: &StravaClient,
     access_token: &str,
 ) -> Result<Athlete, Box<dyn std::error::Error + Send + Sync>> {
     let resp = client
-        .get("https://www.strava.com/api/v3/athlete")
-        .header(AUTHORIZATION, format!("Bearer {}", access_token))
-        .header(USER_AGENT, USER_AGENT_VALUE)
-        .send()
+        .send(|http| {
+            http.get("https://www.strava.com/api/v3/athlete")
+                .header(AUTHORIZATION, format!("Bearer {}", access_token))
+                .header(USER_AGENT, USER_AGENT_VALUE)
+        })
         .await?;
 
     if !resp.status().is_success() {
@@ -142,19 +406,32 @@ pub async fn get_athlete(
     Ok(athlete)
 }
 
-/// Fetch a list of activities for the authenticated athlete
+/// Fetch a page of the authenticated athlete's activities, optionally only those started
+/// after and/or before the given Unix timestamps
+///
+/// With `after`, Strava lists the oldest activities first.
 pub async fn get_activities(
-    client: &reqwest::Client,
+    client: &StravaClient,
     access_token: &str,
     per_page: u32,
     page: u32,
+    after: Option<i64>,
+    before: Option<i64>,
 ) -> Result<Vec<ActivitySummary>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut query = vec![("per_page", per_page as i64), ("page", page as i64)];
+    if let Some(after) = after {
+        query.push(("after", after));
+    }
+    if let Some(before) = before {
+        query.push(("before", before));
+    }
     let resp = client
-        .get("https://www.strava.com/api/v3/athlete/activities")
-        .query(&[("per_page", per_page), ("page", page)])
-        .header(AUTHORIZATION, format!("Bearer {}", access_token))
-        .header(USER_AGENT, USER_AGENT_VALUE)
-        .send()
+        .send(|http| {
+            http.get("https://www.strava.com/api/v3/athlete/activities")
+                .query(&query)
+                .header(AUTHORIZATION, format!("Bearer {}", access_token))
+                .header(USER_AGENT, USER_AGENT_VALUE)
+        })
         .await?;
 
     if !resp.status().is_success() {
@@ -166,32 +443,25 @@ pub async fn get_activities(
         ).into());
     }
 
-    let activities_json: serde_json::Value = resp.json().await?;
-    println!(
-        "Recent activities (JSON):\n{}",
-        serde_json::to_string_pretty(&activities_json)?
-    );
-
-    let activities: Vec<ActivitySummary> =
-        serde_json::from_value(activities_json).unwrap_or_default();
-    Ok(activities)
+    Ok(resp.json().await?)
 }
 
 /// Fetch streams (latlng, time, altitude) for a specific activity
 pub async fn get_activity_streams(
-    client: &reqwest::Client,
+    client: &StravaClient,
     access_token: &str,
     activity_id: i64,
 ) -> Result<StreamSet, Box<dyn std::error::Error + Send + Sync>> {
     let resp = client
-        .get(format!(
-            "https://www.strava.com/api/v3/activities/{}/streams",
-            activity_id
-        ))
-        .query(&[("keys", "latlng,time,altitude"), ("key_by_type", "true")])
-        .header(AUTHORIZATION, format!("Bearer {}", access_token))
-        .header(USER_AGENT, USER_AGENT_VALUE)
-        .send()
+        .send(|http| {
+            http.get(format!(
+                "https://www.strava.com/api/v3/activities/{}/streams",
+                activity_id
+            ))
+            .query(&[("keys", "latlng,time,altitude"), ("key_by_type", "true")])
+            .header(AUTHORIZATION, format!("Bearer {}", access_token))
+            .header(USER_AGENT, USER_AGENT_VALUE)
+        })
         .await?;
 
     if !resp.status().is_success() {
@@ -213,102 +483,23 @@ pub async fn get_activity_streams(
     Ok(streams)
 }
 
-/// Export activities as GPX files to the specified directory
-/// If fetch_all is false, already imported activities are skipped
-/// Returns (imported_count, skipped_count)
-pub async fn export_activities_as_gpx(
-    client: &reqwest::Client,
-    access_token: &str,
-    activities: &[ActivitySummary],
-    out_dir: &PathBuf,
-    db_conn: Option<&rusqlite::Connection>,
-    fetch_all: bool,
-) -> Result<(u32, u32), Box<dyn std::error::Error + Send + Sync>> {
-    fs::create_dir_all(out_dir)?;
-
-    let mut imported_count: u32 = 0;
-    let mut skipped_count: u32 = 0;
-
-    for act in activities.iter() {
-        let id = act.id;
-        let name = act.name.as_deref().unwrap_or("");
-
-        // Check if activity was already imported (unless --fetch-all is set)
-        if !fetch_all {
-            if let Some(conn) = db_conn {
-                if crate::database::is_activity_imported(conn, id).unwrap_or(false) {
-                    println!("Skipping already imported activity {} - {}", id, name);
-                    skipped_count += 1;
-                    continue;
-                }
-            }
-        }
-
-        println!("Exporting GPX for activity {} - {}", id, name);
-
-        match get_activity_streams(client, access_token, id).await {
-            Ok(streams) => {
-                let file_path = out_dir.join(format!("activity_{}.gpx", id));
-                let start_date = act.start_date.as_deref();
-                let gpx = build_gpx_xml(name, start_date, &streams);
-
-                // Calculate distance and elevation from streams
-                let distance_km = calculate_distance_from_streams(&streams);
-                let elevation_gain_m = calculate_elevation_gain_from_streams(&streams);
-
-                fs::write(&file_path, gpx)?;
-                println!(
-                    "Saved GPX: {} ({:.2} km, {} hm)",
-                    file_path.display(),
-                    distance_km,
-                    elevation_gain_m
-                );
-
-                // Mark activity as imported in database
-                if let Some(conn) = db_conn {
-                    if let Err(e) = crate::database::mark_activity_imported(
-                        conn,
-                        id,
-                        act.name.as_deref(),
-                        distance_km,
-                        elevation_gain_m,
-                    ) {
-                        eprintln!("Warning: Failed to mark activity {} as imported: {}", id, e);
-                    }
-                }
-                imported_count += 1;
-            }
-            Err(e) => {
-                eprintln!("Failed to get streams for activity {}: {}", id, e);
-                continue;
-            }
-        }
-    }
-
-    println!(
-        "\nImport summary: {} imported, {} skipped (already imported)",
-        imported_count, skipped_count
-    );
-    Ok((imported_count, skipped_count))
-}
-
 /// Build GPX XML content from activity data and streams
 pub fn build_gpx_xml(name: &str, start_date: Option<&str>, streams: &StreamSet) -> String {
     let mut xml = String::new();
     xml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
     xml.push_str("<gpx version=\"1.1\" creator=\"rust-strava-example\" xmlns=\"http://www.topografix.com/GPX/1/1\" xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\" xsi:schemaLocation=\"http://www.topografix.com/GPX/1/1 http://www.topografix.com/GPX/1/1/gpx.xsd\">\n");
 
-    let start_time: Option<DateTime<Utc>> = start_date.and_then(|d| d.parse().ok());
+    let start_time: Option<i64> = start_date.and_then(geo::parse_iso8601);
 
     if let Some(date) = start_date {
         xml.push_str("  <metadata>\n");
-        xml.push_str(&format!("    <time>{}</time>\n", xml_escape(date)));
+        xml.push_str(&format!("    <time>{}</time>\n", xml::escape(date)));
         xml.push_str("  </metadata>\n");
     }
 
     xml.push_str(&format!(
         "  <trk>\n    <name>{}</name>\n    <trkseg>\n",
-        xml_escape(name)
+        xml::escape(name)
     ));
 
     let points = streams.latlng.as_ref().map(|v| v.data.len()).unwrap_or(0);
@@ -329,7 +520,7 @@ pub fn build_gpx_xml(name: &str, start_date: Option<&str>, streams: &StreamSet)
                 .time
                 .as_ref()
                 .and_then(|t| t.data.get(i))
-                .map(|&secs| st + Duration::seconds(secs))
+                .and_then(|&secs| DateTime::from_timestamp(st + secs, 0))
         });
 
         xml.push_str(&format!(
@@ -349,63 +540,18 @@ pub fn build_gpx_xml(name: &str, start_date: Option<&str>, streams: &StreamSet)
     xml
 }
 
-fn xml_escape(s: &str) -> String {
-    s.replace('&', "&amp;")
-        .replace('<', "&lt;")
-        .replace('>', "&gt;")
-}
-
 /// Calculate distance in km from activity streams
 pub fn calculate_distance_from_streams(streams: &StreamSet) -> f64 {
-    let latlng = match &streams.latlng {
-        Some(l) => &l.data,
-        None => return 0.0,
-    };
-
-    if latlng.len() < 2 {
-        return 0.0;
-    }
-
-    let mut total_km = 0.0;
-    for i in 1..latlng.len() {
-        let p1 = (latlng[i - 1][0], latlng[i - 1][1]);
-        let p2 = (latlng[i][0], latlng[i][1]);
-        total_km += haversine_km(p1, p2);
+    match &streams.latlng {
+        Some(l) => geo::distance_km(l.data.iter().map(|p| (p[0], p[1]))),
+        None => 0.0,
     }
-
-    (total_km * 100.0).round() / 100.0
 }
 
 /// Calculate elevation gain in meters from activity streams
 pub fn calculate_elevation_gain_from_streams(streams: &StreamSet) -> i32 {
-    let altitude = match &streams.altitude {
-        Some(a) => &a.data,
-        None => return 0,
-    };
-
-    if altitude.len() < 2 {
-        return 0;
-    }
-
-    let mut total_gain = 0.0;
-    for i in 1..altitude.len() {
-        let diff = altitude[i] - altitude[i - 1];
-        if diff > 0.0 {
-            total_gain += diff;
-        }
+    match &streams.altitude {
+        Some(a) => geo::elevation_gain_m(a.data.iter().copied()),
+        None => 0,
     }
-
-    total_gain.round() as i32
-}
-
-fn haversine_km(p1: (f64, f64), p2: (f64, f64)) -> f64 {
-    let r = 6371.0; // Earth radius in km
-    let d_lat = (p2.0 - p1.0).to_radians();
-    let d_lon = (p2.1 - p1.1).to_radians();
-    let lat1 = p1.0.to_radians();
-    let lat2 = p2.0.to_radians();
-
-    let a = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lon / 2.0).sin().powi(2);
-    let c = 2.0 * a.sqrt().asin();
-    r * c
 }
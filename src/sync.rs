@@ -0,0 +1,338 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::auth::TokenProvider;
+use crate::database::{self, ActivityMetadata, RegionVisit};
+use crate::regions::{self, Regions};
+use crate::strava::{self, ActivitySummary, StravaClient};
+use crate::tiles::{self, TileOptions};
+
+/// Activities per list request, the most Strava allows
+const PAGE_SIZE: u32 = 200;
+
+/// Which activities to sync
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SyncOptions {
+    /// Only activities started after this Unix timestamp; defaults to the checkpoint
+    #[serde(default)]
+    pub after: Option<i64>,
+    /// Only activities started before this Unix timestamp
+    #[serde(default)]
+    pub before: Option<i64>,
+    /// Download already imported activities again, starting with the oldest
+    #[serde(default)]
+    pub fetch_all: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct SyncSummary {
+    pub imported: u32,
+    pub skipped: u32,
+    pub failed: u32,
+    /// Imported activities, oldest first
+    pub imported_ids: Vec<i64>,
+    /// Checkpoint after the sync
+    pub synced_until: Option<i64>,
+    /// Why the sync stopped before the end of the list
+    pub error: Option<String>,
+    pub cancelled: bool,
+}
+
+/// Progress of a running sync
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SyncEvent {
+    /// A page of the activity list was read; `new_activities` so far need a download
+    Listing { page: u32, new_activities: usize },
+    /// Downloading activity `index` (from 1) of `total`
+    Activity {
+        index: usize,
+        total: usize,
+        activity_id: i64,
+        name: String,
+    },
+    Failed {
+        activity_id: i64,
+        name: String,
+        error: String,
+    },
+    /// Paused until the 15-minute rate limit window resets (Unix timestamp)
+    RateLimitWait { until: i64 },
+}
+
+/// Import activities from Strava: list them page by page until the list is empty, then
+/// download the new ones, oldest first
+///
+/// Without `after`, the sync starts at the checkpoint: the start date up to which all
+/// activities are imported. The checkpoint moves forward with every download, so an
+/// interrupted sync continues where it stopped, but never past an activity whose track
+/// couldn't be downloaded. Syncs that leave out newer activities (`before`) or start
+/// after the checkpoint don't move it.
+///
+/// `cancel` is checked before every request; activities downloaded so far stay imported.
+pub async fn sync_activities(
+    client: &StravaClient,
+    tokens: &TokenProvider,
+    db: &Mutex<Connection>,
+    out_dir: &Path,
+    options: &SyncOptions,
+    on_event: &(dyn Fn(SyncEvent) + Send + Sync),
+    cancel: &AtomicBool,
+) -> SyncSummary {
+    let mut summary = SyncSummary::default();
+    if let Err(e) = fs::create_dir_all(out_dir) {
+        summary.error = Some(format!("{}: {}", out_dir.display(), e));
+        return summary;
+    }
+
+    let state = {
+        let conn = db.lock().unwrap();
+        database::get_synced_until(&conn).and_then(|checkpoint| {
+            let imported: HashSet<i64> = database::get_imported_activity_ids(&conn)?
+                .into_iter()
+                .collect();
+            let deleted: HashSet<i64> = database::get_deleted_activity_ids(&conn)?
+                .into_iter()
+                .collect();
+            Ok((checkpoint, imported, deleted))
+        })
+    };
+    let (checkpoint, already_imported, deleted) = match state {
+        Ok(state) => state,
+        Err(e) => {
+            summary.error = Some(e.to_string());
+            return summary;
+        }
+    };
+    summary.synced_until = checkpoint;
+
+    let default_after = if options.fetch_all {
+        0
+    } else {
+        checkpoint.unwrap_or(0)
+    };
+    // Always pass `after`, so Strava lists the oldest activities first and the checkpoint
+    // can follow the list
+    let after = options.after.unwrap_or(default_after);
+    let moves_checkpoint = options.before.is_none() && after <= checkpoint.unwrap_or(0);
+    // Deleted activities stay deleted, even when fetching everything again
+    let needs_download = |activity: &ActivitySummary| {
+        !deleted.contains(&activity.id)
+            && (options.fetch_all || !already_imported.contains(&activity.id))
+    };
+
+    // List first, so the downloads know their total; a list that breaks off is still
+    // downloaded as far as it got
+    let mut listed: Vec<ActivitySummary> = Vec::new();
+    for page in 1.. {
+        if !wait_for_rate_limit(client, on_event, cancel).await {
+            summary.cancelled = true;
+            return summary;
+        }
+        let activities = match tokens.access_token(client).await {
+            Ok(access_token) => strava::get_activities(
+                client,
+                &access_token,
+                PAGE_SIZE,
+                page,
+                Some(after),
+                options.before,
+            )
+            .await
+            .map_err(|e| e.to_string()),
+            Err(e) => Err(e),
+        };
+        match activities {
+            Ok(activities) if activities.is_empty() => break,
+            Ok(activities) => listed.extend(activities),
+            Err(e) => {
+                summary.error = Some(e);
+                break;
+            }
+        }
+        let new_activities = listed.iter().filter(|a| needs_download(a)).count();
+        println!(
+            "Listed page {} ({} activities to download)",
+            page, new_activities
+        );
+        on_event(SyncEvent::Listing {
+            page,
+            new_activities,
+        });
+    }
+    listed.sort_by_key(|a| a.start_timestamp());
+
+    let total = listed.iter().filter(|a| needs_download(a)).count();
+    let mut index = 0;
+    // Everything up to the current activity is imported
+    let mut complete = true;
+    let mut progress = None;
+    for activity in &listed {
+        if !needs_download(activity) {
+            summary.skipped += 1;
+        } else {
+            index += 1;
+            if !wait_for_rate_limit(client, on_event, cancel).await {
+                summary.cancelled = true;
+                break;
+            }
+            let name = activity.name.clone().unwrap_or_default();
+            on_event(SyncEvent::Activity {
+                index,
+                total,
+                activity_id: activity.id,
+                name: name.clone(),
+            });
+            let result = match tokens.access_token(client).await {
+                Ok(access_token) => {
+                    import_activity(client, &access_token, db, out_dir, activity).await
+                }
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(()) => {
+                    summary.imported += 1;
+                    summary.imported_ids.push(activity.id);
+                }
+                // Nothing more can be downloaded today
+                Err(e) if client.budget().daily_remaining == Some(0) => {
+                    summary.error = Some(e);
+                    break;
+                }
+                Err(e) => {
+                    eprintln!("Failed to import activity {}: {}", activity.id, e);
+                    on_event(SyncEvent::Failed {
+                        activity_id: activity.id,
+                        name,
+                        error: e,
+                    });
+                    summary.failed += 1;
+                    complete = false;
+                }
+            }
+        }
+        if complete {
+            progress = activity.start_timestamp().or(progress);
+            if moves_checkpoint && summary.imported_ids.last() == Some(&activity.id) {
+                save_checkpoint(db, progress, &mut summary);
+            }
+        }
+    }
+    if moves_checkpoint {
+        save_checkpoint(db, progress, &mut summary);
+    }
+
+    summary
+}
+
+/// Pause while the 15-minute budget is used up; false if the sync was cancelled
+///
+/// The client would wait as well, but this wait is reported and can be cancelled.
+async fn wait_for_rate_limit(
+    client: &StravaClient,
+    on_event: &(dyn Fn(SyncEvent) + Send + Sync),
+    cancel: &AtomicBool,
+) -> bool {
+    if let Some(until) = client.pause_until() {
+        let resets_at = DateTime::from_timestamp(until, 0).unwrap_or_default();
+        println!(
+            "Strava 15-minute rate limit reached, pausing until {}",
+            resets_at.format("%H:%M:%S UTC")
+        );
+        on_event(SyncEvent::RateLimitWait { until });
+        while Utc::now().timestamp() < until && !cancel.load(Ordering::Relaxed) {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+    !cancel.load(Ordering::Relaxed)
+}
+
+fn save_checkpoint(db: &Mutex<Connection>, progress: Option<i64>, summary: &mut SyncSummary) {
+    let Some(progress) = progress.filter(|p| Some(*p) > summary.synced_until) else {
+        return;
+    };
+    let conn = db.lock().unwrap();
+    match database::set_synced_until(&conn, progress) {
+        Ok(()) => summary.synced_until = Some(progress),
+        Err(e) => eprintln!("Failed to save sync checkpoint: {}", e),
+    }
+}
+
+/// Download an activity's track as GPX and mark the activity as imported
+async fn import_activity(
+    client: &StravaClient,
+    access_token: &str,
+    db: &Mutex<Connection>,
+    out_dir: &Path,
+    activity: &ActivitySummary,
+) -> Result<(), String> {
+    let name = activity.name.as_deref().unwrap_or("");
+    println!("Exporting GPX for activity {} - {}", activity.id, name);
+
+    let streams = strava::get_activity_streams(client, access_token, activity.id)
+        .await
+        .map_err(|e| e.to_string())?;
+    let gpx = strava::build_gpx_xml(name, activity.start_date.as_deref(), &streams);
+    let distance_km = strava::calculate_distance_from_streams(&streams);
+    let elevation_gain_m = strava::calculate_elevation_gain_from_streams(&streams);
+
+    let file_path = out_dir.join(format!("activity_{}.gpx", activity.id));
+    fs::write(&file_path, gpx).map_err(|e| format!("{}: {}", file_path.display(), e))?;
+    println!(
+        "Saved GPX: {} ({:.2} km, {} hm)",
+        file_path.display(),
+        distance_km,
+        elevation_gain_m
+    );
+
+    let conn = db.lock().unwrap();
+    database::save_activity_metadata(
+        &conn,
+        &ActivityMetadata {
+            activity_id: activity.id,
+            activity_name: activity.name.clone(),
+            activity_type: activity.activity_type.clone(),
+            start_date: activity.start_timestamp(),
+            moving_time_s: activity.moving_time,
+            gear: None,
+            distance_km,
+            elevation_gain_m,
+        },
+    )
+    .map_err(|e| e.to_string())
+}
+
+/// Add the tiles of the downloaded tracks and record the regions they entered
+///
+/// Returns the regions the imported activities entered first.
+pub fn process_new_tracks(
+    conn: &mut Connection,
+    options: &TileOptions,
+    regions: &Regions,
+    imported_ids: &[i64],
+) -> Vec<RegionVisit> {
+    if let Err(e) = tiles::process_all_gpx_files(conn, options) {
+        eprintln!("Error processing track files: {}", e);
+    }
+    regions::record_region_visits(conn, regions, imported_ids).unwrap_or_else(|e| {
+        eprintln!("Error recording region visits: {}", e);
+        Vec::new()
+    })
+}
+
+/// Parse a date (YYYY-MM-DD, midnight UTC) or a Unix timestamp in seconds
+pub fn parse_time(value: &str) -> Result<i64, String> {
+    if let Ok(timestamp) = value.parse::<i64>() {
+        return Ok(timestamp);
+    }
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map(|date| date.and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp())
+        .map_err(|_| format!("expected YYYY-MM-DD or a Unix timestamp, got {}", value))
+}
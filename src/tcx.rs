@@ -0,0 +1,164 @@
+use quick_xml::events::BytesStart;
+use std::io::BufRead;
+
+use crate::geo;
+use crate::gpx::{Gpx, Lap, Track, TrackPoint, TrackSegment};
+use crate::xml::{self, XmlHandler};
+
+/// Parse a Garmin Training Center (TCX) file into the common track model
+///
+/// Every `<Activity>` or `<Course>` becomes a track and every TCX `<Track>` a segment,
+/// since devices start a new `<Track>` after a pause. Heart rate, cadence, power and
+/// speed are stored as point extensions ("hr", "cad", "power", "speed") like in GPX.
+pub fn parse_tcx<R: BufRead>(source: R) -> Result<Gpx, String> {
+    let mut handler = TcxHandler::default();
+    xml::stream(source, &mut handler)?;
+    Ok(handler.gpx)
+}
+
+/// Trackpoint being read; TCX stores the position in child elements
+#[derive(Default)]
+struct PendingPoint {
+    lat: Option<f64>,
+    lon: Option<f64>,
+    point: TrackPoint,
+}
+
+#[derive(Default)]
+struct TcxHandler {
+    gpx: Gpx,
+    current_point: Option<PendingPoint>,
+}
+
+impl XmlHandler for TcxHandler {
+    fn open(&mut self, stack: &[String], name: &str, e: &BytesStart) -> Result<(), String> {
+        match (stack.last().map(String::as_str), name) {
+            (Some("Activities"), "Activity") | (Some("Courses"), "Course") => {
+                self.gpx.tracks.push(Track::default());
+            }
+            (Some("Activity"), "Lap") => {
+                self.gpx.laps.push(Lap {
+                    start_time: xml::attr(e, "StartTime")?
+                        .as_deref()
+                        .and_then(geo::parse_iso8601),
+                    ..Default::default()
+                });
+            }
+            (Some("Lap"), "Track") | (Some("Course"), "Track") => {
+                if let Some(track) = self.gpx.tracks.last_mut() {
+                    track.segments.push(TrackSegment::default());
+                }
+            }
+            (Some("Track"), "Trackpoint") => {
+                self.current_point = Some(PendingPoint::default());
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn close(&mut self, stack: &[String], text: &str) {
+        let gpx = &mut self.gpx;
+        let grandparent = stack.len().checked_sub(3).map(|i| stack[i].as_str());
+        match xml::leaf(stack) {
+            (Some("Track"), "Trackpoint") => {
+                // Points without a position (e.g. heart rate only) can't be placed on the map
+                if let Some(PendingPoint {
+                    lat: Some(lat),
+                    lon: Some(lon),
+                    mut point,
+                }) = self.current_point.take()
+                {
+                    point.lat = lat;
+                    point.lon = lon;
+                    if let Some(segment) = gpx.tracks.last_mut().and_then(|t| t.segments.last_mut())
+                    {
+                        segment.points.push(point);
+                    }
+                }
+            }
+            (Some("Position"), "LatitudeDegrees") => {
+                if let Some(pending) = self.current_point.as_mut() {
+                    pending.lat = text.parse().ok();
+                }
+            }
+            (Some("Position"), "LongitudeDegrees") => {
+                if let Some(pending) = self.current_point.as_mut() {
+                    pending.lon = text.parse().ok();
+                }
+            }
+            (Some("Trackpoint"), "Time") => {
+                if let Some(pending) = self.current_point.as_mut() {
+                    pending.point.time = geo::parse_iso8601(text);
+                }
+            }
+            (Some("Trackpoint"), "AltitudeMeters") => {
+                if let Some(pending) = self.current_point.as_mut() {
+                    pending.point.ele = text.parse().ok();
+                }
+            }
+            (Some("HeartRateBpm"), "Value") if grandparent == Some("Trackpoint") => {
+                self.insert_extension("hr", text);
+            }
+            (Some("Trackpoint"), "Cadence") => self.insert_extension("cad", text),
+            (Some("Trackpoint"), "DistanceMeters") => self.insert_extension("distance", text),
+            // Activity extension (TPX): Speed, Watts, RunCadence
+            (Some("TPX"), "Watts") => self.insert_extension("power", text),
+            (Some("TPX"), "Speed") => self.insert_extension("speed", text),
+            (Some("TPX"), "RunCadence") => self.insert_extension("cad", text),
+            (Some("Lap"), "TotalTimeSeconds") => {
+                if let Some(lap) = gpx.laps.last_mut() {
+                    lap.total_time_s = text.parse().ok();
+                }
+            }
+            (Some("Lap"), "DistanceMeters") => {
+                if let Some(lap) = gpx.laps.last_mut() {
+                    lap.distance_m = text.parse().ok();
+                }
+            }
+            (Some("Lap"), "Calories") => {
+                if let Some(lap) = gpx.laps.last_mut() {
+                    lap.calories = text.parse().ok();
+                }
+            }
+            (Some("Lap"), "Cadence") => {
+                if let Some(lap) = gpx.laps.last_mut() {
+                    lap.cadence = text.parse().ok();
+                }
+            }
+            (Some("AverageHeartRateBpm"), "Value") if grandparent == Some("Lap") => {
+                if let Some(lap) = gpx.laps.last_mut() {
+                    lap.avg_heart_rate = text.parse().ok();
+                }
+            }
+            (Some("MaximumHeartRateBpm"), "Value") if grandparent == Some("Lap") => {
+                if let Some(lap) = gpx.laps.last_mut() {
+                    lap.max_heart_rate = text.parse().ok();
+                }
+            }
+            // The activity Id is its start time
+            (Some("Activity"), "Id") if gpx.time.is_none() => {
+                gpx.time = geo::parse_iso8601(text);
+            }
+            (Some("Activity"), "Notes") | (Some("Course"), "Name") => {
+                if let Some(track) = gpx.tracks.last_mut() {
+                    track.name = xml::non_empty(text);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl TcxHandler {
+    fn insert_extension(&mut self, key: &str, text: &str) {
+        if let Some(pending) = self.current_point.as_mut() {
+            if !text.is_empty() {
+                pending
+                    .point
+                    .extensions
+                    .insert(key.to_string(), text.to_string());
+            }
+        }
+    }
+}
@@ -1,68 +1,14 @@
+use chrono::{Months, Utc};
 use rusqlite::Connection;
 use serde::Serialize;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use crate::database;
-
-/// Calculate distance between two GPS coordinates using Haversine formula
-fn haversine_km(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
-    const R: f64 = 6371.0; // Earth radius in km
-    let d_lat = (lat2 - lat1).to_radians();
-    let d_lon = (lon2 - lon1).to_radians();
-    let lat1_rad = lat1.to_radians();
-    let lat2_rad = lat2.to_radians();
-
-    let a =
-        (d_lat / 2.0).sin().powi(2) + lat1_rad.cos() * lat2_rad.cos() * (d_lon / 2.0).sin().powi(2);
-    let c = 2.0 * a.sqrt().asin();
-    R * c
-}
-
-/// Calculate total distance from a list of GPS points
-fn calculate_distance_from_points(points: &[(f64, f64, i64)]) -> f64 {
-    if points.len() < 2 {
-        return 0.0;
-    }
-
-    let mut total = 0.0;
-    for window in points.windows(2) {
-        let (lat1, lon1, _) = window[0];
-        let (lat2, lon2, _) = window[1];
-        total += haversine_km(lat1, lon1, lat2, lon2);
-    }
-    total
-}
-
-/// Calculate elevation gain from GPX content
-fn calculate_elevation_gain_from_gpx(content: &str) -> i32 {
-    let mut elevations: Vec<f64> = Vec::new();
-
-    // Extract all elevation values from <ele> tags
-    let mut remaining = content;
-    while let Some(start) = remaining.find("<ele>") {
-        let after_tag = &remaining[start + 5..];
-        if let Some(end) = after_tag.find("</ele>") {
-            let ele_str = &after_tag[..end];
-            if let Ok(ele) = ele_str.trim().parse::<f64>() {
-                elevations.push(ele);
-            }
-        }
-        remaining = &remaining[start + 5..];
-    }
-
-    // Sum only positive elevation changes (climbing)
-    let mut total_gain = 0.0;
-    for i in 1..elevations.len() {
-        let diff = elevations[i] - elevations[i - 1];
-        if diff > 0.0 {
-            total_gain += diff;
-        }
-    }
-
-    total_gain.round() as i32
-}
+use crate::geo;
+use crate::gpx::Gpx;
+use crate::track_file;
 
 #[derive(Serialize)]
 pub struct TileInfo {
@@ -82,172 +28,217 @@ pub struct TilesResponse {
     pub total_count: usize,
 }
 
-pub const TILE_ZOOM: u32 = 14;
+/// Directory track files are imported from
+pub const GPX_DIR: &str = "gpx";
 
-pub fn lat_lon_to_tile(lat: f64, lon: f64, zoom: u32) -> (u32, u32) {
-    let n = 2_u32.pow(zoom) as f64;
-    let x = ((lon + 180.0) / 360.0 * n).floor() as u32;
-    let lat_rad = lat.to_radians();
-    let y = ((1.0 - lat_rad.tan().asinh() / std::f64::consts::PI) / 2.0 * n).floor() as u32;
-    (x, y)
-}
-
-// #[allow(dead_code)]
-// pub fn tile_to_bounds(x: u32, y: u32, zoom: u32) -> (f64, f64, f64, f64) {
-//     let n = 2_u32.pow(zoom) as f64;
-//     let lon_min = x as f64 / n * 360.0 - 180.0;
-//     let lon_max = (x + 1) as f64 / n * 360.0 - 180.0;
-
-//     let lat_max = (std::f64::consts::PI * (1.0 - 2.0 * y as f64 / n))
-//         .sinh()
-//         .atan()
-//         .to_degrees();
-//     let lat_min = (std::f64::consts::PI * (1.0 - 2.0 * (y + 1) as f64 / n))
-//         .sinh()
-//         .atan()
-//         .to_degrees();
-
-//     (lat_min, lon_min, lat_max, lon_max)
-// }
+/// Default zoom level (explorer tiles); used when no zoom is requested
+pub const TILE_ZOOM: u32 = 14;
 
-fn extract_attr(s: &str, attr: &str) -> Option<f64> {
-    let pattern = format!("{}=\"", attr);
-    let start = s.find(&pattern)? + pattern.len();
-    let rest = &s[start..];
-    let end = rest.find('"')?;
-    rest[..end].parse().ok()
+/// Zoom level of "squadratinhos"
+const SQUADRATINHO_ZOOM: u32 = 17;
+
+/// Default for the longest gap between two points that is still filled in when rasterizing
+const DEFAULT_MAX_GAP_KM: f64 = 2.0;
+
+/// How track points are turned into visited tiles
+#[derive(Debug, Clone)]
+pub struct TileOptions {
+    /// Zoom levels tiles are computed at, all in the same pass over a file
+    pub zooms: Vec<u32>,
+    /// Mark every tile the line between two consecutive points crosses, not only the
+    /// tiles containing a point
+    pub rasterize: bool,
+    /// Lines between points further apart than this are not rasterized (GPS glitches,
+    /// recording gaps)
+    pub max_gap_km: f64,
 }
 
-/// Extract time attribute from a trkpt element
-fn extract_time_from_trkpt(content: &str, start_pos: usize) -> Option<i64> {
-    // Look for <time> tag after the trkpt
-    let segment = &content[start_pos..];
-
-    // Find the end of this trkpt (could be </trkpt> or next <trkpt)
-    let end_pos = segment.find("</trkpt>").unwrap_or(segment.len().min(500));
-    let trkpt_content = &segment[..end_pos];
-
-    if let Some(time_start) = trkpt_content.find("<time>") {
-        let rest = &trkpt_content[time_start + 6..];
-        if let Some(time_end) = rest.find("</time>") {
-            let time_str = &rest[..time_end];
-            return Some(parse_iso8601(time_str));
+impl Default for TileOptions {
+    fn default() -> Self {
+        TileOptions {
+            zooms: vec![TILE_ZOOM, SQUADRATINHO_ZOOM],
+            rasterize: false,
+            max_gap_km: DEFAULT_MAX_GAP_KM,
         }
     }
-    None
 }
 
-/// Parse ISO8601 timestamp to Unix epoch seconds
-fn parse_iso8601(s: &str) -> i64 {
-    let s = s.trim().trim_end_matches('Z');
-    let s = if let Some(pos) = s.rfind('+') {
-        &s[..pos]
-    } else if let Some(pos) = s.rfind('-') {
-        if pos > 10 {
-            &s[..pos]
-        } else {
-            s
+impl TileOptions {
+    /// Read options from the environment (.env supported):
+    /// TILE_ZOOMS (e.g. "14,17"), TILE_RASTERIZE (true/1) and TILE_MAX_GAP_KM
+    pub fn from_env() -> Self {
+        let mut zooms: Vec<u32> = std::env::var("TILE_ZOOMS")
+            .map(|v| {
+                v.split(',')
+                    .filter_map(|z| z.trim().parse().ok())
+                    .filter(|z| *z <= 24)
+                    .collect()
+            })
+            .unwrap_or_default();
+        zooms.sort_unstable();
+        zooms.dedup();
+        if zooms.is_empty() {
+            zooms = TileOptions::default().zooms;
+        }
+        let rasterize = std::env::var("TILE_RASTERIZE")
+            .map(|v| matches!(v.trim().to_ascii_lowercase().as_str(), "1" | "true" | "yes"))
+            .unwrap_or(false);
+        let max_gap_km = std::env::var("TILE_MAX_GAP_KM")
+            .ok()
+            .and_then(|v| v.trim().parse::<f64>().ok())
+            .filter(|km| *km >= 0.0)
+            .unwrap_or(DEFAULT_MAX_GAP_KM);
+        TileOptions {
+            zooms,
+            rasterize,
+            max_gap_km,
         }
-    } else {
-        s
-    };
-
-    let parts: Vec<&str> = s.split('T').collect();
-    if parts.len() != 2 {
-        return 0;
     }
+}
 
-    let date_parts: Vec<i32> = parts[0].split('-').filter_map(|p| p.parse().ok()).collect();
-    let time_str = parts[1].split('.').next().unwrap_or(parts[1]); // Remove fractional seconds
-    let time_parts: Vec<i32> = time_str.split(':').filter_map(|p| p.parse().ok()).collect();
+/// Fractional tile coordinates of a position (Web Mercator)
+fn lat_lon_to_tile_f64(lat: f64, lon: f64, zoom: u32) -> (f64, f64) {
+    let n = 2_u32.pow(zoom) as f64;
+    let x = (lon + 180.0) / 360.0 * n;
+    let lat_rad = lat.to_radians();
+    let y = (1.0 - lat_rad.tan().asinh() / std::f64::consts::PI) / 2.0 * n;
+    (x, y)
+}
 
-    if date_parts.len() < 3 || time_parts.len() < 2 {
-        return 0;
-    }
+pub fn lat_lon_to_tile(lat: f64, lon: f64, zoom: u32) -> (u32, u32) {
+    let (x, y) = lat_lon_to_tile_f64(lat, lon, zoom);
+    (x.floor() as u32, y.floor() as u32)
+}
 
-    let year = date_parts[0];
-    let month = date_parts[1];
-    let day = date_parts[2];
-    let hour = time_parts[0];
-    let min = time_parts[1];
-    let sec = if time_parts.len() > 2 {
-        time_parts[2]
-    } else {
-        0
+/// All tiles crossed by the straight line from `a` to `b` (fractional tile coordinates)
+///
+/// Walks the tile grid cell by cell (Amanatides & Woo) and returns each tile together
+/// with the fraction of the line (0..=1) at which it is entered.
+fn tiles_on_line(a: (f64, f64), b: (f64, f64)) -> Vec<((u32, u32), f64)> {
+    let (mut x, mut y) = (a.0.floor() as i64, a.1.floor() as i64);
+    let (end_x, end_y) = (b.0.floor() as i64, b.1.floor() as i64);
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+
+    // Line fraction to the next vertical/horizontal tile border and per tile step
+    let axis = |start: f64, cell: i64, delta: f64| {
+        if delta > 0.0 {
+            (1, ((cell + 1) as f64 - start) / delta, 1.0 / delta)
+        } else if delta < 0.0 {
+            (-1, (start - cell as f64) / -delta, -1.0 / delta)
+        } else {
+            (0, f64::INFINITY, f64::INFINITY)
+        }
     };
-
-    let mut days: i64 = 0;
-    for y in 1970..year {
-        days += if is_leap_year(y) { 366 } else { 365 };
-    }
-    let month_days = [0, 31, 59, 90, 120, 151, 181, 212, 243, 273, 304, 334];
-    days += month_days[(month - 1) as usize] as i64;
-    if month > 2 && is_leap_year(year) {
-        days += 1;
+    let (step_x, mut next_x, delta_x) = axis(a.0, x, dx);
+    let (step_y, mut next_y, delta_y) = axis(a.1, y, dy);
+
+    let steps = (end_x - x).abs() + (end_y - y).abs();
+    let mut tiles = Vec::with_capacity(steps as usize + 1);
+    let mut t = 0.0;
+    for _ in 0..=steps {
+        tiles.push(((x as u32, y as u32), t));
+        if x == end_x && y == end_y {
+            break;
+        }
+        if next_x < next_y {
+            x += step_x;
+            t = next_x;
+            next_x += delta_x;
+        } else {
+            y += step_y;
+            t = next_y;
+            next_y += delta_y;
+        }
     }
-    days += (day - 1) as i64;
-
-    days * 86400 + hour as i64 * 3600 + min as i64 * 60 + sec as i64
+    tiles
 }
 
-fn is_leap_year(year: i32) -> bool {
-    (year % 4 == 0 && year % 100 != 0) || (year % 400 == 0)
+/// Tiles crossed by the straight line between two positions given as (lat, lon)
+pub fn tiles_between(a: (f64, f64), b: (f64, f64), zoom: u32) -> Vec<(u32, u32)> {
+    let a = lat_lon_to_tile_f64(a.0, a.1, zoom);
+    let b = lat_lon_to_tile_f64(b.0, b.1, zoom);
+    tiles_on_line(a, b)
+        .into_iter()
+        .map(|(tile, _)| tile)
+        .collect()
 }
 
-fn extract_all_points_with_time_from_gpx(content: &str) -> Vec<(f64, f64, i64)> {
-    let mut points = Vec::new();
-    let mut default_time: Option<i64> = None;
+/// Collect every visited tile at `zoom` with the earliest time it was entered
+fn collect_tile_times(
+    segments: &[Vec<(f64, f64, i64)>],
+    zoom: u32,
+    options: &TileOptions,
+) -> HashMap<(u32, u32), i64> {
+    let mut tile_times: HashMap<(u32, u32), i64> = HashMap::new();
+    let mut visit = |tile: (u32, u32), time: i64| {
+        tile_times
+            .entry(tile)
+            .and_modify(|t| *t = (*t).min(time))
+            .or_insert(time);
+    };
+    let half_world = 2_u32.pow(zoom) as f64 / 2.0;
+
+    for segment in segments {
+        for (i, &(lat, lon, time)) in segment.iter().enumerate() {
+            visit(lat_lon_to_tile(lat, lon, zoom), time);
 
-    // Try to extract default time from metadata
-    if let Some(start) = content.find("<metadata>") {
-        if let Some(end) = content.find("</metadata>") {
-            let metadata = &content[start..end];
-            if let Some(time_start) = metadata.find("<time>") {
-                let rest = &metadata[time_start + 6..];
-                if let Some(time_end) = rest.find("</time>") {
-                    let time_str = &rest[..time_end];
-                    default_time = Some(parse_iso8601(time_str));
-                }
+            if !options.rasterize || i == 0 {
+                continue;
+            }
+            let (prev_lat, prev_lon, prev_time) = segment[i - 1];
+            if geo::haversine_km((prev_lat, prev_lon), (lat, lon)) > options.max_gap_km {
+                continue;
+            }
+            let a = lat_lon_to_tile_f64(prev_lat, prev_lon, zoom);
+            let b = lat_lon_to_tile_f64(lat, lon, zoom);
+            // Don't draw a line around the world when crossing the antimeridian
+            if (b.0 - a.0).abs() > half_world {
+                continue;
+            }
+            for (tile, fraction) in tiles_on_line(a, b) {
+                let entered = prev_time + ((time - prev_time) as f64 * fraction).round() as i64;
+                visit(tile, entered);
             }
         }
     }
 
-    let mut search_start = 0;
-    while let Some(pos) = content[search_start..].find("<trkpt") {
-        let abs_pos = search_start + pos;
-        let segment = &content[abs_pos..];
-
-        let lat = extract_attr(segment, "lat");
-        let lon = extract_attr(segment, "lon");
+    tile_times
+}
 
-        if let (Some(lat), Some(lon)) = (lat, lon) {
-            // Try to get time from this specific trackpoint
-            let time = extract_time_from_trkpt(content, abs_pos)
-                .or(default_time)
-                .unwrap_or(0);
-            points.push((lat, lon, time));
-        }
+// #[allow(dead_code)]
+// pub fn tile_to_bounds(x: u32, y: u32, zoom: u32) -> (f64, f64, f64, f64) {
+//     let n = 2_u32.pow(zoom) as f64;
+//     let lon_min = x as f64 / n * 360.0 - 180.0;
+//     let lon_max = (x + 1) as f64 / n * 360.0 - 180.0;
 
-        search_start = abs_pos + 6;
-    }
+//     let lat_max = (std::f64::consts::PI * (1.0 - 2.0 * y as f64 / n))
+//         .sinh()
+//         .atan()
+//         .to_degrees();
+//     let lat_min = (std::f64::consts::PI * (1.0 - 2.0 * (y + 1) as f64 / n))
+//         .sinh()
+//         .atan()
+//         .to_degrees();
 
-    points
-}
+//     (lat_min, lon_min, lat_max, lon_max)
+// }
 
-/// Extract the track name from GPX content
-fn extract_track_name(content: &str) -> Option<String> {
-    if let Some(start) = content.find("<name>") {
-        let rest = &content[start + 6..];
-        if let Some(end) = rest.find("</name>") {
-            return Some(rest[..end].to_string());
-        }
-    }
-    None
+/// Extract (lat, lon, time) for every point, grouped by track segment
+fn extract_segments_with_time_from_gpx(gpx: &Gpx) -> Vec<Vec<(f64, f64, i64)>> {
+    gpx.segments()
+        .map(|segment| {
+            segment
+                .points
+                .iter()
+                .map(|p| (p.lat, p.lon, p.time.or(gpx.time).unwrap_or(0)))
+                .collect()
+        })
+        .collect()
 }
 
 /// Extract activity ID from filename (e.g., "activity_15409133734.gpx" -> "15409133734")
 fn extract_activity_id(filename: &str) -> Option<String> {
-    let name = filename.strip_suffix(".gpx")?;
+    let name = track_file::file_stem(filename)?;
     if let Some(id) = name.strip_prefix("activity_") {
         Some(id.to_string())
     } else {
@@ -255,57 +246,75 @@ fn extract_activity_id(filename: &str) -> Option<String> {
     }
 }
 
+/// Configured zoom levels not yet in `processed_zooms`
+fn missing_zooms(processed_zooms: &[u32], options: &TileOptions) -> Vec<u32> {
+    options
+        .zooms
+        .iter()
+        .filter(|z| !processed_zooms.contains(z))
+        .copied()
+        .collect()
+}
+
 /// Process a single GPX file and store tiles in the database
 pub fn process_gpx_file(
     conn: &mut Connection,
     filename: &str,
-    content: &str,
+    gpx: &Gpx,
+    options: &TileOptions,
 ) -> Result<usize, String> {
-    // Check if already processed
-    if database::is_file_processed(conn, filename).map_err(|e| e.to_string())? {
+    // Only compute zoom levels this file hasn't been processed at yet
+    let processed_zooms = database::get_processed_zooms(conn, filename)
+        .map_err(|e| e.to_string())?
+        .unwrap_or_default();
+    let zooms = missing_zooms(&processed_zooms, options);
+    if zooms.is_empty() {
         return Ok(0);
     }
 
-    let points = extract_all_points_with_time_from_gpx(content);
-    let activity_title = extract_track_name(content).unwrap_or_else(|| filename.to_string());
-
-    // Calculate distance and elevation from GPS points / GPX content
-    let distance_km = calculate_distance_from_points(&points);
-    let elevation_gain_m = calculate_elevation_gain_from_gpx(content);
+    let segments = extract_segments_with_time_from_gpx(gpx);
     let activity_id = extract_activity_id(filename).unwrap_or_default();
-
-    // Collect tiles with their earliest timestamp
-    let mut tile_times: HashMap<(u32, u32), i64> = HashMap::new();
-
-    for (lat, lon, time) in points {
-        let (x, y) = lat_lon_to_tile(lat, lon, TILE_ZOOM);
-        tile_times
-            .entry((x, y))
-            .and_modify(|t| *t = (*t).min(time))
-            .or_insert(time);
+    let activity_title = gpx
+        .track_name()
+        .map(str::to_string)
+        .unwrap_or_else(|| filename.to_string());
+
+    // Calculate distance and elevation from GPS points
+    let distance_km = gpx.distance_km();
+    let elevation_gain_m = gpx.elevation_gain_m();
+
+    // Collect tiles with their earliest timestamp at every zoom level
+    let mut tiles: Vec<database::TileData> = Vec::new();
+    for &zoom in &zooms {
+        let tile_times = collect_tile_times(&segments, zoom, options);
+        tiles.extend(
+            tile_times
+                .into_iter()
+                .map(|((x, y), time)| database::TileData {
+                    x,
+                    y,
+                    z: zoom,
+                    visited_at: time,
+                    activity_id: activity_id.clone(),
+                    activity_title: activity_title.clone(),
+                    gpx_filename: filename.to_string(),
+                }),
+        );
     }
 
-    // Prepare batch insert
-    let tiles: Vec<database::TileData> = tile_times
-        .into_iter()
-        .map(|((x, y), time)| database::TileData {
-            x,
-            y,
-            z: TILE_ZOOM,
-            visited_at: time,
-            activity_id: activity_id.clone(),
-            activity_title: activity_title.clone(),
-            gpx_filename: filename.to_string(),
-        })
-        .collect();
-
     let count = tiles.len();
 
-    // Insert tiles
+    // Insert tiles and update the cached Übersquadrat and Yard
     database::insert_tiles_batch(conn, &tiles).map_err(|e| e.to_string())?;
+    for &zoom in &zooms {
+        database::update_tile_metrics(conn, zoom).map_err(|e| e.to_string())?;
+    }
 
     // Mark file as processed
-    database::mark_file_processed(conn, filename).map_err(|e| e.to_string())?;
+    let mut all_zooms = processed_zooms;
+    all_zooms.extend(zooms);
+    all_zooms.sort_unstable();
+    database::mark_file_processed(conn, filename, &all_zooms).map_err(|e| e.to_string())?;
 
     // Store activity with distance in imported_activities
     if let Ok(activity_id_num) = activity_id.parse::<i64>() {
@@ -326,40 +335,235 @@ pub fn process_gpx_file(
     Ok(count)
 }
 
-/// Process all GPX files in the gpx directory
-pub fn process_all_gpx_files(conn: &mut Connection) -> Result<usize, String> {
-    let gpx_dir = PathBuf::from("gpx");
-    let mut total_new_tiles = 0;
+/// Process a track file from the gpx directory
+///
+/// The file is only parsed if it is new, misses a configured zoom level or its content
+/// changed since it was processed. A changed file replaces its previous tiles. Files are
+/// only hashed when their size or modification time changed.
+pub fn process_track_file(
+    conn: &mut Connection,
+    path: &Path,
+    options: &TileOptions,
+) -> Result<usize, String> {
+    let name = file_name(path)?;
+    let stored = database::get_file_version(conn, name)
+        .map_err(|e| e.to_string())?
+        .unwrap_or_default();
+    let version = file_version(path, &stored)?;
+    if stored
+        .content_hash
+        .as_ref()
+        .is_some_and(|h| Some(h) != version.content_hash.as_ref())
+    {
+        println!("{} changed, reprocessing", name);
+        return reprocess_track_file(conn, path, options);
+    }
+
+    let processed_zooms = database::get_processed_zooms(conn, name)
+        .map_err(|e| e.to_string())?
+        .unwrap_or_default();
+    if missing_zooms(&processed_zooms, options).is_empty() {
+        // Files processed before versions were stored, or touched without changes
+        if stored != version {
+            database::set_file_version(conn, name, &version).map_err(|e| e.to_string())?;
+        }
+        return Ok(0);
+    }
+
+    let gpx = read_named_track(conn, path, name)?;
+    let count = process_gpx_file(conn, name, &gpx, options)?;
+    database::set_file_version(conn, name, &version).map_err(|e| e.to_string())?;
+    Ok(count)
+}
+
+/// Read a track file for processing
+///
+/// Files without a name (e.g. FIT) get the name stored when the activity was imported.
+fn read_named_track(conn: &Connection, path: &Path, name: &str) -> Result<Gpx, String> {
+    let mut gpx = track_file::read_track_file(path)?;
+    if gpx.track_name().is_none() {
+        gpx.name = extract_activity_id(name)
+            .and_then(|id| id.parse::<i64>().ok())
+            .and_then(|id| database::get_activity_name(conn, id).ok().flatten());
+    }
+    Ok(gpx)
+}
+
+/// Current version of a file, reusing the stored hash if size and modification time
+/// still match
+fn file_version(
+    path: &Path,
+    stored: &database::FileVersion,
+) -> Result<database::FileVersion, String> {
+    let (size, modified) = track_file::size_and_modified(path)?;
+    let unchanged = stored.size == Some(size) && stored.modified == Some(modified);
+    let content_hash = match &stored.content_hash {
+        Some(hash) if unchanged => hash.clone(),
+        _ => track_file::content_hash(path)?,
+    };
+    Ok(database::FileVersion {
+        content_hash: Some(content_hash),
+        size: Some(size),
+        modified: Some(modified),
+    })
+}
+
+/// Drop a file's tiles and process it again, updating the activity's distance and climbing
+fn reprocess_track_file(
+    conn: &mut Connection,
+    path: &Path,
+    options: &TileOptions,
+) -> Result<usize, String> {
+    let name = file_name(path)?;
+    database::remove_file_tiles(conn, name).map_err(|e| e.to_string())?;
+
+    let gpx = read_named_track(conn, path, name)?;
+    let count = process_gpx_file(conn, name, &gpx, options)?;
+    let version = file_version(path, &database::FileVersion::default())?;
+    database::set_file_version(conn, name, &version).map_err(|e| e.to_string())?;
 
-    if let Ok(entries) = fs::read_dir(&gpx_dir) {
+    if let Some(Ok(activity_id)) = extract_activity_id(name).map(|id| id.parse::<i64>()) {
+        database::update_activity_totals(
+            conn,
+            activity_id,
+            gpx.distance_km(),
+            gpx.elevation_gain_m(),
+        )
+        .map_err(|e| e.to_string())?;
+    }
+    Ok(count)
+}
+
+fn file_name(path: &Path) -> Result<&str, String> {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| format!("Invalid file name: {}", path.display()))
+}
+
+/// Track files of an activity in the gpx directory (e.g. "activity_123.gpx" for "123")
+fn activity_files(activity_id: &str) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    if let Ok(entries) = fs::read_dir(GPX_DIR) {
         for entry in entries.flatten() {
             if let Some(name) = entry.file_name().to_str() {
-                if name.ends_with(".gpx") {
-                    let path = entry.path();
-                    if let Ok(content) = fs::read_to_string(&path) {
-                        match process_gpx_file(conn, name, &content) {
-                            Ok(count) => {
-                                if count > 0 {
-                                    println!("Processed {}: {} tiles", name, count);
-                                    total_new_tiles += count;
-                                }
-                            }
-                            Err(e) => {
-                                eprintln!("Error processing {}: {}", name, e);
-                            }
+                if track_file::is_track_file(name)
+                    && extract_activity_id(name).as_deref() == Some(activity_id)
+                {
+                    files.push(entry.path());
+                }
+            }
+        }
+    }
+    files
+}
+
+/// Reprocess the track files of an activity from scratch
+///
+/// Returns the number of tiles stored for the activity.
+pub fn reprocess_activity(
+    conn: &mut Connection,
+    activity_id: &str,
+    options: &TileOptions,
+) -> Result<usize, String> {
+    let files = activity_files(activity_id);
+    if files.is_empty() {
+        return Err(format!("No track file found for activity {}", activity_id));
+    }
+    let mut count = 0;
+    for path in &files {
+        count += reprocess_track_file(conn, path, options)?;
+    }
+    Ok(count)
+}
+
+/// Delete an activity: its track files and its tiles
+///
+/// Tiles first visited by the activity go to the next activity that visited them. The
+/// activity is kept as deleted, so syncs and archive imports skip it. Returns the number
+/// of such tiles.
+pub fn delete_activity(conn: &mut Connection, activity_id: &str) -> Result<usize, String> {
+    let files = activity_files(activity_id);
+    let numeric_id = activity_id.parse::<i64>().ok();
+    let imported = match numeric_id {
+        Some(id) => database::is_activity_imported(conn, id).map_err(|e| e.to_string())?,
+        None => false,
+    };
+    if files.is_empty() && !imported {
+        return Err(format!("Activity {} not found", activity_id));
+    }
+
+    let mut affected = 0;
+    for path in &files {
+        let name = file_name(path)?;
+        affected += database::remove_file_tiles(conn, name).map_err(|e| e.to_string())?;
+        fs::remove_file(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    }
+    if let Some(id) = numeric_id {
+        database::mark_activity_deleted(conn, id).map_err(|e| e.to_string())?;
+    }
+    Ok(affected)
+}
+
+/// Undo a track file that failed to import: its tiles, the file and the activity entry
+pub fn forget_track_file(
+    conn: &mut Connection,
+    path: &Path,
+    activity_id: i64,
+) -> Result<(), String> {
+    database::remove_file_tiles(conn, file_name(path)?).map_err(|e| e.to_string())?;
+    if path.exists() {
+        fs::remove_file(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    }
+    database::delete_activity(conn, activity_id).map_err(|e| e.to_string())
+}
+
+/// Process all track files (GPX, TCX, FIT) in the gpx directory
+///
+/// Tiles of processed files that were removed from the directory are removed as well.
+pub fn process_all_gpx_files(
+    conn: &mut Connection,
+    options: &TileOptions,
+) -> Result<usize, String> {
+    let mut total_new_tiles = 0;
+
+    let Ok(entries) = fs::read_dir(GPX_DIR) else {
+        return Ok(0);
+    };
+    let mut on_disk = HashSet::new();
+    for entry in entries.flatten() {
+        if let Some(name) = entry.file_name().to_str() {
+            if track_file::is_track_file(name) {
+                on_disk.insert(name.to_string());
+                match process_track_file(conn, &entry.path(), options) {
+                    Ok(count) => {
+                        if count > 0 {
+                            println!("Processed {}: {} tiles", name, count);
+                            total_new_tiles += count;
                         }
                     }
+                    Err(e) => {
+                        eprintln!("Error processing {}: {}", name, e);
+                    }
                 }
             }
         }
     }
 
+    for name in database::get_processed_filenames(conn).map_err(|e| e.to_string())? {
+        if !on_disk.contains(&name) {
+            match database::remove_file_tiles(conn, &name) {
+                Ok(count) => println!("Removed {} (file deleted): {} tiles affected", name, count),
+                Err(e) => eprintln!("Error removing {}: {}", name, e),
+            }
+        }
+    }
+
     Ok(total_new_tiles)
 }
 
-/// Get visited tiles from the database
-pub fn get_visited_tiles(conn: &Connection) -> TilesResponse {
-    let tiles = match database::get_all_tiles(conn) {
+/// Get visited tiles at a zoom level from the database
+pub fn get_visited_tiles(conn: &Connection, zoom: u32) -> TilesResponse {
+    let tiles = match database::get_all_tiles(conn, zoom) {
         Ok(records) => records
             .into_iter()
             .map(|r| TileInfo {
@@ -382,11 +586,138 @@ pub fn get_visited_tiles(conn: &Connection) -> TilesResponse {
 
     TilesResponse {
         tiles,
-        zoom: TILE_ZOOM,
+        zoom,
         total_count,
     }
 }
 
+/// Übersquadrat and Yard of a zoom level
+pub struct TileMetricsSummary {
+    pub tile_count: usize,
+    pub max_square: MaxSquareResult,
+    pub max_cluster: MaxClusterResult,
+}
+
+/// Read the cached Übersquadrat and Yard, catching up on tiles added since the last update
+pub fn get_tile_metrics(conn: &mut Connection, zoom: u32) -> TileMetricsSummary {
+    if let Err(e) = database::update_tile_metrics(conn, zoom) {
+        eprintln!("Error updating tile metrics: {}", e);
+    }
+    let metrics = database::get_tile_metrics(conn, zoom).unwrap_or_else(|e| {
+        eprintln!("Error getting tile metrics from database: {}", e);
+        database::TileMetricsRecord::default()
+    });
+    let cluster_tiles = metrics
+        .cluster_id
+        .map(|id| database::get_cluster_tiles(conn, zoom, id).unwrap_or_default())
+        .unwrap_or_default();
+
+    TileMetricsSummary {
+        tile_count: database::get_tile_count(conn, zoom).unwrap_or(0),
+        max_square: MaxSquareResult {
+            size: metrics.max_square,
+            top_left_x: (metrics.square_x + 1).saturating_sub(metrics.max_square),
+            top_left_y: (metrics.square_y + 1).saturating_sub(metrics.max_square),
+        },
+        max_cluster: MaxClusterResult {
+            size: metrics.max_cluster,
+            tiles: cluster_tiles,
+        },
+    }
+}
+
+/// Visit statistics of a tile
+#[derive(Serialize)]
+pub struct TileVisitInfo {
+    pub x: u32,
+    pub y: u32,
+    pub z: u32,
+    pub visit_count: usize,
+    pub first_visited_at: i64,
+    pub last_visited_at: i64,
+}
+
+/// One activity that visited a tile
+#[derive(Serialize)]
+pub struct TileVisit {
+    pub activity_id: Option<String>,
+    pub activity_title: Option<String>,
+    pub gpx_filename: String,
+    pub visited_at: i64,
+}
+
+/// All visits of a single tile, oldest first
+#[derive(Serialize)]
+pub struct TileDetail {
+    pub x: u32,
+    pub y: u32,
+    pub z: u32,
+    pub visit_count: usize,
+    pub visits: Vec<TileVisit>,
+}
+
+/// Get visit counts and last visits of all tiles at a zoom level
+///
+/// With `not_visited_years`, only tiles whose last visit is longer ago than that are
+/// returned (re-exploration).
+pub fn get_tile_visit_summaries(
+    conn: &Connection,
+    zoom: u32,
+    not_visited_years: Option<u32>,
+) -> Vec<TileVisitInfo> {
+    let cutoff = not_visited_years.map(|years| {
+        Utc::now()
+            .checked_sub_months(Months::new(years.saturating_mul(12)))
+            .map(|t| t.timestamp())
+            .unwrap_or(i64::MIN)
+    });
+
+    match database::get_tile_visit_summaries(conn, zoom, cutoff) {
+        Ok(records) => records
+            .into_iter()
+            .map(|r| TileVisitInfo {
+                x: r.x,
+                y: r.y,
+                z: r.z,
+                visit_count: r.visit_count,
+                first_visited_at: r.first_visited_at,
+                last_visited_at: r.last_visited_at,
+            })
+            .collect(),
+        Err(e) => {
+            eprintln!("Error getting tile visits from database: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+/// Get every activity that visited a tile
+pub fn get_tile_detail(conn: &Connection, x: u32, y: u32, zoom: u32) -> TileDetail {
+    let visits: Vec<TileVisit> = match database::get_tile_visits(conn, x, y, zoom) {
+        Ok(records) => records
+            .into_iter()
+            .map(|r| TileVisit {
+                activity_id: r.activity_id,
+                activity_title: r.activity_title,
+                gpx_filename: r.gpx_filename,
+                visited_at: r.visited_at,
+            })
+            .collect(),
+        Err(e) => {
+            eprintln!("Error getting tile visits from database: {}", e);
+            Vec::new()
+        }
+    };
+
+    TileDetail {
+        x,
+        y,
+        z: zoom,
+        visit_count: visits.len(),
+        visits,
+    }
+}
+
 /// Result for max square calculation
 #[derive(Serialize, Clone)]
 pub struct MaxSquareResult {
@@ -402,145 +733,652 @@ pub struct MaxClusterResult {
     pub tiles: Vec<(u32, u32)>,
 }
 
-/// Calculate the Yard:
-/// 1. Find all tiles that are surrounded on all 4 sides by other visited tiles
-/// 2. From those, find the largest connected cluster (BFS)
-pub fn calculate_max_cluster(tiles: &[TileInfo]) -> MaxClusterResult {
-    use std::collections::{HashSet, VecDeque};
+/// A connected group of tiles with its bounding box (tile coordinates, inclusive)
+#[derive(Serialize, Clone)]
+pub struct TileCluster {
+    pub size: usize,
+    pub min_x: u32,
+    pub min_y: u32,
+    pub max_x: u32,
+    pub max_y: u32,
+    pub tiles: Vec<(u32, u32)>,
+}
 
-    if tiles.is_empty() {
-        return MaxClusterResult {
-            size: 0,
-            tiles: vec![],
-        };
+impl TileCluster {
+    fn new(tiles: Vec<(u32, u32)>) -> Self {
+        TileCluster {
+            size: tiles.len(),
+            min_x: tiles.iter().map(|t| t.0).min().unwrap_or(0),
+            min_y: tiles.iter().map(|t| t.1).min().unwrap_or(0),
+            max_x: tiles.iter().map(|t| t.0).max().unwrap_or(0),
+            max_y: tiles.iter().map(|t| t.1).max().unwrap_or(0),
+            tiles,
+        }
     }
+}
 
-    // Step 1: Find all tiles surrounded on all 4 sides
-    let all_visited: HashSet<(u32, u32)> = tiles.iter().map(|t| (t.x, t.y)).collect();
+/// All clusters and islands, largest first
+#[derive(Serialize, Clone)]
+pub struct ClusterOverview {
+    /// Connected groups of surrounded tiles; the first one is the Yard
+    pub clusters: Vec<TileCluster>,
+    /// Connected groups of visited tiles that contain no surrounded tile at all
+    pub islands: Vec<TileCluster>,
+}
 
-    let surrounded_tiles: HashSet<(u32, u32)> = tiles
+/// Tiles visited on all 4 sides
+fn surrounded_tiles(visited: &HashSet<(u32, u32)>) -> HashSet<(u32, u32)> {
+    visited
         .iter()
-        .filter(|t| {
-            let x = t.x;
-            let y = t.y;
+        .copied()
+        .filter(|&t| neighbours(t).filter(|n| visited.contains(n)).count() == 4)
+        .collect()
+}
 
-            let has_left = x > 0 && all_visited.contains(&(x - 1, y));
-            let has_right = all_visited.contains(&(x + 1, y));
-            let has_up = y > 0 && all_visited.contains(&(x, y - 1));
-            let has_down = all_visited.contains(&(x, y + 1));
+/// Split tiles into 4-connected components (BFS), largest first
+fn connected_components(tiles: &HashSet<(u32, u32)>) -> Vec<Vec<(u32, u32)>> {
+    use std::collections::VecDeque;
 
-            has_left && has_right && has_up && has_down
-        })
-        .map(|t| (t.x, t.y))
+    let mut unvisited = tiles.clone();
+    let mut components = Vec::new();
+
+    while let Some(&start) = unvisited.iter().next() {
+        let mut queue = VecDeque::from([start]);
+        let mut component = vec![];
+        unvisited.remove(&start);
+
+        while let Some(tile) = queue.pop_front() {
+            component.push(tile);
+            for neighbour in neighbours(tile) {
+                if unvisited.remove(&neighbour) {
+                    queue.push_back(neighbour);
+                }
+            }
+        }
+        components.push(component);
+    }
+
+    // Ties broken by position so the order is stable between requests
+    components.sort_by_key(|c| (std::cmp::Reverse(c.len()), c.iter().min().copied()));
+    components
+}
+
+/// Enumerate every cluster of surrounded tiles and every island of visited tiles
+/// that doesn't touch one
+pub fn calculate_clusters(tiles: &[TileInfo]) -> ClusterOverview {
+    let all_visited: HashSet<(u32, u32)> = tiles.iter().map(|t| (t.x, t.y)).collect();
+    let surrounded = surrounded_tiles(&all_visited);
+
+    let islands = connected_components(&all_visited)
+        .into_iter()
+        .filter(|c| !c.iter().any(|t| surrounded.contains(t)))
+        .map(TileCluster::new)
+        .collect();
+    let clusters = connected_components(&surrounded)
+        .into_iter()
+        .map(TileCluster::new)
         .collect();
 
-    if surrounded_tiles.is_empty() {
-        return MaxClusterResult {
-            size: 0,
-            tiles: vec![],
-        };
+    ClusterOverview { clusters, islands }
+}
+
+/// Calculate the largest square (Übersquadrat) within a set of tiles
+///
+/// Uses the sparse DP of `TileMetrics`, so memory grows with the number of tiles, not
+/// with their bounding box.
+pub fn calculate_max_square_from_coords(tile_coords: &[(u32, u32)]) -> MaxSquareResult {
+    let mut metrics = TileMetrics::new();
+    metrics.add_tiles(tile_coords);
+    metrics.max_square()
+}
+
+/// Result for max rectangle calculation
+#[derive(Serialize, Clone, Default)]
+pub struct MaxRectangleResult {
+    pub width: u32,
+    pub height: u32,
+    pub top_left_x: u32,
+    pub top_left_y: u32,
+}
+
+impl MaxRectangleResult {
+    pub fn area(&self) -> u64 {
+        self.width as u64 * self.height as u64
     }
+}
 
-    // Step 2: Find the largest connected cluster within surrounded tiles using BFS
-    let mut unvisited = surrounded_tiles.clone();
-    let mut max_cluster: Vec<(u32, u32)> = vec![];
+/// Calculate the largest fully visited axis-aligned rectangle (by number of tiles)
+///
+/// Row by row, each column keeps the height of visited tiles ending in that row, and
+/// every run of visited tiles is solved as "largest rectangle in a histogram". Only
+/// visited tiles are looked at.
+pub fn calculate_max_rectangle(tile_coords: &[(u32, u32)]) -> MaxRectangleResult {
+    let mut rows: HashMap<u32, Vec<u32>> = HashMap::new();
+    for &(x, y) in tile_coords {
+        rows.entry(y).or_default().push(x);
+    }
+    let mut row_ys: Vec<u32> = rows.keys().copied().collect();
+    row_ys.sort_unstable();
+
+    let mut best = MaxRectangleResult::default();
+    let mut heights: HashMap<u32, u32> = HashMap::new();
+    let mut previous_y: Option<u32> = None;
+    for y in row_ys {
+        let xs = rows.get_mut(&y).unwrap();
+        xs.sort_unstable();
+        xs.dedup();
+
+        let continues = previous_y.is_some_and(|p| p + 1 == y);
+        let row_heights: HashMap<u32, u32> = xs
+            .iter()
+            .map(|&x| {
+                let above = if continues {
+                    heights.get(&x).copied()
+                } else {
+                    None
+                };
+                (x, above.unwrap_or(0) + 1)
+            })
+            .collect();
+
+        // Runs of adjacent columns
+        for run in xs.chunk_by(|a, b| a + 1 == *b) {
+            // Stack of (start column, height) with increasing heights
+            let mut stack: Vec<(u32, u32)> = Vec::new();
+            let end = run[run.len() - 1] + 1;
+            for x in run[0]..=end {
+                let height = if x < end { row_heights[&x] } else { 0 };
+                let mut start = x;
+                while let Some(&(top_start, top_height)) = stack.last() {
+                    if top_height < height {
+                        break;
+                    }
+                    stack.pop();
+                    let candidate = MaxRectangleResult {
+                        width: x - top_start,
+                        height: top_height,
+                        top_left_x: top_start,
+                        top_left_y: y + 1 - top_height,
+                    };
+                    if candidate.area() > best.area() {
+                        best = candidate;
+                    }
+                    start = top_start;
+                }
+                if height > 0 {
+                    stack.push((start, height));
+                }
+            }
+        }
 
-    while !unvisited.is_empty() {
-        let start = *unvisited.iter().next().unwrap();
-        let mut queue = VecDeque::new();
-        let mut cluster = vec![];
+        heights = row_heights;
+        previous_y = Some(y);
+    }
 
-        queue.push_back(start);
-        unvisited.remove(&start);
+    best
+}
+
+/// Largest square with at most a given number of unvisited tiles ("holes")
+#[derive(Serialize, Clone, Default)]
+pub struct HoledSquareResult {
+    pub size: u32,
+    pub top_left_x: u32,
+    pub top_left_y: u32,
+    pub missing_tiles: Vec<(u32, u32)>,
+}
+
+/// Most unvisited tiles allowed in a square with holes; each one widens the search
+pub const MAX_SQUARE_HOLES: u32 = 50;
+
+/// Calculate the largest square containing at most `max_holes` unvisited tiles
+/// (at most [`MAX_SQUARE_HOLES`])
+///
+/// A square with few enough holes contains a smaller one with few enough holes, so the
+/// size is found by binary search. Each size is checked by sliding a band of rows over
+/// the tiles and a window over the visited columns in that band.
+pub fn calculate_max_square_with_holes(
+    tile_coords: &[(u32, u32)],
+    max_holes: u32,
+) -> HoledSquareResult {
+    let max_holes = max_holes.min(MAX_SQUARE_HOLES);
+    let visited: HashSet<(u32, u32)> = tile_coords.iter().copied().collect();
+    if visited.is_empty() {
+        return HoledSquareResult::default();
+    }
+
+    // Without holes the Übersquadrat is a lower bound; a square can't have more tiles
+    // than there are visited tiles plus holes
+    let mut low = calculate_max_square_from_coords(tile_coords).size.max(1);
+    let mut high = ((visited.len() as u64 + max_holes as u64) as f64).sqrt() as u32 + 1;
+    let mut best = find_square_with_holes(&visited, low, max_holes).unwrap_or((0, 0));
+    while low + 1 < high {
+        let size = low + (high - low) / 2;
+        match find_square_with_holes(&visited, size, max_holes) {
+            Some(position) => {
+                low = size;
+                best = position;
+            }
+            None => high = size,
+        }
+    }
 
-        while let Some((x, y)) = queue.pop_front() {
-            cluster.push((x, y));
+    let (top_left_x, top_left_y) = best;
+    let missing_tiles = (top_left_y..top_left_y + low)
+        .flat_map(|y| (top_left_x..top_left_x + low).map(move |x| (x, y)))
+        .filter(|t| !visited.contains(t))
+        .collect();
+    HoledSquareResult {
+        size: low,
+        top_left_x,
+        top_left_y,
+        missing_tiles,
+    }
+}
 
-            // Check 4 orthogonal neighbors (only within surrounded tiles)
-            let mut neighbors = Vec::new();
-            if x > 0 {
-                neighbors.push((x - 1, y));
+/// Top-left tile of some `size`×`size` square with at most `max_holes` unvisited tiles
+fn find_square_with_holes(
+    visited: &HashSet<(u32, u32)>,
+    size: u32,
+    max_holes: u32,
+) -> Option<(u32, u32)> {
+    let needed = (size as u64 * size as u64).saturating_sub(max_holes as u64);
+    let mut found = None;
+    for_each_square(visited, size, |x, y, count| {
+        if count >= needed {
+            found = Some((x, y));
+        }
+        found.is_none()
+    });
+    found
+}
+
+/// Call `visit(top_left_x, top_left_y, visited_count)` for the `size`×`size` squares
+/// worth looking at, until it returns false
+///
+/// A best square can always be moved until its top row and left column contain a
+/// visited tile, so only those positions are tried: a band of rows slides over the
+/// tiles and a window over the visited columns in that band. Only visited tiles are
+/// looked at, never their bounding box.
+fn for_each_square(
+    visited: &HashSet<(u32, u32)>,
+    size: u32,
+    mut visit: impl FnMut(u32, u32, u64) -> bool,
+) {
+    let mut rows: Vec<(u32, u32)> = visited.iter().map(|&(x, y)| (y, x)).collect();
+    rows.sort_unstable();
+
+    let mut band_start = 0;
+    let mut band_end = 0;
+    let mut columns: HashMap<u32, u64> = HashMap::new();
+    let mut top_rows: Vec<u32> = rows.iter().map(|&(y, _)| y).collect();
+    top_rows.dedup();
+    for top in top_rows {
+        while band_end < rows.len() && rows[band_end].0 < top + size {
+            *columns.entry(rows[band_end].1).or_default() += 1;
+            band_end += 1;
+        }
+        while rows[band_start].0 < top {
+            let x = rows[band_start].1;
+            let count = columns.get_mut(&x).unwrap();
+            *count -= 1;
+            if *count == 0 {
+                columns.remove(&x);
             }
-            neighbors.push((x + 1, y));
-            if y > 0 {
-                neighbors.push((x, y - 1));
+            band_start += 1;
+        }
+
+        let mut xs: Vec<(u32, u64)> = columns.iter().map(|(&x, &c)| (x, c)).collect();
+        xs.sort_unstable();
+        let mut right = 0;
+        let mut sum = 0;
+        for left in 0..xs.len() {
+            while right < xs.len() && xs[right].0 < xs[left].0 + size {
+                sum += xs[right].1;
+                right += 1;
             }
-            neighbors.push((x, y + 1));
+            if !visit(xs[left].0, top, sum) {
+                return;
+            }
+            sum -= xs[left].1;
+        }
+    }
+}
+
+/// Übersquadrat and Yard kept up to date while tiles are added one by one
+///
+/// Replaying tiles in first-visit order with a full recalculation per step would be
+/// quadratic; here each added tile only touches its neighbourhood. The square uses a
+/// sparse DP (side of the largest square ending at each tile as bottom-right corner),
+/// the Yard a union-find over surrounded tiles. Tiles are never removed.
+#[derive(Default)]
+pub struct TileMetrics {
+    visited: HashSet<(u32, u32)>,
+    square_dp: HashMap<(u32, u32), u32>,
+    max_square: (u32, (u32, u32)), // (size, bottom-right tile)
+    // Surrounded tiles: parent in the union-find and size of the cluster at the root
+    yard_parent: HashMap<(u32, u32), (u32, u32)>,
+    yard_size: HashMap<(u32, u32), usize>,
+    max_cluster: usize,
+}
+
+impl TileMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn tile_count(&self) -> usize {
+        self.visited.len()
+    }
+
+    pub fn max_square(&self) -> MaxSquareResult {
+        let (size, (x, y)) = self.max_square;
+        MaxSquareResult {
+            size,
+            top_left_x: (x + 1).saturating_sub(size),
+            top_left_y: (y + 1).saturating_sub(size),
+        }
+    }
+
+    pub fn max_cluster_size(&self) -> usize {
+        self.max_cluster
+    }
+
+    /// Add tiles; already known tiles are ignored
+    pub fn add_tiles(&mut self, tiles: &[(u32, u32)]) {
+        let new_tiles: Vec<(u32, u32)> = tiles
+            .iter()
+            .copied()
+            .filter(|&t| self.visited.insert(t))
+            .collect();
+        if new_tiles.is_empty() {
+            return;
+        }
+        self.update_squares(&new_tiles);
+        self.update_yard(&new_tiles);
+    }
 
-            for neighbor in neighbors {
-                if unvisited.remove(&neighbor) {
-                    queue.push_back(neighbor);
+    /// Recompute the DP for the new tiles and everything right/below that changes with them
+    fn update_squares(&mut self, new_tiles: &[(u32, u32)]) {
+        use std::collections::BTreeSet;
+
+        // Row-major order (y, x) processes left, upper and upper-left neighbours first
+        let mut pending: BTreeSet<(u32, u32)> = new_tiles.iter().map(|&(x, y)| (y, x)).collect();
+        while let Some((y, x)) = pending.pop_first() {
+            let dp = |tile: Option<(u32, u32)>| {
+                tile.and_then(|t| self.square_dp.get(&t))
+                    .copied()
+                    .unwrap_or(0)
+            };
+            let left = dp(x.checked_sub(1).map(|x| (x, y)));
+            let up = dp(y.checked_sub(1).map(|y| (x, y)));
+            let up_left = dp(x.checked_sub(1).zip(y.checked_sub(1)));
+            let size = left.min(up).min(up_left) + 1;
+
+            if self.square_dp.insert((x, y), size) == Some(size) {
+                continue;
+            }
+            if size > self.max_square.0 {
+                self.max_square = (size, (x, y));
+            }
+            for next in [(x + 1, y), (x, y + 1), (x + 1, y + 1)] {
+                if self.visited.contains(&next) {
+                    pending.insert((next.1, next.0));
                 }
             }
         }
+    }
 
-        if cluster.len() > max_cluster.len() {
-            max_cluster = cluster;
+    /// Surround status only changes for the new tiles and their neighbours
+    fn update_yard(&mut self, new_tiles: &[(u32, u32)]) {
+        let mut candidates: Vec<(u32, u32)> = new_tiles
+            .iter()
+            .flat_map(|&t| std::iter::once(t).chain(neighbours(t)))
+            .filter(|t| self.visited.contains(t) && !self.yard_parent.contains_key(t))
+            .collect();
+        candidates.sort_unstable();
+        candidates.dedup();
+
+        for tile in candidates {
+            if neighbours(tile)
+                .filter(|n| self.visited.contains(n))
+                .count()
+                < 4
+            {
+                continue;
+            }
+            self.yard_parent.insert(tile, tile);
+            self.yard_size.insert(tile, 1);
+            self.max_cluster = self.max_cluster.max(1);
+            for n in neighbours(tile) {
+                if self.yard_parent.contains_key(&n) {
+                    self.union(tile, n);
+                }
+            }
         }
     }
 
-    MaxClusterResult {
-        size: max_cluster.len(),
-        tiles: max_cluster,
+    fn find(&mut self, tile: (u32, u32)) -> (u32, u32) {
+        let mut root = tile;
+        while self.yard_parent[&root] != root {
+            root = self.yard_parent[&root];
+        }
+        // Path compression
+        let mut current = tile;
+        while current != root {
+            let next = self.yard_parent[&current];
+            self.yard_parent.insert(current, root);
+            current = next;
+        }
+        root
+    }
+
+    fn union(&mut self, a: (u32, u32), b: (u32, u32)) {
+        let (root_a, root_b) = (self.find(a), self.find(b));
+        if root_a == root_b {
+            return;
+        }
+        let size = self.yard_size[&root_a] + self.yard_size[&root_b];
+        self.yard_parent.insert(root_b, root_a);
+        self.yard_size.remove(&root_b);
+        self.yard_size.insert(root_a, size);
+        self.max_cluster = self.max_cluster.max(size);
     }
 }
 
-/// Calculate the largest square (Übersquadrat) within a set of tiles
-/// This should be called with the tiles from the max cluster (Yard)
-pub fn calculate_max_square_from_coords(tile_coords: &[(u32, u32)]) -> MaxSquareResult {
-    use std::collections::HashSet;
+/// A position where the Übersquadrat could grow by one, with the tiles still missing
+#[derive(Serialize, Clone)]
+pub struct SquareSuggestion {
+    pub size: u32,
+    pub top_left_x: u32,
+    pub top_left_y: u32,
+    pub missing_tiles: Vec<(u32, u32)>,
+}
 
-    if tile_coords.is_empty() {
-        return MaxSquareResult {
-            size: 0,
-            top_left_x: 0,
-            top_left_y: 0,
-        };
+/// List the positions of a square one larger than the current Übersquadrat, ranked by
+/// how few unvisited tiles they contain
+///
+/// Positions are enumerated like for the square with holes, and only the best `limit`
+/// are kept, so memory grows with the number of tiles and not with their bounding box.
+pub fn suggest_square_growth(tile_coords: &[(u32, u32)], limit: usize) -> Vec<SquareSuggestion> {
+    use std::collections::BinaryHeap;
+
+    if tile_coords.is_empty() || limit == 0 {
+        return Vec::new();
     }
 
-    // Create a set of tiles for O(1) lookup
     let visited: HashSet<(u32, u32)> = tile_coords.iter().copied().collect();
+    let target = calculate_max_square_from_coords(tile_coords).size + 1;
+    let area = target as u64 * target as u64;
+
+    // Max-heap of (missing, x, y): the worst kept candidate is dropped first
+    let mut candidates: BinaryHeap<(u64, u32, u32)> = BinaryHeap::new();
+    for_each_square(&visited, target, |x, y, count| {
+        candidates.push((area - count, x, y));
+        if candidates.len() > limit {
+            candidates.pop();
+        }
+        true
+    });
 
-    // Find bounds
-    let min_x = tile_coords.iter().map(|(x, _)| *x).min().unwrap();
-    let max_x = tile_coords.iter().map(|(x, _)| *x).max().unwrap();
-    let min_y = tile_coords.iter().map(|(_, y)| *y).min().unwrap();
-    let max_y = tile_coords.iter().map(|(_, y)| *y).max().unwrap();
+    candidates
+        .into_sorted_vec()
+        .into_iter()
+        .map(|(_, top_left_x, top_left_y)| {
+            let missing_tiles = (top_left_y..top_left_y + target)
+                .flat_map(|y| (top_left_x..top_left_x + target).map(move |x| (x, y)))
+                .filter(|tile| !visited.contains(tile))
+                .collect();
+            SquareSuggestion {
+                size: target,
+                top_left_x,
+                top_left_y,
+                missing_tiles,
+            }
+        })
+        .collect()
+}
 
-    let width = (max_x - min_x + 1) as usize;
-    let height = (max_y - min_y + 1) as usize;
+/// An unvisited tile and what visiting it would do to the Yard
+#[derive(Serialize, Clone)]
+pub struct YardSuggestion {
+    pub x: u32,
+    pub y: u32,
+    /// Tiles that would become surrounded on all 4 sides (including this one)
+    pub new_yard_tiles: usize,
+    /// Size of the largest cluster afterwards
+    pub yard_size_after: usize,
+}
 
-    // DP table for largest square ending at each cell
-    let mut dp = vec![vec![0u32; width]; height];
-    let mut max_size = 0u32;
-    let mut max_pos = (min_x, min_y);
+fn neighbours((x, y): (u32, u32)) -> impl Iterator<Item = (u32, u32)> {
+    [
+        x.checked_sub(1).map(|x| (x, y)),
+        Some((x + 1, y)),
+        y.checked_sub(1).map(|y| (x, y)),
+        Some((x, y + 1)),
+    ]
+    .into_iter()
+    .flatten()
+}
 
-    for y in 0..height {
-        for x in 0..width {
-            let abs_x = min_x + x as u32;
-            let abs_y = min_y + y as u32;
+/// Representative of a union-find group
+fn group_root(group: &[usize], mut i: usize) -> usize {
+    while group[i] != i {
+        i = group[i];
+    }
+    i
+}
 
-            if visited.contains(&(abs_x, abs_y)) {
-                if x == 0 || y == 0 {
-                    dp[y][x] = 1;
-                } else {
-                    dp[y][x] = dp[y - 1][x].min(dp[y][x - 1]).min(dp[y - 1][x - 1]) + 1;
-                }
+/// List unvisited tiles that would add the most tiles to the Yard, best first
+///
+/// A visited tile joins the Yard once all 4 neighbours are visited, so visiting a tile can
+/// complete its neighbours and itself, and may connect separate clusters.
+pub fn suggest_yard_growth(tile_coords: &[(u32, u32)], limit: usize) -> Vec<YardSuggestion> {
+    use std::collections::{HashSet, VecDeque};
 
-                if dp[y][x] > max_size {
-                    max_size = dp[y][x];
-                    // Top-left corner of the square
-                    max_pos = (abs_x - max_size + 1, abs_y - max_size + 1);
+    let visited: HashSet<(u32, u32)> = tile_coords.iter().copied().collect();
+    let is_surrounded = |tile: (u32, u32), visited: &dyn Fn((u32, u32)) -> bool| {
+        neighbours(tile).filter(|n| visited(*n)).count() == 4
+    };
+
+    // Label the clusters of surrounded tiles
+    let surrounded: HashSet<(u32, u32)> = visited
+        .iter()
+        .copied()
+        .filter(|&t| is_surrounded(t, &|n| visited.contains(&n)))
+        .collect();
+    let mut cluster_of: HashMap<(u32, u32), usize> = HashMap::new();
+    let mut cluster_sizes: Vec<usize> = Vec::new();
+    for &start in &surrounded {
+        if cluster_of.contains_key(&start) {
+            continue;
+        }
+        let id = cluster_sizes.len();
+        let mut size = 0;
+        let mut queue = VecDeque::from([start]);
+        cluster_of.insert(start, id);
+        while let Some(tile) = queue.pop_front() {
+            size += 1;
+            for n in neighbours(tile) {
+                if surrounded.contains(&n) && !cluster_of.contains_key(&n) {
+                    cluster_of.insert(n, id);
+                    queue.push_back(n);
                 }
             }
         }
+        cluster_sizes.push(size);
     }
+    let current_yard = cluster_sizes.iter().copied().max().unwrap_or(0);
 
-    MaxSquareResult {
-        size: max_size,
-        top_left_x: max_pos.0,
-        top_left_y: max_pos.1,
-    }
+    // Candidates: unvisited tiles next to visited ones
+    let frontier: HashSet<(u32, u32)> = visited
+        .iter()
+        .flat_map(|&t| neighbours(t))
+        .filter(|n| !visited.contains(n))
+        .collect();
+
+    let mut suggestions: Vec<YardSuggestion> = frontier
+        .into_iter()
+        .filter_map(|candidate| {
+            let now_visited = |n: (u32, u32)| n == candidate || visited.contains(&n);
+            let new_tiles: Vec<(u32, u32)> = std::iter::once(candidate)
+                .chain(neighbours(candidate))
+                .filter(|&t| now_visited(t) && !surrounded.contains(&t))
+                .filter(|&t| is_surrounded(t, &now_visited))
+                .collect();
+            if new_tiles.is_empty() {
+                return None;
+            }
+
+            // Join new tiles that touch each other or the same existing cluster
+            let mut group: Vec<usize> = (0..new_tiles.len()).collect();
+            let touching: Vec<HashSet<usize>> = new_tiles
+                .iter()
+                .map(|&t| {
+                    neighbours(t)
+                        .filter_map(|n| cluster_of.get(&n).copied())
+                        .collect()
+                })
+                .collect();
+            for i in 0..new_tiles.len() {
+                for j in i + 1..new_tiles.len() {
+                    let adjacent = neighbours(new_tiles[i]).any(|n| n == new_tiles[j]);
+                    if adjacent || !touching[i].is_disjoint(&touching[j]) {
+                        let (a, b) = (group_root(&group, i), group_root(&group, j));
+                        group[a] = b;
+                    }
+                }
+            }
+            let mut merged: HashMap<usize, (usize, HashSet<usize>)> = HashMap::new();
+            for (i, clusters) in touching.iter().enumerate() {
+                let entry = merged.entry(group_root(&group, i)).or_default();
+                entry.0 += 1;
+                entry.1.extend(clusters);
+            }
+            let largest = merged
+                .values()
+                .map(|(count, clusters)| {
+                    count + clusters.iter().map(|&c| cluster_sizes[c]).sum::<usize>()
+                })
+                .max()
+                .unwrap_or(0);
+
+            Some(YardSuggestion {
+                x: candidate.0,
+                y: candidate.1,
+                new_yard_tiles: new_tiles.len(),
+                yard_size_after: largest.max(current_yard),
+            })
+        })
+        .collect();
+
+    suggestions.sort_by(|a, b| {
+        b.yard_size_after
+            .cmp(&a.yard_size_after)
+            .then(b.new_yard_tiles.cmp(&a.new_yard_tiles))
+            .then((a.x, a.y).cmp(&(b.x, b.y)))
+    });
+    suggestions.truncate(limit);
+    suggestions
 }
 
 /// Convert tile coordinates to lat/lon bounds
@@ -560,3 +1398,447 @@ pub fn tile_to_bounds(x: u32, y: u32, zoom: u32) -> (f64, f64, f64, f64) {
 
     (lat_min, lon_min, lat_max, lon_max)
 }
+
+/// Center of a tile as (lat, lon)
+pub fn tile_center(x: u32, y: u32, zoom: u32) -> (f64, f64) {
+    let n = 2_u32.pow(zoom) as f64;
+    let lon = (x as f64 + 0.5) / n * 360.0 - 180.0;
+    let lat = (std::f64::consts::PI * (1.0 - 2.0 * (y as f64 + 0.5) / n))
+        .sinh()
+        .atan()
+        .to_degrees();
+    (lat, lon)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Small deterministic pseudo-random generator, so failures can be reproduced
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn next(&mut self, bound: u32) -> u32 {
+            self.0 = self
+                .0
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            ((self.0 >> 33) % bound as u64) as u32
+        }
+    }
+
+    /// Random tiles on a `size`×`size` grid, `percent` of them visited, starting at 1 so
+    /// the grid has room to the left and top
+    fn random_grid(seed: u64, size: u32, percent: u32) -> Vec<(u32, u32)> {
+        let mut rng = Lcg(seed);
+        (1..=size)
+            .flat_map(|y| (1..=size).map(move |x| (x, y)))
+            .filter(|_| rng.next(100) < percent)
+            .collect()
+    }
+
+    fn brute_max_square(visited: &HashSet<(u32, u32)>) -> u32 {
+        let mut best = 0;
+        for &(x, y) in visited {
+            let mut size = 1;
+            while (0..size + 1).all(|i| {
+                visited.contains(&(x + i, y + size)) && visited.contains(&(x + size, y + i))
+            }) {
+                size += 1;
+            }
+            best = best.max(size);
+        }
+        best
+    }
+
+    /// Surrounded tiles grouped by flood fill, largest group first
+    fn brute_yards(visited: &HashSet<(u32, u32)>) -> Vec<HashSet<(u32, u32)>> {
+        let surrounded: HashSet<(u32, u32)> = visited
+            .iter()
+            .copied()
+            .filter(|&(x, y)| {
+                x > 0
+                    && y > 0
+                    && [(x - 1, y), (x + 1, y), (x, y - 1), (x, y + 1)]
+                        .iter()
+                        .all(|n| visited.contains(n))
+            })
+            .collect();
+        let mut groups: Vec<HashSet<(u32, u32)>> = Vec::new();
+        for &tile in &surrounded {
+            if groups.iter().any(|g| g.contains(&tile)) {
+                continue;
+            }
+            let mut group = HashSet::from([tile]);
+            let mut stack = vec![tile];
+            while let Some((x, y)) = stack.pop() {
+                for n in [
+                    (x.wrapping_sub(1), y),
+                    (x + 1, y),
+                    (x, y.wrapping_sub(1)),
+                    (x, y + 1),
+                ] {
+                    if surrounded.contains(&n) && group.insert(n) {
+                        stack.push(n);
+                    }
+                }
+            }
+            groups.push(group);
+        }
+        groups.sort_by_key(|g| std::cmp::Reverse(g.len()));
+        groups
+    }
+
+    fn brute_max_rectangle(visited: &HashSet<(u32, u32)>) -> u64 {
+        let mut best = 0;
+        for &(x, y) in visited {
+            // Widest run to the right for every height
+            let mut width = u32::MAX;
+            let mut height = 0;
+            while visited.contains(&(x, y + height)) {
+                let run = (0..)
+                    .take_while(|i| visited.contains(&(x + i, y + height)))
+                    .count();
+                width = width.min(run as u32);
+                height += 1;
+                best = best.max(width as u64 * height as u64);
+            }
+        }
+        best
+    }
+
+    fn brute_max_square_with_holes(visited: &HashSet<(u32, u32)>, size: u32, holes: u32) -> u32 {
+        let mut best = 0;
+        for top in 0..=size {
+            for left in 0..=size {
+                for side in best + 1..=size + 1 {
+                    let missing = (top..top + side)
+                        .flat_map(|y| (left..left + side).map(move |x| (x, y)))
+                        .filter(|t| !visited.contains(t))
+                        .count();
+                    if missing as u32 <= holes && missing < (side * side) as usize {
+                        best = side;
+                    }
+                }
+            }
+        }
+        best
+    }
+
+    fn is_full_square(visited: &HashSet<(u32, u32)>, square: &MaxSquareResult) -> bool {
+        (square.top_left_y..square.top_left_y + square.size).all(|y| {
+            (square.top_left_x..square.top_left_x + square.size).all(|x| visited.contains(&(x, y)))
+        })
+    }
+
+    #[test]
+    fn tile_metrics_match_brute_force_while_adding() {
+        for seed in 0..20 {
+            let tiles = random_grid(seed, 12, 55 + seed as u32);
+            let mut metrics = TileMetrics::new();
+            let mut visited = HashSet::new();
+            // Added in uneven batches, in a scrambled order
+            let mut rng = Lcg(seed + 100);
+            let mut order = tiles.clone();
+            for i in (1..order.len()).rev() {
+                order.swap(i, rng.next(i as u32 + 1) as usize);
+            }
+            for batch in order.chunks(1 + seed as usize % 7) {
+                metrics.add_tiles(batch);
+                visited.extend(batch.iter().copied());
+
+                assert_eq!(metrics.tile_count(), visited.len());
+                let square = metrics.max_square();
+                assert_eq!(square.size, brute_max_square(&visited), "seed {}", seed);
+                assert!(is_full_square(&visited, &square), "seed {}", seed);
+                let yard = brute_yards(&visited).first().map_or(0, |g| g.len());
+                assert_eq!(metrics.max_cluster_size(), yard, "seed {}", seed);
+            }
+        }
+    }
+
+    #[test]
+    fn tile_metrics_ignore_known_tiles() {
+        let mut metrics = TileMetrics::new();
+        let square: Vec<(u32, u32)> = (0..3).flat_map(|y| (0..3).map(move |x| (x, y))).collect();
+        metrics.add_tiles(&square);
+        metrics.add_tiles(&square);
+        assert_eq!(metrics.tile_count(), 9);
+        assert_eq!(metrics.max_square().size, 3);
+        assert_eq!(metrics.max_cluster_size(), 1);
+    }
+
+    #[test]
+    fn connected_components_are_largest_first() {
+        let tiles: HashSet<(u32, u32)> =
+            HashSet::from([(0, 0), (1, 0), (1, 1), (5, 5), (7, 7), (7, 8)]);
+        let components = connected_components(&tiles);
+        let sizes: Vec<usize> = components.iter().map(|c| c.len()).collect();
+        assert_eq!(sizes, vec![3, 2, 1]);
+        // Diagonal neighbours are not connected
+        assert!(connected_components(&HashSet::from([(0, 0), (1, 1)]))
+            .iter()
+            .all(|c| c.len() == 1));
+    }
+
+    #[test]
+    fn clusters_match_brute_force() {
+        for seed in 0..20 {
+            let tiles = random_grid(seed, 12, 70);
+            let visited: HashSet<(u32, u32)> = tiles.iter().copied().collect();
+            let infos: Vec<TileInfo> = tiles
+                .iter()
+                .map(|&(x, y)| TileInfo {
+                    x,
+                    y,
+                    z: TILE_ZOOM,
+                    first_visited_at: 0,
+                    activity_id: None,
+                    activity_title: None,
+                    gpx_filename: None,
+                })
+                .collect();
+            let expected = brute_yards(&visited);
+            let overview = calculate_clusters(&infos);
+            let sizes: Vec<usize> = overview.clusters.iter().map(|c| c.size).collect();
+            let expected_sizes: Vec<usize> = expected.iter().map(|g| g.len()).collect();
+            assert_eq!(sizes, expected_sizes, "seed {}", seed);
+
+            // The first cluster is the Yard
+            if let (Some(largest), Some(yard)) = (expected.first(), overview.clusters.first()) {
+                let yard_tiles: HashSet<(u32, u32)> = yard.tiles.iter().copied().collect();
+                assert!(
+                    expected
+                        .iter()
+                        .any(|g| g.len() == largest.len() && *g == yard_tiles),
+                    "seed {}",
+                    seed
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn max_rectangle_matches_brute_force() {
+        for seed in 0..30 {
+            let tiles = random_grid(seed, 10, 60 + seed as u32);
+            let visited: HashSet<(u32, u32)> = tiles.iter().copied().collect();
+            let rectangle = calculate_max_rectangle(&tiles);
+            assert_eq!(
+                rectangle.area(),
+                brute_max_rectangle(&visited),
+                "seed {}",
+                seed
+            );
+            assert!(
+                (rectangle.top_left_y..rectangle.top_left_y + rectangle.height).all(|y| {
+                    (rectangle.top_left_x..rectangle.top_left_x + rectangle.width)
+                        .all(|x| visited.contains(&(x, y)))
+                })
+            );
+        }
+        assert_eq!(calculate_max_rectangle(&[]).area(), 0);
+    }
+
+    #[test]
+    fn square_with_holes_matches_brute_force() {
+        for seed in 0..15 {
+            let tiles = random_grid(seed, 8, 75);
+            let visited: HashSet<(u32, u32)> = tiles.iter().copied().collect();
+            for holes in 0..4 {
+                let result = calculate_max_square_with_holes(&tiles, holes);
+                assert_eq!(
+                    result.size,
+                    brute_max_square_with_holes(&visited, 8, holes),
+                    "seed {} holes {}",
+                    seed,
+                    holes
+                );
+                assert!(result.missing_tiles.len() as u32 <= holes);
+                assert!(result.missing_tiles.iter().all(|t| !visited.contains(t)));
+            }
+        }
+    }
+
+    #[test]
+    fn square_with_holes_caps_the_holes() {
+        // A lone tile: with unlimited holes any square would do
+        let result = calculate_max_square_with_holes(&[(10, 10)], u32::MAX);
+        assert!(result.missing_tiles.len() as u32 <= MAX_SQUARE_HOLES);
+    }
+
+    #[test]
+    fn square_suggestions_find_the_fewest_missing_tiles() {
+        for seed in 0..15 {
+            let tiles = random_grid(seed, 10, 65);
+            let visited: HashSet<(u32, u32)> = tiles.iter().copied().collect();
+            let target = brute_max_square(&visited) + 1;
+            let fewest_missing = (0..=10)
+                .flat_map(|y| (0..=10).map(move |x| (x, y)))
+                .map(|(left, top)| {
+                    (top..top + target)
+                        .flat_map(|y| (left..left + target).map(move |x| (x, y)))
+                        .filter(|t| !visited.contains(t))
+                        .count()
+                })
+                .min()
+                .unwrap();
+
+            let suggestions = suggest_square_growth(&tiles, 5);
+            assert_eq!(suggestions.len(), 5, "seed {}", seed);
+            assert!(suggestions.iter().all(|s| s.size == target));
+            assert_eq!(
+                suggestions[0].missing_tiles.len(),
+                fewest_missing,
+                "seed {}",
+                seed
+            );
+            assert!(suggestions
+                .windows(2)
+                .all(|w| w[0].missing_tiles.len() <= w[1].missing_tiles.len()));
+        }
+    }
+
+    fn test_db() -> Connection {
+        database::setup_db(Connection::open_in_memory().unwrap()).unwrap()
+    }
+
+    fn insert_file(conn: &mut Connection, filename: &str, visited_at: i64, tiles: &[(u32, u32)]) {
+        let data: Vec<database::TileData> = tiles
+            .iter()
+            .map(|&(x, y)| database::TileData {
+                x,
+                y,
+                z: TILE_ZOOM,
+                visited_at,
+                activity_id: filename.to_string(),
+                activity_title: filename.to_string(),
+                gpx_filename: filename.to_string(),
+            })
+            .collect();
+        database::insert_tiles_batch(conn, &data).unwrap();
+    }
+
+    fn assert_db_metrics(conn: &mut Connection, visited: &HashSet<(u32, u32)>, context: &str) {
+        let summary = get_tile_metrics(conn, TILE_ZOOM);
+        assert_eq!(summary.tile_count, visited.len(), "{}", context);
+        assert_eq!(
+            summary.max_square.size,
+            brute_max_square(visited),
+            "{}",
+            context
+        );
+        assert!(is_full_square(visited, &summary.max_square), "{}", context);
+
+        let yards = brute_yards(visited);
+        let yard_size = yards.first().map_or(0, |g| g.len());
+        assert_eq!(summary.max_cluster.size, yard_size, "{}", context);
+        let yard_tiles: HashSet<(u32, u32)> = summary.max_cluster.tiles.iter().copied().collect();
+        assert!(yard_size == 0 || yards.contains(&yard_tiles), "{}", context);
+    }
+
+    #[test]
+    fn stored_metrics_match_brute_force_after_adding_and_removing() {
+        for seed in 0..8 {
+            let mut conn = test_db();
+            let files: Vec<(String, Vec<(u32, u32)>)> = (0..4)
+                .map(|i| (format!("{}.gpx", i), random_grid(seed * 10 + i, 10, 45)))
+                .collect();
+
+            let mut visited = HashSet::new();
+            for (i, (name, tiles)) in files.iter().enumerate() {
+                insert_file(&mut conn, name, 1000 + i as i64, tiles);
+                visited.extend(tiles.iter().copied());
+                assert_db_metrics(&mut conn, &visited, &format!("seed {} add {}", seed, name));
+            }
+
+            for (removed, (name, _)) in files.iter().enumerate().take(3) {
+                database::remove_file_tiles(&mut conn, name).unwrap();
+                let visited: HashSet<(u32, u32)> = files[removed + 1..]
+                    .iter()
+                    .flat_map(|(_, tiles)| tiles.iter().copied())
+                    .collect();
+                assert_db_metrics(
+                    &mut conn,
+                    &visited,
+                    &format!("seed {} remove {}", seed, name),
+                );
+            }
+        }
+    }
+
+    fn write_track(path: &Path, lat: f64) {
+        fs::write(
+            path,
+            format!(
+                r#"<?xml version="1.0"?><gpx version="1.1"><trk><name>Ride</name><trkseg>
+<trkpt lat="{}" lon="13.7"><time>2024-05-01T10:00:00Z</time></trkpt>
+</trkseg></trk></gpx>"#,
+                lat
+            ),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn track_files_are_hashed_only_when_size_or_time_changed() {
+        let dir = std::env::temp_dir().join(format!("rust_strava_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("activity_1.gpx");
+        write_track(&path, 51.0);
+        let mut conn = test_db();
+        let options = TileOptions::default();
+
+        assert!(process_track_file(&mut conn, &path, &options).unwrap() > 0);
+        assert_eq!(process_track_file(&mut conn, &path, &options).unwrap(), 0);
+        let stored = database::get_file_version(&conn, "activity_1.gpx")
+            .unwrap()
+            .unwrap();
+        assert!(stored.content_hash.is_some() && stored.size.is_some());
+
+        // With size and time unchanged the stored hash is trusted, even a wrong one
+        let trusted = database::FileVersion {
+            content_hash: Some("not the hash".to_string()),
+            ..stored.clone()
+        };
+        database::set_file_version(&conn, "activity_1.gpx", &trusted).unwrap();
+        assert_eq!(process_track_file(&mut conn, &path, &options).unwrap(), 0);
+
+        // Same size, new content and time: hashed and reprocessed
+        database::set_file_version(&conn, "activity_1.gpx", &stored).unwrap();
+        write_track(&path, 52.0);
+        let modified = fs::File::options().write(true).open(&path).unwrap();
+        modified
+            .set_modified(std::time::SystemTime::now() + std::time::Duration::from_secs(5))
+            .unwrap();
+        assert!(process_track_file(&mut conn, &path, &options).unwrap() > 0);
+        let tiles = database::get_all_tiles(&conn, TILE_ZOOM).unwrap();
+        assert_eq!(tiles.len(), 1);
+        assert_eq!(tiles[0].y, lat_lon_to_tile(52.0, 13.7, TILE_ZOOM).1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn track_totals_only_fill_in_missing_ones() {
+        let conn = test_db();
+        for (activity_id, csv_km) in [(1, 20.0), (2, 0.0)] {
+            let activity = database::ActivityMetadata {
+                activity_id,
+                activity_name: None,
+                activity_type: None,
+                start_date: None,
+                moving_time_s: None,
+                gear: None,
+                distance_km: csv_km,
+                elevation_gain_m: 0,
+            };
+            database::save_activity_metadata(&conn, &activity).unwrap();
+            database::mark_activity_imported(&conn, activity_id, None, 12.5, 300).unwrap();
+        }
+        let first = database::get_activity(&conn, 1).unwrap().unwrap();
+        assert_eq!((first.distance_km, first.elevation_gain_m), (20.0, 300));
+        let second = database::get_activity(&conn, 2).unwrap().unwrap();
+        assert_eq!((second.distance_km, second.elevation_gain_m), (12.5, 300));
+    }
+}
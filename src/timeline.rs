@@ -0,0 +1,208 @@
+use chrono::DateTime;
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::database;
+use crate::tiles::TileMetrics;
+
+/// Granularity of the timeline
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TimelineStep {
+    #[default]
+    Activity,
+    Day,
+}
+
+/// Activity that first visited a group of tiles
+#[derive(Debug, Clone, Serialize)]
+pub struct ActivityRef {
+    pub activity_id: Option<String>,
+    pub activity_title: Option<String>,
+    pub gpx_filename: Option<String>,
+}
+
+/// State after one step of the replay
+#[derive(Debug, Serialize)]
+pub struct TimelineEntry {
+    /// First visit of the step's first tile (Unix timestamp in seconds)
+    pub time: i64,
+    /// Day of the step in UTC (YYYY-MM-DD), empty for tiles without time
+    pub date: String,
+    /// Activities in this step, oldest first (exactly one when stepping per activity)
+    pub activities: Vec<ActivityRef>,
+    pub new_tiles: usize,
+    pub tile_count: usize,
+    pub max_square: u32,
+    pub max_cluster: usize,
+    /// Activity whose tiles made the Übersquadrat grow in this step
+    pub square_grown_by: Option<ActivityRef>,
+    /// Activity whose tiles made the Yard grow in this step
+    pub cluster_grown_by: Option<ActivityRef>,
+}
+
+/// Tiles first visited by one activity
+struct ActivityTiles {
+    activity: ActivityRef,
+    time: i64,
+    tiles: Vec<(u32, u32)>,
+}
+
+/// Replay all tiles at a zoom level in first-visit order
+///
+/// Tiles are grouped by the activity that first visited them; activities are replayed
+/// by their earliest tile. With `TimelineStep::Day`, activities of the same day are
+/// combined into one entry.
+pub fn build_timeline(
+    conn: &Connection,
+    zoom: u32,
+    step: TimelineStep,
+) -> Result<Vec<TimelineEntry>, String> {
+    let records = database::get_all_tiles(conn, zoom).map_err(|e| e.to_string())?;
+
+    let mut groups: HashMap<Option<String>, ActivityTiles> = HashMap::new();
+    for record in records {
+        let group = groups
+            .entry(record.gpx_filename.clone())
+            .or_insert_with(|| ActivityTiles {
+                activity: ActivityRef {
+                    activity_id: record.activity_id.clone(),
+                    activity_title: record.activity_title.clone(),
+                    gpx_filename: record.gpx_filename.clone(),
+                },
+                time: record.first_visited_at,
+                tiles: Vec::new(),
+            });
+        group.time = group.time.min(record.first_visited_at);
+        group.tiles.push((record.x, record.y));
+    }
+    let mut groups: Vec<ActivityTiles> = groups.into_values().collect();
+    groups.sort_by(|a, b| {
+        (a.time, &a.activity.gpx_filename).cmp(&(b.time, &b.activity.gpx_filename))
+    });
+
+    let mut metrics = TileMetrics::new();
+    let mut entries: Vec<TimelineEntry> = Vec::new();
+    for group in groups {
+        let square_before = metrics.max_square().size;
+        let cluster_before = metrics.max_cluster_size();
+        metrics.add_tiles(&group.tiles);
+        let square_grew = metrics.max_square().size > square_before;
+        let cluster_grew = metrics.max_cluster_size() > cluster_before;
+
+        let date = DateTime::from_timestamp(group.time, 0)
+            .filter(|_| group.time > 0)
+            .map(|t| t.format("%Y-%m-%d").to_string())
+            .unwrap_or_default();
+
+        let entry = match entries.last_mut() {
+            Some(last) if step == TimelineStep::Day && last.date == date => last,
+            _ => {
+                entries.push(TimelineEntry {
+                    time: group.time,
+                    date,
+                    activities: Vec::new(),
+                    new_tiles: 0,
+                    tile_count: 0,
+                    max_square: 0,
+                    max_cluster: 0,
+                    square_grown_by: None,
+                    cluster_grown_by: None,
+                });
+                entries.last_mut().unwrap()
+            }
+        };
+        entry.new_tiles += group.tiles.len();
+        entry.tile_count = metrics.tile_count();
+        entry.max_square = metrics.max_square().size;
+        entry.max_cluster = metrics.max_cluster_size();
+        // Within a day the last activity that grew a value reached the final size
+        if square_grew {
+            entry.square_grown_by = Some(group.activity.clone());
+        }
+        if cluster_grew {
+            entry.cluster_grown_by = Some(group.activity.clone());
+        }
+        entry.activities.push(group.activity);
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tiles::TILE_ZOOM;
+
+    fn insert_file(conn: &mut Connection, filename: &str, visited_at: i64, tiles: &[(u32, u32)]) {
+        let data: Vec<database::TileData> = tiles
+            .iter()
+            .map(|&(x, y)| database::TileData {
+                x,
+                y,
+                z: TILE_ZOOM,
+                visited_at,
+                activity_id: filename.to_string(),
+                activity_title: filename.to_string(),
+                gpx_filename: filename.to_string(),
+            })
+            .collect();
+        database::insert_tiles_batch(conn, &data).unwrap();
+    }
+
+    #[test]
+    fn replays_activities_in_first_visit_order() {
+        let mut conn = database::setup_db(Connection::open_in_memory().unwrap()).unwrap();
+        const DAY: i64 = 86_400;
+        // Inserted out of order; the second ride completes a 2×2 square, the third
+        // (same day) a 3×3 square around (2, 2), the only surrounded tile
+        insert_file(
+            &mut conn,
+            "c.gpx",
+            10 * DAY + 60,
+            &[(3, 1), (3, 2), (3, 3), (1, 3), (2, 3)],
+        );
+        insert_file(&mut conn, "a.gpx", DAY, &[(1, 1), (2, 1)]);
+        insert_file(&mut conn, "b.gpx", 10 * DAY, &[(1, 2), (2, 2), (2, 1)]);
+
+        let entries = build_timeline(&conn, TILE_ZOOM, TimelineStep::Activity).unwrap();
+        let files: Vec<&str> = entries
+            .iter()
+            .map(|e| e.activities[0].gpx_filename.as_deref().unwrap())
+            .collect();
+        assert_eq!(files, vec!["a.gpx", "b.gpx", "c.gpx"]);
+        // (2, 1) stays with the earlier visit
+        let new_tiles: Vec<usize> = entries.iter().map(|e| e.new_tiles).collect();
+        assert_eq!(new_tiles, vec![2, 2, 5]);
+        let squares: Vec<u32> = entries.iter().map(|e| e.max_square).collect();
+        assert_eq!(squares, vec![1, 2, 3]);
+        assert_eq!(entries[2].tile_count, 9);
+        assert_eq!(entries[2].max_cluster, 1);
+        assert!(entries[0].square_grown_by.is_some());
+        assert_eq!(
+            entries[2]
+                .cluster_grown_by
+                .as_ref()
+                .unwrap()
+                .gpx_filename
+                .as_deref(),
+            Some("c.gpx")
+        );
+
+        let days = build_timeline(&conn, TILE_ZOOM, TimelineStep::Day).unwrap();
+        assert_eq!(days.len(), 2);
+        assert_eq!(days[1].activities.len(), 2);
+        assert_eq!(days[1].new_tiles, 7);
+        assert_eq!(days[1].max_square, 3);
+        assert_eq!(
+            days[1]
+                .square_grown_by
+                .as_ref()
+                .unwrap()
+                .gpx_filename
+                .as_deref(),
+            Some("c.gpx")
+        );
+    }
+}
@@ -0,0 +1,135 @@
+use flate2::read::GzDecoder;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use crate::fit;
+use crate::gpx::{self, Gpx};
+use crate::tcx;
+
+/// Supported track file formats in the import directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackFormat {
+    Gpx,
+    Tcx,
+    Fit,
+}
+
+impl TrackFormat {
+    /// Detect the format from a file name (case-insensitive, optionally gzip compressed)
+    pub fn from_filename(filename: &str) -> Option<TrackFormat> {
+        split_filename(filename).map(|(_, format, _)| format)
+    }
+
+    /// Content type of the file as served to the map (FIT is converted to GPX)
+    pub fn content_type(self) -> &'static str {
+        match self {
+            TrackFormat::Gpx | TrackFormat::Fit => "application/gpx+xml",
+            TrackFormat::Tcx => "application/vnd.garmin.tcx+xml",
+        }
+    }
+}
+
+/// Split "activity_123.fit.gz" into ("activity_123", Fit, compressed)
+fn split_filename(filename: &str) -> Option<(&str, TrackFormat, bool)> {
+    let lower = filename.to_ascii_lowercase();
+    let (lower, compressed) = match lower.strip_suffix(".gz") {
+        Some(rest) => (rest.to_string(), true),
+        None => (lower, false),
+    };
+    let format = if lower.ends_with(".gpx") {
+        TrackFormat::Gpx
+    } else if lower.ends_with(".tcx") {
+        TrackFormat::Tcx
+    } else if lower.ends_with(".fit") {
+        TrackFormat::Fit
+    } else {
+        return None;
+    };
+    let suffix_len = if compressed { 7 } else { 4 };
+    Some((&filename[..filename.len() - suffix_len], format, compressed))
+}
+
+/// Check if a file in the import directory can be processed
+pub fn is_track_file(filename: &str) -> bool {
+    split_filename(filename).is_some()
+}
+
+/// File name without its track format extension (e.g. "activity_123.fit.gz" -> "activity_123")
+pub fn file_stem(filename: &str) -> Option<&str> {
+    split_filename(filename).map(|(stem, _, _)| stem)
+}
+
+/// Read any supported track file into the common track model
+pub fn read_track_file(path: &Path) -> Result<Gpx, String> {
+    let (format, reader) = open(path)?;
+    match format {
+        TrackFormat::Gpx => gpx::parse_gpx(BufReader::new(reader)),
+        TrackFormat::Tcx => tcx::parse_tcx(BufReader::new(reader)),
+        TrackFormat::Fit => {
+            let mut data = Vec::new();
+            let mut reader = reader;
+            reader
+                .read_to_end(&mut data)
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+            fit::parse_fit(&data)
+        }
+    }
+}
+
+/// Read a track file as XML text for the map: GPX and TCX are decompressed if needed,
+/// FIT is converted to GPX
+pub fn read_track_xml(path: &Path) -> Result<String, String> {
+    let (format, mut reader) = open(path)?;
+    match format {
+        TrackFormat::Gpx | TrackFormat::Tcx => {
+            let mut content = String::new();
+            reader
+                .read_to_string(&mut content)
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+            Ok(content)
+        }
+        TrackFormat::Fit => read_track_file(path).map(|g| gpx::to_gpx_xml(&g)),
+    }
+}
+
+/// SHA-256 of the file as stored on disk, to notice when a processed file was replaced
+pub fn content_hash(path: &Path) -> Result<String, String> {
+    let mut file = File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher).map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+/// Size and modification time (nanoseconds since the Unix epoch) of a file, which are
+/// compared before hashing it
+pub fn size_and_modified(path: &Path) -> Result<(i64, i64), String> {
+    let metadata = std::fs::metadata(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_nanos() as i64);
+    Ok((metadata.len() as i64, modified))
+}
+
+fn open(path: &Path) -> Result<(TrackFormat, Box<dyn Read>), String> {
+    let filename = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+    let (_, format, compressed) = split_filename(filename)
+        .ok_or_else(|| format!("Unsupported track file: {}", path.display()))?;
+    let file = File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let reader: Box<dyn Read> = if compressed {
+        Box::new(GzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+    Ok((format, reader))
+}
@@ -0,0 +1,112 @@
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use std::io::BufRead;
+
+/// Callbacks for `stream`, driven by element local names (namespace prefixes stripped)
+pub trait XmlHandler {
+    /// Called for an opening tag; `stack` holds the open ancestors, not `name` itself
+    fn open(&mut self, stack: &[String], name: &str, e: &BytesStart) -> Result<(), String>;
+
+    /// Called for a closing tag; `stack` still ends with the element being closed
+    /// and `text` is its trimmed direct text content
+    fn close(&mut self, stack: &[String], text: &str);
+}
+
+/// Stream an XML document through a handler without building a DOM
+pub fn stream<R: BufRead, H: XmlHandler>(source: R, handler: &mut H) -> Result<(), String> {
+    let mut reader = Reader::from_reader(source);
+    reader.config_mut().trim_text(true);
+
+    let mut buf = Vec::new();
+    // Local names of all currently open elements
+    let mut stack: Vec<String> = Vec::new();
+    let mut text = String::new();
+
+    loop {
+        match reader.read_event_into(&mut buf) {
+            Ok(Event::Start(e)) => {
+                let name = local_name(&e);
+                handler.open(&stack, &name, &e)?;
+                stack.push(name);
+                text.clear();
+            }
+            Ok(Event::Empty(e)) => {
+                let name = local_name(&e);
+                handler.open(&stack, &name, &e)?;
+                stack.push(name);
+                text.clear();
+                handler.close(&stack, "");
+                stack.pop();
+            }
+            Ok(Event::Text(e)) => {
+                let value = e.unescape().map_err(|e| e.to_string())?;
+                text.push_str(&value);
+            }
+            Ok(Event::CData(e)) => {
+                text.push_str(&String::from_utf8_lossy(&e.into_inner()));
+            }
+            Ok(Event::End(_)) => {
+                if !stack.is_empty() {
+                    handler.close(&stack, text.trim());
+                    stack.pop();
+                }
+                text.clear();
+            }
+            Ok(Event::Eof) => break,
+            Ok(_) => {}
+            Err(e) => {
+                return Err(format!(
+                    "XML error at position {}: {}",
+                    reader.buffer_position(),
+                    e
+                ))
+            }
+        }
+        buf.clear();
+    }
+
+    Ok(())
+}
+
+/// Unescaped value of an attribute, matched by local name
+pub fn attr(e: &BytesStart, name: &str) -> Result<Option<String>, String> {
+    for attr in e.attributes() {
+        let attr = attr.map_err(|e| e.to_string())?;
+        if attr.key.local_name().as_ref() == name.as_bytes() {
+            let value = attr.unescape_value().map_err(|e| e.to_string())?;
+            return Ok(Some(value.trim().to_string()));
+        }
+    }
+    Ok(None)
+}
+
+/// The element being closed and its parent, from a `close` stack
+pub fn leaf(stack: &[String]) -> (Option<&str>, &str) {
+    let len = stack.len();
+    let parent = if len >= 2 {
+        Some(stack[len - 2].as_str())
+    } else {
+        None
+    };
+    (parent, stack[len - 1].as_str())
+}
+
+/// Escape text for use in element content or double-quoted attributes
+pub fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+pub fn non_empty(text: &str) -> Option<String> {
+    if text.is_empty() {
+        None
+    } else {
+        Some(text.to_string())
+    }
+}
+
+fn local_name(e: &BytesStart) -> String {
+    String::from_utf8_lossy(e.local_name().as_ref()).into_owned()
+}